@@ -2,17 +2,95 @@
 #define STACK_VM_H
 
 #include <stdint.h>
+#include <stdbool.h>
 
 #include "./stack_vm_op.h"
 #include "./stack_vm_result.h"
+#include "./stack_vm_config.h"
+#include "./stack_vm_receipt.h"
 
-extern void* create_vm(
+// Generation-checked VM handle. 0 is never a valid handle.
+typedef uint64_t VMHandle;
+#define VM_INVALID_HANDLE 0
+
+// Formats of vm_profile_report.
+#define VM_PROFILE_FORMAT_TEXT   0
+#define VM_PROFILE_FORMAT_FOLDED 1
+
+// Handle of a VM pool. Uses the same encoding as VMHandle.
+typedef uint64_t VMPoolHandle;
+
+// Handle of a loaded program. Uses the same encoding as VMHandle.
+typedef uint64_t VMProgramHandle;
+
+// One job of run_vm_batch: a loaded program and the inputs to run it with.
+typedef struct {
+    VMProgramHandle program;
+    const VMResult* inputs_ptr;
+    size_t          inputs_len;
+} VMJob;
+
+extern VMHandle create_vm(
     size_t stack_size,
     Operation* instruction_ptr,
     size_t instruction_len
 );
-extern VMResult run_vm(void* vm_ptr);
-extern void free_byte_array(uint8_t* ptr, size_t len, size_t capacity);
-extern void free_vm(void* vm_ptr);
+extern VMHandle create_vm_with_config(
+    size_t stack_size,
+    Operation* instruction_ptr,
+    size_t instruction_len,
+    VMConfig config
+);
+extern VMProgramHandle load_program(
+    Operation* instruction_ptr,
+    size_t instruction_len,
+    VMConfig config
+);
+extern bool free_program(VMProgramHandle program);
+extern VMHandle create_vm_from_program(size_t stack_size, VMProgramHandle program);
+extern VMResult run_vm(VMHandle handle);
+extern VMResultArray run_vm_multi(VMHandle handle);
+extern VMResult run_vm_with_inputs(
+    VMHandle handle,
+    const VMResult* inputs_ptr,
+    size_t inputs_len
+);
+extern VMResultArray run_vm_batch(
+    const VMJob* jobs_ptr,
+    size_t jobs_len,
+    size_t stack_size,
+    size_t threads
+);
+extern bool reset_vm(VMHandle handle);
+extern void free_byte_array(ByteArrayPtr bytes);
+extern void free_vm_result_array(VMResult* ptr, size_t len, size_t capacity);
+extern VMErrorReport vm_last_error(VMHandle handle);
+extern void free_vm_error_report(VMErrorReport report);
+extern char* last_ffi_error_message(void);
+extern void free_ffi_string(char* s);
+extern ByteArrayPtr snapshot_vm(VMHandle handle);
+extern VMHandle restore_vm(
+    const uint8_t* snapshot_ptr,
+    size_t snapshot_len,
+    Operation* instruction_ptr,
+    size_t instruction_len
+);
+extern bool set_vm_trace(VMHandle handle, bool enabled);
+extern bool set_vm_profiling(VMHandle handle, bool enabled);
+extern char* vm_profile_report(VMHandle handle, uint8_t format);
+extern ExecutionReceipt vm_receipt(VMHandle handle);
+extern uint64_t vm_peak_memory(VMHandle handle);
+extern bool interrupt_vm(VMHandle handle);
+extern bool free_vm(VMHandle handle);
+extern VMHandleArray vm_live_handles(void);
+extern void free_vm_handle_array(uint64_t* ptr, size_t len, size_t capacity);
+extern VMPoolHandle create_vm_pool(size_t capacity, size_t stack_size, VMConfig config);
+extern VMHandle vm_pool_checkout(
+    VMPoolHandle pool,
+    Operation* instruction_ptr,
+    size_t instruction_len
+);
+extern bool vm_pool_checkin(VMPoolHandle pool, VMHandle handle);
+extern bool free_vm_pool(VMPoolHandle pool);
 
 #endif // STACK_VM_H
\ No newline at end of file
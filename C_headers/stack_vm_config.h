@@ -0,0 +1,25 @@
+#ifndef STACK_VM_CONFIG
+#define STACK_VM_CONFIG
+
+#include <stdbool.h>
+#include <stdint.h>
+
+// Values of VMConfig.backend. Both give identical results; the register
+// backend runs straight-line arithmetic faster.
+#define VM_BACKEND_STACK 0
+#define VM_BACKEND_REGISTER 1
+
+typedef struct {
+    bool deterministic_float;
+    bool ieee_float_division;
+    // 0 means unlimited.
+    uint64_t max_steps;
+    // Bytes held in byte arrays and linear memory. 0 means unlimited.
+    uint64_t max_memory_bytes;
+    // Fold constants and drop dead push/pop pairs when the program loads.
+    bool optimize;
+    // One of the VM_BACKEND_ values.
+    uint8_t backend;
+} VMConfig;
+
+#endif // STACK_VM_CONFIG
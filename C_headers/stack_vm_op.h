@@ -17,6 +17,41 @@
 #define OP_LT         0x0D
 #define OP_LTE        0x0E
 #define OP_CONCAT     0x0F
+#define OP_RETURN     0x10
+#define OP_MLOAD      0x11
+#define OP_MSTORE     0x12
+#define OP_MSTORE8    0x13
+#define OP_MCOPY      0x14
+#define OP_MSIZE      0x15
+#define OP_MLOADBYTES 0x16
+#define OP_MSTOREBYTES 0x17
+#define OP_I2F        0x18
+#define OP_F2I        0x19
+#define OP_I2B        0x1A
+#define OP_B2I        0x1B
+#define OP_BOOL2I     0x1C
+#define OP_TOBYTES_LE 0x1D
+#define OP_TOBYTES_BE 0x1E
+#define OP_FROMBYTES_LE 0x1F
+#define OP_FROMBYTES_BE 0x20
+#define OP_MOD        0x21
+#define OP_REM        0x22
+#define OP_NEG        0x23
+#define OP_ABS        0x24
+#define OP_MIN        0x25
+#define OP_MAX        0x26
+#define OP_POW        0x27
+#define OP_SQRT       0x28
+#define OP_FLOOR      0x29
+#define OP_CEIL       0x2A
+#define OP_ROUND      0x2B
+#define OP_TRUNC      0x2C
+#define OP_SLICE      0x2D
+
+// Target type operand of OP_FROMBYTES_LE / OP_FROMBYTES_BE
+#define FROMBYTES_INTEGER 0x00
+#define FROMBYTES_FLOAT   0x01
+#define FROMBYTES_BYTE    0x02
 
 #include <stdint.h>
 
@@ -0,0 +1,15 @@
+#ifndef STACK_VM_RECEIPT
+#define STACK_VM_RECEIPT
+
+#include <stdint.h>
+
+typedef struct {
+    uint64_t instructions_executed;
+    uint64_t gas_used;
+    uint64_t peak_stack_depth;
+    uint64_t peak_memory;
+    uint64_t frames_pushed;
+    uint64_t wall_time_ns;
+} ExecutionReceipt;
+
+#endif // STACK_VM_RECEIPT
@@ -11,12 +11,19 @@ typedef enum {
     VM_RESULT_BYTE_ARRAY,
     VM_RESULT_BOOL,
     VM_RESULT_ERROR,
+    VM_RESULT_PANIC,
 } VMResultTag;
 
+// Error codes raised by the FFI layer rather than by program execution
+#define VM_ERROR_NULL_POINTER   100
+#define VM_ERROR_INVALID_HANDLE 101
+
+// Byte arrays returned by the VM point into its shared buffer, which owner
+// keeps alive until free_byte_array. owner is ignored on inputs.
 typedef struct {
-    uint8_t*  ptr;
-    size_t    len;
-    size_t    capacity;
+    uint8_t*    ptr;
+    size_t      len;
+    const void* owner;
 } ByteArrayPtr;
 
 typedef union {
@@ -32,4 +39,27 @@ typedef struct {
     VMResultValue value;
 } VMResult;
 
+typedef struct {
+    VMResult* ptr;
+    size_t    len;
+    size_t    capacity;
+} VMResultArray;
+
+typedef struct {
+    uint64_t* ptr;
+    size_t    len;
+    size_t    capacity;
+} VMHandleArray;
+
+typedef struct {
+    bool          has_error;
+    int32_t       code;
+    bool          has_ip;
+    size_t        ip;
+    uint8_t       opcode;
+    char*         operand_types;
+    char*         message;
+    VMResultArray stack;
+} VMErrorReport;
+
 #endif // STACK_VM_RESULT
\ No newline at end of file
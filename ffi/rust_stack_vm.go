@@ -2,36 +2,450 @@ package ffi
 
 /*
    #cgo LDFLAGS: -L${SRCDIR}/rust_stack_vm/target/release -lrust_stack_vm
+   #include <stdlib.h>
    #include "../C_headers/stack_vm.h"
 */
 import "C"
 
 import (
 	"fmt"
+	"runtime"
+	"time"
 	"unsafe"
 )
 
-type VmPtr unsafe.Pointer
+type VmHandle uint64
+
+const InvalidVmHandle VmHandle = C.VM_INVALID_HANDLE
+
+type VmPoolHandle uint64
+
+type ProgramHandle uint64
+
+// Interpreters a VM can run its program with. Both give identical results,
+// errors and statistics.
+const (
+	BackendStack    uint8 = C.VM_BACKEND_STACK
+	BackendRegister uint8 = C.VM_BACKEND_REGISTER
+)
+
+type Config struct {
+	DeterministicFloat bool
+	IEEEFloatDivision  bool
+	// MaxSteps bounds the instructions a single run may execute; 0 means
+	// unlimited.
+	MaxSteps uint64
+	// MaxMemoryBytes bounds the bytes a run may hold in byte arrays and
+	// linear memory; 0 means unlimited.
+	MaxMemoryBytes uint64
+	// Optimize folds constant expressions and removes dead push/pop pairs
+	// when the program is loaded. Results and errors are unchanged.
+	Optimize bool
+	// Backend is BackendStack or BackendRegister. The register backend
+	// runs straight-line arithmetic faster.
+	Backend uint8
+}
+
+// Receipt holds the statistics of the runs since the VM was last reset.
+type Receipt struct {
+	InstructionsExecuted uint64
+	GasUsed              uint64
+	PeakStackDepth       uint64
+	PeakMemory           uint64
+	FramesPushed         uint64
+	WallTime             time.Duration
+}
+
+func (config Config) toC() C.VMConfig {
+	return C.VMConfig{
+		deterministic_float: C.bool(config.DeterministicFloat),
+		ieee_float_division: C.bool(config.IEEEFloatDivision),
+		max_steps:           C.uint64_t(config.MaxSteps),
+		max_memory_bytes:    C.uint64_t(config.MaxMemoryBytes),
+		optimize:            C.bool(config.Optimize),
+		backend:             C.uint8_t(config.Backend),
+	}
+}
+
+func CreateVM(stackSize int, insts []Operation) (VmHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	cStackSize := C.size_t(stackSize)
+	cInstPtr := (*C.Operation)(unsafe.Pointer(&insts[0]))
+	instLen := C.size_t(len(insts))
+	handle := C.create_vm(cStackSize, cInstPtr, instLen)
+
+	if handle == C.VM_INVALID_HANDLE {
+		return InvalidVmHandle, fmt.Errorf("failed to create VM in Rust: %s", LastFFIErrorMessage())
+	}
+
+	return VmHandle(handle), nil
+}
+
+func CreateVMWithConfig(stackSize int, insts []Operation, config Config) (VmHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
 
-func CreateVM(stackSize int, insts []Operation) (VmPtr, error) {
 	cStackSize := C.size_t(stackSize)
 	cInstPtr := (*C.Operation)(unsafe.Pointer(&insts[0]))
 	instLen := C.size_t(len(insts))
-	vmPtr := C.create_vm(cStackSize, cInstPtr, instLen)
+	handle := C.create_vm_with_config(cStackSize, cInstPtr, instLen, config.toC())
+
+	if handle == C.VM_INVALID_HANDLE {
+		return InvalidVmHandle, fmt.Errorf("failed to create VM in Rust: %s", LastFFIErrorMessage())
+	}
+
+	return VmHandle(handle), nil
+}
+
+// LoadProgram decodes and prepares insts once, so that many VMs can be
+// created from them with CreateVMFromProgram.
+func LoadProgram(insts []Operation, config Config) (ProgramHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	cInstPtr := (*C.Operation)(unsafe.Pointer(&insts[0]))
+	instLen := C.size_t(len(insts))
+	program := C.load_program(cInstPtr, instLen, config.toC())
 
-	if vmPtr == nil {
-		return nil, fmt.Errorf("failed to create VM in Rust")
+	if program == C.VM_INVALID_HANDLE {
+		return ProgramHandle(InvalidVmHandle), fmt.Errorf("failed to load program in Rust: %s", LastFFIErrorMessage())
 	}
 
-	return VmPtr(vmPtr), nil
+	return ProgramHandle(program), nil
 }
 
-func RunVM(vmPtr VmPtr) Result {
-	VMResult := C.run_vm(unsafe.Pointer(vmPtr))
+// FreeProgram releases the program handle; VMs created from it keep
+// running it.
+func FreeProgram(program ProgramHandle) bool {
+	return bool(C.free_program(C.VMProgramHandle(program)))
+}
+
+// CreateVMFromProgram creates a VM sharing a loaded program, with the
+// config the program was loaded with.
+func CreateVMFromProgram(stackSize int, program ProgramHandle) (VmHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	handle := C.create_vm_from_program(C.size_t(stackSize), C.VMProgramHandle(program))
+
+	if handle == C.VM_INVALID_HANDLE {
+		return InvalidVmHandle, fmt.Errorf("failed to create VM in Rust: %s", LastFFIErrorMessage())
+	}
+
+	return VmHandle(handle), nil
+}
+
+func RunVM(handle VmHandle) Result {
+	VMResult := C.run_vm(C.VMHandle(handle))
 
 	return NewResult(VMResult)
 }
 
-func FreeVm(vmPtr VmPtr) {
-	C.free_vm(unsafe.Pointer(vmPtr))
+func RunVMMulti(handle VmHandle) ResultArray {
+	VMResultArray := C.run_vm_multi(C.VMHandle(handle))
+
+	return NewResultArray(VMResultArray)
+}
+
+// toCInputs converts inputs to VM values. Byte arrays are copied into C
+// memory, which free releases.
+func toCInputs(inputs []any) (cInputs []C.VMResult, free func(), err error) {
+	cInputs = make([]C.VMResult, len(inputs))
+	cBytes := make([]unsafe.Pointer, 0)
+
+	free = func() {
+		for _, p := range cBytes {
+			C.free(p)
+		}
+	}
+
+	for i, input := range inputs {
+		value := unsafe.Pointer(&cInputs[i].value)
+
+		switch v := input.(type) {
+		case int32:
+			cInputs[i].tag = VMResultTagInteger
+			*(*C.int32_t)(value) = C.int32_t(v)
+
+		case float32:
+			cInputs[i].tag = VMResultTagFloat
+			*(*C.float)(value) = C.float(v)
+
+		case byte:
+			cInputs[i].tag = VMResultTagByte
+			*(*C.uint8_t)(value) = C.uint8_t(v)
+
+		case bool:
+			cInputs[i].tag = VMResultTagBool
+			*(*C.bool)(value) = C.bool(v)
+
+		case []byte:
+			cInputs[i].tag = VMResultTagByteArray
+			bytesPtr := (*ByteArrayPtr)(value)
+			bytesPtr.len = C.size_t(len(v))
+
+			if len(v) > 0 {
+				p := C.CBytes(v)
+				cBytes = append(cBytes, p)
+				bytesPtr.ptr = (*C.uint8_t)(p)
+			}
+
+		default:
+			free()
+
+			return nil, func() {}, fmt.Errorf("unsupported input type: %T", v)
+		}
+	}
+
+	return cInputs, free, nil
+}
+
+func RunVMWithInputs(handle VmHandle, inputs []any) (Result, error) {
+	cInputs, free, err := toCInputs(inputs)
+
+	if err != nil {
+		return Result{}, err
+	}
+
+	defer free()
+
+	var cInputPtr *C.VMResult
+
+	if len(cInputs) > 0 {
+		cInputPtr = &cInputs[0]
+	}
+
+	VMResult := C.run_vm_with_inputs(C.VMHandle(handle), cInputPtr, C.size_t(len(cInputs)))
+
+	return NewResult(VMResult), nil
+}
+
+// Job is one entry of RunBatch.
+type Job struct {
+	Program ProgramHandle
+	Inputs  []any
+}
+
+// RunBatch runs every job in a single call, on up to threads threads, and
+// returns one result per job in job order. The caller frees the array.
+func RunBatch(jobs []Job, stackSize int, threads int) (ResultArray, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	cJobs := make([]C.VMJob, len(jobs))
+
+	// The job array handed to C points at each job's inputs, which must
+	// stay put until the call returns.
+	var pinner runtime.Pinner
+	defer pinner.Unpin()
+
+	for i, job := range jobs {
+		cInputs, free, err := toCInputs(job.Inputs)
+
+		if err != nil {
+			return ResultArray{}, err
+		}
+
+		defer free()
+
+		cJobs[i].program = C.VMProgramHandle(job.Program)
+		cJobs[i].inputs_len = C.size_t(len(cInputs))
+
+		if len(cInputs) > 0 {
+			pinner.Pin(&cInputs[0])
+			cJobs[i].inputs_ptr = &cInputs[0]
+		}
+	}
+
+	var cJobPtr *C.VMJob
+
+	if len(cJobs) > 0 {
+		cJobPtr = &cJobs[0]
+	}
+
+	cResults := C.run_vm_batch(cJobPtr, C.size_t(len(cJobs)), C.size_t(stackSize), C.size_t(threads))
+	results := NewResultArray(cResults)
+
+	if len(results.Results) != len(jobs) {
+		results.Free()
+
+		return ResultArray{}, fmt.Errorf("failed to run batch in Rust: %s", LastFFIErrorMessage())
+	}
+
+	return results, nil
+}
+
+func ResetVM(handle VmHandle) bool {
+	return bool(C.reset_vm(C.VMHandle(handle)))
+}
+
+// LastError copies the report of the last failed run into Go memory. An
+// invalid handle yields a report with ErrorCodeInvalidHandle.
+func LastError(handle VmHandle) ErrorReport {
+	cReport := C.vm_last_error(C.VMHandle(handle))
+
+	defer C.free_vm_error_report(cReport)
+
+	return NewErrorReport(cReport)
+}
+
+// LastFFIErrorMessage returns the message of the last panic or rejected
+// argument seen by the Rust side on the calling OS thread.
+func LastFFIErrorMessage() string {
+	cMessage := C.last_ffi_error_message()
+
+	if cMessage == nil {
+		return ""
+	}
+
+	defer C.free_ffi_string(cMessage)
+
+	return C.GoString(cMessage)
+}
+
+// SnapshotVM serializes the VM into a versioned buffer owned by Go.
+func SnapshotVM(handle VmHandle) []byte {
+	snapshot := C.snapshot_vm(C.VMHandle(handle))
+
+	defer C.free_byte_array(snapshot)
+
+	return C.GoBytes(unsafe.Pointer(snapshot.ptr), C.int(snapshot.len))
+}
+
+// RestoreVM rebuilds a VM from a SnapshotVM buffer and the program it was
+// taken from.
+func RestoreVM(snapshot []byte, insts []Operation) (VmHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	if len(snapshot) == 0 {
+		return InvalidVmHandle, fmt.Errorf("empty snapshot")
+	}
+
+	cSnapshot := C.CBytes(snapshot)
+	defer C.free(cSnapshot)
+
+	cInstPtr := (*C.Operation)(unsafe.Pointer(&insts[0]))
+	instLen := C.size_t(len(insts))
+	handle := C.restore_vm((*C.uint8_t)(cSnapshot), C.size_t(len(snapshot)), cInstPtr, instLen)
+
+	if handle == C.VM_INVALID_HANDLE {
+		return InvalidVmHandle, fmt.Errorf("failed to restore VM in Rust: %s", LastFFIErrorMessage())
+	}
+
+	return VmHandle(handle), nil
+}
+
+// SetTrace prints the VM's stack to stdout after every instruction. It is
+// off by default and only useful while debugging a single VM.
+func SetTrace(handle VmHandle, enabled bool) bool {
+	return bool(C.set_vm_trace(C.VMHandle(handle), C.bool(enabled)))
+}
+
+func SetProfiling(handle VmHandle, enabled bool) bool {
+	return bool(C.set_vm_profiling(C.VMHandle(handle), C.bool(enabled)))
+}
+
+// ProfileReport returns the profile as a hot-spot table, or as folded
+// stacks for flame graph tools. ok is false while profiling is disabled.
+func ProfileReport(handle VmHandle, folded bool) (report string, ok bool) {
+	format := C.uint8_t(C.VM_PROFILE_FORMAT_TEXT)
+
+	if folded {
+		format = C.VM_PROFILE_FORMAT_FOLDED
+	}
+
+	cReport := C.vm_profile_report(C.VMHandle(handle), format)
+
+	if cReport == nil {
+		return "", false
+	}
+
+	defer C.free_ffi_string(cReport)
+
+	return C.GoString(cReport), true
+}
+
+func VMReceipt(handle VmHandle) Receipt {
+	receipt := C.vm_receipt(C.VMHandle(handle))
+
+	return Receipt{
+		InstructionsExecuted: uint64(receipt.instructions_executed),
+		GasUsed:              uint64(receipt.gas_used),
+		PeakStackDepth:       uint64(receipt.peak_stack_depth),
+		PeakMemory:           uint64(receipt.peak_memory),
+		FramesPushed:         uint64(receipt.frames_pushed),
+		WallTime:             time.Duration(receipt.wall_time_ns),
+	}
+}
+
+// PeakMemory returns the most bytes the VM held since its last reset.
+func PeakMemory(handle VmHandle) uint64 {
+	return uint64(C.vm_peak_memory(C.VMHandle(handle)))
+}
+
+// InterruptVM stops a run in progress on another goroutine. It does not
+// wait for the run to finish.
+func InterruptVM(handle VmHandle) bool {
+	return bool(C.interrupt_vm(C.VMHandle(handle)))
+}
+
+// FreeVm returns false if the handle is unknown or was already freed.
+func FreeVm(handle VmHandle) bool {
+	return bool(C.free_vm(C.VMHandle(handle)))
+}
+
+// LiveVmHandles lists every VM that has not been freed, for leak detection.
+func LiveVmHandles() []VmHandle {
+	cHandles := C.vm_live_handles()
+
+	defer C.free_vm_handle_array(cHandles.ptr, cHandles.len, cHandles.capacity)
+
+	handles := make([]VmHandle, int(cHandles.len))
+
+	for i, h := range unsafe.Slice(cHandles.ptr, int(cHandles.len)) {
+		handles[i] = VmHandle(h)
+	}
+
+	return handles
+}
+
+// CreateVMPool pre-allocates capacity VMs that any number of goroutines can
+// check out concurrently.
+func CreateVMPool(capacity int, stackSize int, config Config) (VmPoolHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	pool := C.create_vm_pool(C.size_t(capacity), C.size_t(stackSize), config.toC())
+
+	if pool == C.VM_INVALID_HANDLE {
+		return VmPoolHandle(InvalidVmHandle), fmt.Errorf("failed to create VM pool in Rust: %s", LastFFIErrorMessage())
+	}
+
+	return VmPoolHandle(pool), nil
+}
+
+func VMPoolCheckout(pool VmPoolHandle, insts []Operation) (VmHandle, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	cInstPtr := (*C.Operation)(unsafe.Pointer(&insts[0]))
+	instLen := C.size_t(len(insts))
+	handle := C.vm_pool_checkout(C.VMPoolHandle(pool), cInstPtr, instLen)
+
+	if handle == C.VM_INVALID_HANDLE {
+		return InvalidVmHandle, fmt.Errorf("failed to check out VM: %s", LastFFIErrorMessage())
+	}
+
+	return VmHandle(handle), nil
+}
+
+func VMPoolCheckin(pool VmPoolHandle, handle VmHandle) bool {
+	return bool(C.vm_pool_checkin(C.VMPoolHandle(pool), C.VMHandle(handle)))
+}
+
+func FreeVMPool(pool VmPoolHandle) bool {
+	return bool(C.free_vm_pool(C.VMPoolHandle(pool)))
 }
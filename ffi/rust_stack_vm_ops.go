@@ -15,22 +15,58 @@ type OperationCode C.uint8_t
 type OperationValue C.OperationValue
 
 const (
-	OpCodeHalt      OperationCode = C.OP_HALT
-	OpCodePushInt   OperationCode = C.OP_PUSHINT
-	OpCodePushFloat OperationCode = C.OP_PUSHFLOAT
-	OpCodePushByte  OperationCode = C.OP_PUSHBYTE
-	OpCodePack      OperationCode = C.OP_PACK
-	OpCodePop       OperationCode = C.OP_POP
-	OpCodeAdd       OperationCode = C.OP_ADD
-	OpCodeSub       OperationCode = C.OP_SUB
-	OpCodeMul       OperationCode = C.OP_MUL
-	OpCodeDiv       OperationCode = C.OP_DIV
-	OpCodeEq        OperationCode = C.OP_EQ
-	OpCodeLt        OperationCode = C.OP_LT
-	OpCodeLte       OperationCode = C.OP_LTE
-	OpCodeGt        OperationCode = C.OP_GT
-	OpCodeGte       OperationCode = C.OP_GTE
-	OpCodeConcat    OperationCode = C.OP_CONCAT
+	OpCodeHalt        OperationCode = C.OP_HALT
+	OpCodePushInt     OperationCode = C.OP_PUSHINT
+	OpCodePushFloat   OperationCode = C.OP_PUSHFLOAT
+	OpCodePushByte    OperationCode = C.OP_PUSHBYTE
+	OpCodePack        OperationCode = C.OP_PACK
+	OpCodePop         OperationCode = C.OP_POP
+	OpCodeAdd         OperationCode = C.OP_ADD
+	OpCodeSub         OperationCode = C.OP_SUB
+	OpCodeMul         OperationCode = C.OP_MUL
+	OpCodeDiv         OperationCode = C.OP_DIV
+	OpCodeEq          OperationCode = C.OP_EQ
+	OpCodeLt          OperationCode = C.OP_LT
+	OpCodeLte         OperationCode = C.OP_LTE
+	OpCodeGt          OperationCode = C.OP_GT
+	OpCodeGte         OperationCode = C.OP_GTE
+	OpCodeConcat      OperationCode = C.OP_CONCAT
+	OpCodeReturn      OperationCode = C.OP_RETURN
+	OpCodeMLoad       OperationCode = C.OP_MLOAD
+	OpCodeMStore      OperationCode = C.OP_MSTORE
+	OpCodeMStore8     OperationCode = C.OP_MSTORE8
+	OpCodeMCopy       OperationCode = C.OP_MCOPY
+	OpCodeMSize       OperationCode = C.OP_MSIZE
+	OpCodeMLoadBytes  OperationCode = C.OP_MLOADBYTES
+	OpCodeMStoreBytes OperationCode = C.OP_MSTOREBYTES
+	OpCodeI2F         OperationCode = C.OP_I2F
+	OpCodeF2I         OperationCode = C.OP_F2I
+	OpCodeI2B         OperationCode = C.OP_I2B
+	OpCodeB2I         OperationCode = C.OP_B2I
+	OpCodeBool2I      OperationCode = C.OP_BOOL2I
+	OpCodeToBytesLE   OperationCode = C.OP_TOBYTES_LE
+	OpCodeToBytesBE   OperationCode = C.OP_TOBYTES_BE
+	OpCodeFromBytesLE OperationCode = C.OP_FROMBYTES_LE
+	OpCodeFromBytesBE OperationCode = C.OP_FROMBYTES_BE
+	OpCodeMod         OperationCode = C.OP_MOD
+	OpCodeRem         OperationCode = C.OP_REM
+	OpCodeNeg         OperationCode = C.OP_NEG
+	OpCodeAbs         OperationCode = C.OP_ABS
+	OpCodeMin         OperationCode = C.OP_MIN
+	OpCodeMax         OperationCode = C.OP_MAX
+	OpCodePow         OperationCode = C.OP_POW
+	OpCodeSqrt        OperationCode = C.OP_SQRT
+	OpCodeFloor       OperationCode = C.OP_FLOOR
+	OpCodeCeil        OperationCode = C.OP_CEIL
+	OpCodeRound       OperationCode = C.OP_ROUND
+	OpCodeTrunc       OperationCode = C.OP_TRUNC
+	OpCodeSlice       OperationCode = C.OP_SLICE
+)
+
+const (
+	FromBytesInteger uint8 = C.FROMBYTES_INTEGER
+	FromBytesFloat   uint8 = C.FROMBYTES_FLOAT
+	FromBytesByte    uint8 = C.FROMBYTES_BYTE
 )
 
 func NewOperation(kind OperationCode, val any) Operation {
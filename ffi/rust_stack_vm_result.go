@@ -14,12 +14,19 @@ const (
 	VMResultTagByteArray = C.VM_RESULT_BYTE_ARRAY
 	VMResultTagBool      = C.VM_RESULT_BOOL
 	VMResultTagError     = C.VM_RESULT_ERROR
+	VMResultTagPanic     = C.VM_RESULT_PANIC
+)
+
+const (
+	ErrorCodeNullPointer   = C.VM_ERROR_NULL_POINTER
+	ErrorCodeInvalidHandle = C.VM_ERROR_INVALID_HANDLE
 )
 
 type ByteArrayPtr = C.ByteArrayPtr
 
 type Result struct {
 	IsError        bool
+	IsPanic        bool
 	IsFloat        bool
 	IsByte         bool
 	IsBool         bool
@@ -78,6 +85,12 @@ func NewResult(r C.VMResult) Result {
 			BoolValue: true,
 		}
 
+	case VMResultTagPanic:
+		return Result{
+			IsError: true,
+			IsPanic: true,
+		}
+
 	case VMResultTagError:
 		fallthrough
 
@@ -92,13 +105,68 @@ func NewResult(r C.VMResult) Result {
 
 func (r *Result) Free() {
 	if r.IsByteArray && r.ByteArrayPtr.ptr != nil {
-		arrPtr := r.ByteArrayPtr.ptr
-		arrLen := C.size_t(r.ByteArrayPtr.len)
-		arrCap := C.size_t(r.ByteArrayPtr.capacity)
-
-		C.free_byte_array(arrPtr, arrLen, arrCap)
+		C.free_byte_array(r.ByteArrayPtr)
 
 		r.ByteArrayPtr.ptr = nil
 	}
 
 }
+
+type ResultArray struct {
+	Results []Result
+	raw     C.VMResultArray
+}
+
+func NewResultArray(r C.VMResultArray) ResultArray {
+	cResults := unsafe.Slice(r.ptr, int(r.len))
+	results := make([]Result, len(cResults))
+
+	for i, cResult := range cResults {
+		results[i] = NewResult(cResult)
+		// The byte arrays are owned by the array and released by its Free.
+		results[i].ByteArrayPtr.ptr = nil
+	}
+
+	return ResultArray{
+		Results: results,
+		raw:     r,
+	}
+}
+
+func (r *ResultArray) Free() {
+	if r.raw.ptr != nil {
+		C.free_vm_result_array(r.raw.ptr, r.raw.len, r.raw.capacity)
+
+		r.raw.ptr = nil
+	}
+}
+
+type ErrorReport struct {
+	HasError     bool
+	Code         int32
+	HasIP        bool
+	IP           int
+	Opcode       OperationCode
+	OperandTypes string
+	Message      string
+	Stack        []Result
+}
+
+func NewErrorReport(r C.VMErrorReport) ErrorReport {
+	if !bool(r.has_error) {
+		return ErrorReport{}
+	}
+
+	stack := NewResultArray(r.stack)
+
+	return ErrorReport{
+		HasError:     true,
+		Code:         int32(r.code),
+		HasIP:        bool(r.has_ip),
+		IP:           int(r.ip),
+		Opcode:       OperationCode(r.opcode),
+		OperandTypes: C.GoString(r.operand_types),
+		Message:      C.GoString(r.message),
+		Stack:        stack.Results,
+	}
+}
@@ -45,6 +45,8 @@ func main() {
 		panic(err)
 	}
 
+	defer hvm.Free()
+
 	result, err := hvm.Run()
 
 	if err != nil {
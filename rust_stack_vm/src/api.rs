@@ -0,0 +1,412 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::stack::composite_stack::StackValue;
+use crate::stack::stack::StackError;
+use crate::vm::config::{VMConfig, BACKEND_REGISTER, BACKEND_STACK};
+use crate::vm::error::ErrorReport;
+use crate::vm::op::OpCode;
+use crate::vm::receipt::ExecutionReceipt;
+use crate::vm::snapshot::{SnapshotError, VMSnapshot};
+use crate::vm::vm::{Program, VirtualMachine, VM, MAX_STACK_SIZE};
+
+// The safe Rust API. The C API in lib.rs is a thin layer over it that maps
+// handles to Vm values and errors to result codes.
+
+/// Stack size used when [`VmBuilder::stack_size`] is not called.
+pub const DEFAULT_STACK_SIZE: usize = 1024;
+
+/// Interpreter a VM runs its program with. Both give identical results,
+/// errors and statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Executes each instruction against the operand stack.
+    #[default]
+    Stack,
+    /// Compiles runs of scalar instructions into register code, which runs
+    /// straight-line arithmetic faster.
+    Register,
+}
+
+/// Why a VM could not be built, run or restored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// A builder setting was rejected.
+    InvalidConfig(String),
+    /// The program failed. [`Vm::last_error`] has the failing instruction
+    /// and the stack around it.
+    Execution(StackError),
+    /// A snapshot could not be restored.
+    Snapshot(SnapshotError),
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidConfig(message) => f.write_str(message),
+            VmError::Execution(e) => Display::fmt(e, f),
+            VmError::Snapshot(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl Error for VmError {}
+
+impl From<StackError> for VmError {
+    fn from(e: StackError) -> Self {
+        VmError::Execution(e)
+    }
+}
+
+impl From<SnapshotError> for VmError {
+    fn from(e: SnapshotError) -> Self {
+        VmError::Snapshot(e)
+    }
+}
+
+/// Configures and creates VMs.
+///
+/// Programs are prepared for the builder's settings when they are loaded,
+/// so one builder can load a [`Program`] once and create any number of VMs
+/// from it.
+#[derive(Debug, Clone)]
+pub struct VmBuilder {
+    stack_size: usize,
+    config: VMConfig,
+    trace: bool,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        VmBuilder::new()
+    }
+}
+
+impl VmBuilder {
+    /// A builder with a [`DEFAULT_STACK_SIZE`] stack, no limits, no
+    /// optimization and the stack backend.
+    pub fn new() -> Self {
+        VmBuilder::with_config(VMConfig::default())
+    }
+
+    pub(crate) fn with_config(config: VMConfig) -> Self {
+        VmBuilder { stack_size: DEFAULT_STACK_SIZE, config, trace: false }
+    }
+
+    /// Number of values the operand stack holds, from 1 to
+    /// [`MAX_STACK_SIZE`].
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// Canonicalizes every NaN the program produces and rejects float
+    /// operations whose result may differ between platforms.
+    pub fn deterministic_float(mut self, enabled: bool) -> Self {
+        self.config.deterministic_float = enabled;
+        self
+    }
+
+    /// Float division by zero yields IEEE infinities and NaN instead of
+    /// [`StackError::DivisionByZero`].
+    pub fn ieee_float_division(mut self, enabled: bool) -> Self {
+        self.config.ieee_float_division = enabled;
+        self
+    }
+
+    /// Bounds the instructions a single run may execute; 0 means unlimited.
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.config.max_steps = max_steps;
+        self
+    }
+
+    /// Bounds the bytes a run may hold in byte arrays and linear memory; 0
+    /// means unlimited.
+    pub fn max_memory_bytes(mut self, max_memory_bytes: u64) -> Self {
+        self.config.max_memory_bytes = max_memory_bytes;
+        self
+    }
+
+    /// Folds constant expressions and removes dead push/pop pairs when a
+    /// program is loaded. Results, errors and the instruction indexes they
+    /// report are unchanged; only statistics such as the step count drop.
+    pub fn optimize(mut self, enabled: bool) -> Self {
+        self.config.optimize = enabled;
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.config.backend = match backend {
+            Backend::Stack => BACKEND_STACK,
+            Backend::Register => BACKEND_REGISTER,
+        };
+        self
+    }
+
+    /// Prints the stack after every instruction, or after every compiled
+    /// block on the register backend. Off by default.
+    pub fn trace(mut self, enabled: bool) -> Self {
+        self.trace = enabled;
+        self
+    }
+
+    fn check_config(&self) -> Result<(), VmError> {
+        if self.config.backend > BACKEND_REGISTER {
+            return Err(VmError::InvalidConfig(format!("Unknown backend: {}", self.config.backend)));
+        }
+
+        Ok(())
+    }
+
+    // Checks every setting, for callers that build VMs later and cannot
+    // report an error then.
+    pub(crate) fn validate(&self) -> Result<(), VmError> {
+        self.check_stack_size()?;
+        self.check_config()
+    }
+
+    fn check_stack_size(&self) -> Result<(), VmError> {
+        if !(1..=MAX_STACK_SIZE).contains(&self.stack_size) {
+            return Err(VmError::InvalidConfig(format!("stack size must be from 1 to {}", MAX_STACK_SIZE)));
+        }
+
+        Ok(())
+    }
+
+    /// Prepares `instructions` once for this builder's settings, for use
+    /// with [`VmBuilder::build_from_program`].
+    pub fn load(&self, instructions: Vec<OpCode>) -> Result<Arc<Program>, VmError> {
+        self.check_config()?;
+
+        Ok(Arc::new(Program::new(instructions, self.config)))
+    }
+
+    /// Creates a VM running `instructions`.
+    pub fn build(&self, instructions: Vec<OpCode>) -> Result<Vm, VmError> {
+        self.validate()?;
+
+        Ok(self.vm(VM::from_program(self.stack_size, Arc::new(Program::new(instructions, self.config)))))
+    }
+
+    /// Creates a VM running a loaded program without preparing it again.
+    /// The VM uses the settings the program was loaded with; only the
+    /// stack size and tracing come from this builder.
+    pub fn build_from_program(&self, program: Arc<Program>) -> Result<Vm, VmError> {
+        self.check_stack_size()?;
+
+        Ok(self.vm(VM::from_program(self.stack_size, program)))
+    }
+
+    fn vm(&self, mut vm: VM) -> Vm {
+        vm.set_trace(self.trace);
+
+        Vm { vm }
+    }
+}
+
+/// Stops a running VM from another thread. See [`Vm::interrupt_handle`].
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Asks the VM to stop. The run notices within a few thousand
+    /// instructions and fails with [`StackError::Interrupted`], leaving the
+    /// VM suspended so [`Vm::run`] resumes it. Cleared by [`Vm::reset`].
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A virtual machine with a program loaded.
+///
+/// A VM runs its program once per reset: [`Vm::run`] on a VM that has
+/// already run fails with [`StackError::InvalidState`] until [`Vm::reset`]
+/// or [`Vm::run_with_inputs`].
+pub struct Vm {
+    vm: VM,
+}
+
+impl Vm {
+    /// Runs the program and returns the values it halted or returned with,
+    /// in push order.
+    pub fn run(&mut self) -> Result<Vec<StackValue>, VmError> {
+        Ok(self.vm.execute()?)
+    }
+
+    /// Resets the VM and runs the program with `inputs` pushed onto the
+    /// stack in order.
+    pub fn run_with_inputs(&mut self, inputs: Vec<StackValue>) -> Result<Vec<StackValue>, VmError> {
+        self.vm.load_inputs(inputs)?;
+
+        self.run()
+    }
+
+    /// Runs `instructions` against the current stack and memory without
+    /// resetting them, for interactive use. They replace the program and
+    /// run unoptimized on the stack backend. Returns the values HALT or
+    /// RETURN handed back, or None when the instructions ran off the end.
+    /// Neither ends the session: the VM accepts more instructions after
+    /// returning or failing, until [`Vm::reset`] clears it.
+    pub fn run_incremental(&mut self, instructions: Vec<OpCode>) -> Result<Option<Vec<StackValue>>, VmError> {
+        Ok(self.vm.execute_incremental(instructions)?)
+    }
+
+    /// Values on the stack, bottom first.
+    pub fn stack(&self) -> Vec<StackValue> {
+        self.vm.stack_values()
+    }
+
+    /// Clears the stack, memory and statistics so the program can run
+    /// again.
+    pub fn reset(&mut self) {
+        self.vm.reset();
+    }
+
+    /// Replaces the program and resets the VM, keeping its allocations.
+    /// The program is prepared with the VM's settings.
+    pub fn load_program(&mut self, instructions: Vec<OpCode>) {
+        self.vm.load_program(instructions);
+    }
+
+    /// Replaces the program with a loaded one and resets the VM, keeping
+    /// its allocations. The VM takes on the program's settings.
+    pub fn set_program(&mut self, program: Arc<Program>) {
+        self.vm.set_program(program);
+    }
+
+    pub fn program(&self) -> &Arc<Program> {
+        self.vm.program()
+    }
+
+    /// Context of the last failed run, or None if it succeeded.
+    pub fn last_error(&self) -> Option<&ErrorReport> {
+        self.vm.last_error()
+    }
+
+    /// Statistics of the runs since the last reset.
+    pub fn receipt(&self) -> ExecutionReceipt {
+        self.vm.receipt()
+    }
+
+    /// Most bytes held in byte arrays and linear memory since the last
+    /// reset.
+    pub fn peak_memory(&self) -> usize {
+        self.vm.peak_memory()
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.vm.interrupt_flag())
+    }
+
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.vm.set_trace(enabled);
+    }
+
+    /// Enabling starts a fresh per-instruction profile that accumulates
+    /// over every run until profiling is enabled again or the program
+    /// changes.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.vm.set_profiling(enabled);
+    }
+
+    /// The profile as a text hot-spot table, or as folded stacks for flame
+    /// graph tools. None while profiling is disabled.
+    pub fn profile_report(&self, folded: bool) -> Option<String> {
+        self.vm.profile_report(folded)
+    }
+
+    /// Serializes the VM into a versioned snapshot, usually taken while it
+    /// is suspended after an interrupt.
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.vm.snapshot().encode()
+    }
+
+    /// Rebuilds a VM from [`Vm::snapshot`] bytes, possibly taken in another
+    /// process. `instructions` must be the program the snapshot was taken
+    /// from, as it was originally loaded.
+    pub fn restore(snapshot: &[u8], instructions: Vec<OpCode>) -> Result<Vm, VmError> {
+        let mut vm = VM::restore(VMSnapshot::decode(snapshot)?, instructions)?;
+        vm.set_trace(false);
+
+        Ok(Vm { vm })
+    }
+
+    pub(crate) fn mark_faulted(&mut self) {
+        self.vm.mark_faulted();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn ran_register_blocks(&self) -> bool {
+        self.vm.ran_register_blocks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::{Backend, OpCode, StackError, StackValue, Vm, VmBuilder, VmError, MAX_STACK_SIZE};
+
+    #[test]
+    fn test_api_builds_and_runs_programs() {
+        let builder = VmBuilder::new().stack_size(8).backend(Backend::Register).optimize(true);
+
+        let mut vm = builder.build(vec![OpCode::PUSHINT(6), OpCode::PUSHINT(7), OpCode::MUL, OpCode::HALT]).unwrap();
+        assert_eq!(vm.run(), Ok(vec![StackValue::Integer(42)]));
+        assert_eq!(vm.run(), Err(VmError::Execution(StackError::InvalidState)));
+
+        let program = builder.load(vec![OpCode::DIV, OpCode::HALT]).unwrap();
+        let workers: Vec<_> = (1..=4).map(|i| {
+            let mut vm = builder.build_from_program(program.clone()).unwrap();
+
+            thread::spawn(move || vm.run_with_inputs(vec![StackValue::Integer(12), StackValue::Integer(i)]))
+        }).collect();
+
+        let results: Vec<_> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+        assert_eq!(results[3], Ok(vec![StackValue::Integer(3)]));
+
+        let mut vm = builder.build_from_program(program).unwrap();
+        assert_eq!(vm.run_with_inputs(vec![StackValue::Integer(1), StackValue::Integer(0)]),
+            Err(VmError::Execution(StackError::DivisionByZero)));
+        assert_eq!(vm.last_error().unwrap().ip, Some(0));
+
+        let rejected = VmBuilder::new().stack_size(0).build(vec![OpCode::HALT]);
+        assert_eq!(rejected.err().map(|e| e.to_string()), Some("stack size must be from 1 to 1048576".to_string()));
+        assert!(VmBuilder::new().stack_size(MAX_STACK_SIZE + 1).build(vec![OpCode::HALT]).is_err());
+    }
+
+    #[test]
+    fn test_api_snapshots_interrupted_runs() {
+        let program = vec![OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::ADD, OpCode::HALT];
+        let mut vm = VmBuilder::new().build(program.clone()).unwrap();
+
+        vm.interrupt_handle().interrupt();
+        assert_eq!(vm.run(), Err(VmError::Execution(StackError::Interrupted)));
+
+        let mut restored = Vm::restore(&vm.snapshot(), program).unwrap();
+        assert_eq!(restored.run(), Ok(vec![StackValue::Integer(3)]));
+
+        assert!(matches!(Vm::restore(&[], Vec::new()), Err(VmError::Snapshot(_))));
+    }
+
+    #[test]
+    fn test_api_runs_incrementally() {
+        let mut vm = VmBuilder::new().stack_size(4).max_steps(7).build(Vec::new()).unwrap();
+
+        assert_eq!(vm.run_incremental(vec![OpCode::PUSHINT(6), OpCode::PUSHINT(7)]), Ok(None));
+        assert_eq!(vm.run_incremental(vec![OpCode::MUL, OpCode::PUSHINT(0)]), Ok(None));
+        assert_eq!(vm.stack(), vec![StackValue::Integer(42), StackValue::Integer(0)]);
+
+        // A failing instruction leaves what it did not consume.
+        assert_eq!(vm.run_incremental(vec![OpCode::DIV]), Err(VmError::Execution(StackError::DivisionByZero)));
+        assert_eq!(vm.last_error().unwrap().ip, Some(0));
+        assert_eq!(vm.stack(), Vec::new());
+
+        assert_eq!(vm.run_incremental(vec![OpCode::PUSHINT(1), OpCode::HALT]), Ok(Some(vec![StackValue::Integer(1)])));
+        assert_eq!(vm.run_incremental(vec![OpCode::PUSHINT(1)]), Err(VmError::Execution(StackError::StepLimitExceeded)));
+        assert_eq!(vm.receipt().instructions_executed, 7);
+
+        vm.reset();
+        assert_eq!(vm.run_incremental(vec![OpCode::PUSHINT(1)]), Ok(None));
+    }
+}
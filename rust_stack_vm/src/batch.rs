@@ -0,0 +1,214 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use crate::api::{Vm, VmBuilder, VmError};
+use crate::guard::panic_message;
+use crate::handle::Handle;
+use crate::stack::composite_stack::StackValue;
+use crate::vm::result::VMResult;
+use crate::vm::vm::Program;
+
+// One entry of a run_vm_batch call: a program from load_program and the
+// inputs to push before running it.
+#[repr(C)]
+pub struct VMJob {
+    pub program: Handle,
+    pub inputs_ptr: *const VMResult,
+    pub inputs_len: usize,
+}
+
+pub struct Job {
+    pub program: Arc<Program>,
+    pub inputs: Vec<StackValue>,
+}
+
+// The values a job returned, or the message of the panic that aborted it.
+pub type Outcome = Result<Result<Vec<StackValue>, VmError>, String>;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+// Worker threads shared by every batch. They are started on first use and
+// live for the rest of the process; a batch asking for more threads than
+// have been started starts the missing ones.
+struct Workers {
+    sender: Mutex<Sender<Task>>,
+    receiver: Arc<Mutex<Receiver<Task>>>,
+    started: Mutex<usize>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn workers() -> &'static Workers {
+    static WORKERS: OnceLock<Workers> = OnceLock::new();
+
+    WORKERS.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+
+        Workers {
+            sender: Mutex::new(sender),
+            receiver: Arc::new(Mutex::new(receiver)),
+            started: Mutex::new(0),
+        }
+    })
+}
+
+impl Workers {
+    fn ensure(&self, count: usize) {
+        let mut started = lock(&self.started);
+
+        while *started < count {
+            let receiver = Arc::clone(&self.receiver);
+
+            thread::Builder::new()
+                .name(format!("vm-batch-{}", *started))
+                .spawn(move || loop {
+                    let task = lock(&receiver).recv();
+
+                    match task {
+                        Ok(task) => task(),
+                        Err(_) => return,
+                    }
+                })
+                .expect("failed to start a batch worker");
+
+            *started += 1;
+        }
+    }
+
+    fn submit(&self, task: Task) {
+        lock(&self.sender).send(task).expect("batch workers have stopped");
+    }
+}
+
+// Runs every job on VMs made by builder and returns their outcomes in job
+// order. With more than one thread, the calling thread and threads - 1
+// workers take jobs in turn until none are left.
+pub fn run_batch(jobs: Vec<Job>, builder: VmBuilder, threads: usize) -> Vec<Outcome> {
+    let len = jobs.len();
+    let threads = threads.clamp(1, len.max(1));
+
+    if threads == 1 {
+        let mut outcomes = Vec::with_capacity(len);
+        drain(&jobs, &AtomicUsize::new(0), &builder, |_, outcome| outcomes.push(outcome));
+
+        return outcomes;
+    }
+
+    let jobs = Arc::new(jobs);
+    let builder = Arc::new(builder);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    workers().ensure(threads - 1);
+
+    for _ in 1..threads {
+        let (jobs, next, builder) = (Arc::clone(&jobs), Arc::clone(&next), Arc::clone(&builder));
+        let sender = sender.clone();
+
+        workers().submit(Box::new(move || {
+            drain(&jobs, &next, &builder, |index, outcome| {
+                // The batch only stops listening once it has every outcome.
+                let _ = sender.send((index, outcome));
+            });
+        }));
+    }
+
+    let mut outcomes: Vec<Option<Outcome>> = (0..len).map(|_| None).collect();
+    let mut received = 0;
+
+    drain(&jobs, &next, &builder, |index, outcome| {
+        outcomes[index] = Some(outcome);
+        received += 1;
+    });
+
+    while received < len {
+        let (index, outcome) = receiver.recv().expect("batch worker disappeared");
+
+        outcomes[index] = Some(outcome);
+        received += 1;
+    }
+
+    outcomes.into_iter().map(|outcome| outcome.expect("every job has an outcome")).collect()
+}
+
+// Takes jobs until none are left. One VM is kept across jobs and only
+// switched to a new program when the next job needs one.
+fn drain(jobs: &[Job], next: &AtomicUsize, builder: &VmBuilder, mut done: impl FnMut(usize, Outcome)) {
+    let mut vm: Option<Vm> = None;
+
+    loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+
+        let Some(job) = jobs.get(index) else {
+            return;
+        };
+
+        done(index, run_job(&mut vm, job, builder));
+    }
+}
+
+fn run_job(slot: &mut Option<Vm>, job: &Job, builder: &VmBuilder) -> Outcome {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let vm = match slot {
+            Some(vm) if Arc::ptr_eq(vm.program(), &job.program) => vm,
+            Some(vm) => {
+                vm.set_program(Arc::clone(&job.program));
+                vm
+            },
+            None => slot.insert(builder.build_from_program(Arc::clone(&job.program))?),
+        };
+
+        vm.run_with_inputs(job.inputs.clone())
+    }));
+
+    // A VM a panic left half updated is not reused.
+    result.map_err(|payload| {
+        *slot = None;
+
+        panic_message(payload.as_ref())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::api::{VmBuilder, VmError};
+    use crate::batch::{run_batch, Job};
+    use crate::stack::composite_stack::StackValue;
+    use crate::stack::stack::StackError;
+    use crate::vm::config::VMConfig;
+    use crate::vm::op::OpCode;
+    use crate::vm::vm::Program;
+
+    #[test]
+    fn test_batch_outcomes_follow_job_order() {
+        let add = Arc::new(Program::new(vec![OpCode::ADD, OpCode::HALT], VMConfig::default()));
+        let div = Arc::new(Program::new(vec![OpCode::DIV, OpCode::HALT], VMConfig::default()));
+
+        let jobs = || (0..64).map(|i| Job {
+            program: Arc::clone(if i % 3 == 0 { &div } else { &add }),
+            inputs: vec![StackValue::Integer(i), StackValue::Integer(i % 2)],
+        }).collect::<Vec<_>>();
+
+        let expected: Vec<_> = (0..64).map(|i| match (i % 3, i % 2) {
+            (0, 0) => Err(VmError::Execution(StackError::DivisionByZero)),
+            (0, _) => Ok(vec![StackValue::Integer(i)]),
+            _ => Ok(vec![StackValue::Integer(i + i % 2)]),
+        }).collect();
+
+        for threads in [0, 1, 4] {
+            let outcomes: Vec<_> = run_batch(jobs(), VmBuilder::new().stack_size(4), threads)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+
+            assert_eq!(outcomes, expected);
+        }
+
+        assert!(run_batch(Vec::new(), VmBuilder::new(), 4).is_empty());
+    }
+}
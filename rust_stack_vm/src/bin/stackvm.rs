@@ -0,0 +1,293 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+use rust_stack_vm::{
+    assemble, decode_binary, disassemble, encode_binary, verify, Backend, OpCode, StackValue, VmBuilder, VmError,
+    DEFAULT_STACK_SIZE,
+};
+
+// Runs programs without the Go host, mostly to reproduce bugs from
+// programs captured in production:
+//
+//   stackvm run program.asm 1 2.5 bytes:68690a
+//   stackvm asm program.asm -o program.bin
+//   stackvm disasm program.bin
+//
+// Programs are text assembly or the binary form the host passes to
+// create_vm. The format is guessed from the contents unless --format is
+// given: binary programs always hold zero bytes and text never does.
+
+const USAGE: &str = "\
+usage: stackvm <command> [options] <program|-> [inputs...]
+
+commands:
+  run       run the program and print the values it returns
+  trace     run the program, printing the stack after every instruction
+  verify    check the program's stack depth without running it
+  asm       write the program in the binary form
+  disasm    write the program as assembly
+
+options:
+  --format text|binary      program format, guessed from the contents by default
+  --stack-size N            stack slots (default 1024)
+  --max-steps N             fail after N instructions
+  --max-memory N            fail when byte arrays and memory exceed N bytes
+  --backend stack|register  interpreter to run with
+  --optimize                optimize the program before running it
+  --deterministic-float     canonicalize NaN results
+  --ieee-float-division     float division by zero gives infinity or NaN
+  --stats                   print execution statistics to stderr
+  -o FILE                   where asm and disasm write (default stdout)
+
+inputs are pushed in order before the run: 42, 1.5, true, or typed as
+int:42, float:1.5, byte:7, bool:false, bytes:68690a.
+";
+
+enum Command {
+    Run,
+    Trace,
+    Verify,
+    Asm,
+    Disasm,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Text,
+    Binary,
+}
+
+struct Options {
+    command: Command,
+    format: Option<Format>,
+    builder: VmBuilder,
+    stack_size: usize,
+    stats: bool,
+    output: Option<String>,
+    program: String,
+    inputs: Vec<StackValue>,
+}
+
+// A failure to report before exiting. Usage errors also print the usage.
+enum Failure {
+    Usage(String),
+    Error(String),
+}
+
+fn usage<T>(message: impl Into<String>) -> Result<T, Failure> {
+    Err(Failure::Usage(message.into()))
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, Failure> {
+    let Some(value) = value else {
+        return usage(format!("{} needs a value", option));
+    };
+
+    value.parse().or_else(|_| usage(format!("invalid value '{}' for {}", value, option)))
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn parse_input(text: &str) -> Result<StackValue, String> {
+    let invalid = || format!("invalid input '{}'", text);
+
+    let value = match text.split_once(':') {
+        Some(("int", value)) => StackValue::Integer(value.parse().map_err(|_| invalid())?),
+        Some(("float", value)) => StackValue::Float(value.parse().map_err(|_| invalid())?),
+        Some(("byte", value)) => StackValue::Byte(value.parse().map_err(|_| invalid())?),
+        Some(("bool", value)) => StackValue::Bool(value.parse().map_err(|_| invalid())?),
+        Some(("bytes", value)) => StackValue::ByteArray(parse_hex(value).ok_or_else(invalid)?.into()),
+        Some(_) => return Err(invalid()),
+        None => match (text.parse::<i32>(), text.parse::<bool>(), text.parse::<f32>()) {
+            (Ok(i), _, _) => StackValue::Integer(i),
+            (_, Ok(b), _) => StackValue::Bool(b),
+            (_, _, Ok(f)) => StackValue::Float(f),
+            _ => return Err(invalid()),
+        },
+    };
+
+    Ok(value)
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, Failure> {
+    let command = match args.next().as_deref() {
+        Some("run") => Command::Run,
+        Some("trace") => Command::Trace,
+        Some("verify") => Command::Verify,
+        Some("asm") => Command::Asm,
+        Some("disasm") => Command::Disasm,
+        Some(other) => return usage(format!("unknown command '{}'", other)),
+        None => return usage("missing command"),
+    };
+
+    let mut builder = VmBuilder::new();
+    let mut format = None;
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut stats = false;
+    let mut output = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(match args.next().as_deref() {
+                Some("text") => Format::Text,
+                Some("binary") => Format::Binary,
+                _ => return usage("--format takes text or binary"),
+            }),
+            "--stack-size" => stack_size = number(&arg, args.next())?,
+            "--max-steps" => builder = builder.max_steps(number(&arg, args.next())?),
+            "--max-memory" => builder = builder.max_memory_bytes(number(&arg, args.next())?),
+            "--backend" => builder = builder.backend(match args.next().as_deref() {
+                Some("stack") => Backend::Stack,
+                Some("register") => Backend::Register,
+                _ => return usage("--backend takes stack or register"),
+            }),
+            "--optimize" => builder = builder.optimize(true),
+            "--deterministic-float" => builder = builder.deterministic_float(true),
+            "--ieee-float-division" => builder = builder.ieee_float_division(true),
+            "--stats" => stats = true,
+            "-o" => output = Some(args.next().map_or_else(|| usage("-o needs a file"), Ok)?),
+            "-h" | "--help" => return usage(""),
+            // Negative numbers are inputs, not options.
+            option if option.starts_with("--") || (option.starts_with('-') && option.len() > 1
+                && parse_input(option).is_err()) => return usage(format!("unknown option '{}'", option)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+
+    let Some(program) = positional.next() else {
+        return usage("missing program");
+    };
+
+    let inputs = positional
+        .map(|input| parse_input(&input))
+        .collect::<Result<Vec<_>, _>>()
+        .or_else(usage)?;
+
+    if !inputs.is_empty() && matches!(command, Command::Asm | Command::Disasm) {
+        return usage("asm and disasm take no inputs");
+    }
+
+    Ok(Options {
+        command,
+        format,
+        builder: builder.stack_size(stack_size),
+        stack_size,
+        stats,
+        output,
+        program,
+        inputs,
+    })
+}
+
+fn read_program(options: &Options) -> Result<Vec<OpCode>, Failure> {
+    let bytes = if options.program == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(&options.program)
+    }.map_err(|e| Failure::Error(format!("cannot read {}: {}", options.program, e)))?;
+
+    let format = options.format.unwrap_or(if bytes.contains(&0) { Format::Binary } else { Format::Text });
+
+    let program = match format {
+        Format::Binary => decode_binary(&bytes),
+        Format::Text => {
+            let source = String::from_utf8(bytes)
+                .map_err(|_| Failure::Error(format!("{} is not UTF-8 text", options.program)))?;
+
+            assemble(&source)
+        },
+    };
+
+    program.map_err(|e| Failure::Error(format!("{}: {}", options.program, e)))
+}
+
+fn write_output(options: &Options, bytes: &[u8]) -> Result<(), Failure> {
+    match &options.output {
+        Some(path) => fs::write(path, bytes).map_err(|e| Failure::Error(format!("cannot write {}: {}", path, e))),
+        None => io::stdout().write_all(bytes).map_err(|e| Failure::Error(format!("cannot write output: {}", e))),
+    }
+}
+
+fn run(options: Options, trace: bool) -> Result<(), Failure> {
+    let program = read_program(&options)?;
+
+    let mut vm = options.builder.trace(trace).build(program).map_err(|e| Failure::Error(e.to_string()))?;
+    let result = vm.run_with_inputs(options.inputs);
+
+    if options.stats {
+        let receipt = vm.receipt();
+
+        eprintln!("instructions executed: {}", receipt.instructions_executed);
+        eprintln!("gas used:              {}", receipt.gas_used);
+        eprintln!("peak stack depth:      {}", receipt.peak_stack_depth);
+        eprintln!("peak memory:           {} bytes", receipt.peak_memory);
+        eprintln!("frames pushed:         {}", receipt.frames_pushed);
+        eprintln!("wall time:             {} ns", receipt.wall_time_ns);
+    }
+
+    match result {
+        Ok(values) => {
+            for value in values {
+                println!("{:?}", value);
+            }
+
+            Ok(())
+        },
+        Err(VmError::Execution(error)) => Err(Failure::Error(match vm.last_error() {
+            Some(report) => report.message(),
+            None => error.to_string(),
+        })),
+        Err(e) => Err(Failure::Error(e.to_string())),
+    }
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(env::args().skip(1)).and_then(|options| match options.command {
+        Command::Run => run(options, false),
+        Command::Trace => run(options, true),
+        Command::Verify => {
+            let program = read_program(&options)?;
+            let verification = verify(&program, options.inputs.len(), options.stack_size)
+                .map_err(|e| Failure::Error(e.to_string()))?;
+
+            for warning in &verification.warnings {
+                eprintln!("warning: {}", warning);
+            }
+
+            println!("ok: {} instructions, max stack depth {}", program.len(), verification.max_stack_depth);
+            Ok(())
+        },
+        Command::Asm => write_output(&options, &encode_binary(&read_program(&options)?)),
+        Command::Disasm => write_output(&options, disassemble(&read_program(&options)?).as_bytes()),
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Error(message)) => {
+            eprintln!("stackvm: {}", message);
+            ExitCode::from(1)
+        },
+        Err(Failure::Usage(message)) => {
+            if !message.is_empty() {
+                eprintln!("stackvm: {}", message);
+            }
+
+            eprint!("{}", USAGE);
+            ExitCode::from(2)
+        },
+    }
+}
@@ -0,0 +1,103 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+use rust_stack_vm::{assemble, parse_instruction, OpCode, Vm, VmBuilder, VmError, DEFAULT_STACK_SIZE};
+
+// An interactive session for trying out instructions. Each line of
+// assembly runs against the stack the previous lines left behind, and the
+// stack is printed after every line:
+//
+//   > PUSHINT 6
+//   [Integer(6)]
+//   > PUSHINT 7
+//   [Integer(6), Integer(7)]
+//   > MUL
+//   [Integer(42)]
+
+const HELP: &str = "\
+enter one instruction per line, e.g. PUSHINT 6 or ADD. commands:
+  :stack        print the stack
+  :reset        clear the stack, memory and statistics
+  :load FILE    run every instruction of an assembly file
+  :help         show this help
+  :quit         leave (or end the input)
+";
+
+fn load(path: &str) -> Result<Vec<OpCode>, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+
+    assemble(&source).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn run(vm: &mut Vm, instructions: Vec<OpCode>) {
+    match vm.run_incremental(instructions) {
+        Ok(None) => {},
+        Ok(Some(values)) => println!("=> {:?}", values),
+        Err(VmError::Execution(error)) => match vm.last_error() {
+            Some(report) => println!("error: {}", report.message()),
+            None => println!("error: {}", error),
+        },
+        Err(e) => println!("error: {}", e),
+    }
+
+    println!("{:?}", vm.stack());
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let mut stack_size = DEFAULT_STACK_SIZE;
+
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next().map(|value| value.parse())) {
+            ("--stack-size", Some(Ok(size))) => stack_size = size,
+            _ => {
+                eprintln!("usage: stackvm_repl [--stack-size N]");
+                return ExitCode::from(2);
+            },
+        }
+    }
+
+    let mut vm = match VmBuilder::new().stack_size(stack_size).build(Vec::new()) {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprintln!("stackvm_repl: {}", e);
+            return ExitCode::from(2);
+        },
+    };
+
+    let mut lines = io::stdin().lock().lines();
+
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            return ExitCode::SUCCESS;
+        };
+
+        let line = line.trim();
+
+        match line.split_once(char::is_whitespace).unwrap_or((line, "")) {
+            (":quit" | ":q", _) => return ExitCode::SUCCESS,
+            (":help", _) => print!("{}", HELP),
+            (":stack", _) => println!("{:?}", vm.stack()),
+            (":reset", _) => {
+                vm.reset();
+                println!("{:?}", vm.stack());
+            },
+            (":load", path) if !path.trim().is_empty() => match load(path.trim()) {
+                Ok(instructions) => run(&mut vm, instructions),
+                Err(message) => println!("error: {}", message),
+            },
+            (":load", _) => println!("error: :load needs a file"),
+            (command, _) if command.starts_with(':') => println!("error: unknown command '{}', try :help", line),
+            _ => match parse_instruction(line) {
+                Ok(Some(op)) => run(&mut vm, vec![op]),
+                Ok(None) => {},
+                Err(message) => println!("error: {}", message),
+            },
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+
+thread_local! {
+    // Message of the last panic or rejected argument seen by an exported
+    // function on this thread. Kept per thread so concurrent hosts never
+    // read each other's messages.
+    static LAST_FFI_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn set_last_ffi_error(message: String) {
+    LAST_FFI_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+pub fn last_ffi_error() -> Option<String> {
+    LAST_FFI_ERROR.with(|last| last.borrow().clone())
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Runs f and converts a panic into the value built by on_panic, so no
+// unwind ever crosses an extern "C" boundary.
+pub fn guard<T>(f: impl FnOnce() -> T, on_panic: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(payload) => {
+            set_last_ffi_error(panic_message(payload.as_ref()));
+
+            on_panic()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guard::{guard, last_ffi_error};
+
+    #[test]
+    fn test_guard_catches_panic() {
+        assert_eq!(guard(|| 1, || 0), 1);
+
+        let caught = guard(|| -> i32 { panic!("Unknown opcode: {}", 0xFF) }, || -1);
+
+        assert_eq!(caught, -1);
+        assert_eq!(last_ffi_error(), Some("Unknown opcode: 255".to_string()));
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+// Opaque handle given to the host instead of a raw pointer. The low 32 bits
+// index a slot and the high 32 bits hold the slot generation, which is bumped
+// whenever the slot is freed, so a stale handle never reaches a new value.
+// Generations start at 1, so 0 is never a valid handle. Values are shared
+// through Arc so a lookup does not hold the table; callers that mutate them
+// store their own locks.
+pub type Handle = u64;
+
+pub const INVALID_HANDLE: Handle = 0;
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<Arc<T>>,
+}
+
+pub struct HandleTable<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+fn split(handle: Handle) -> (usize, u32) {
+    ((handle & 0xFFFF_FFFF) as usize, (handle >> 32) as u32)
+}
+
+fn join(index: usize, generation: u32) -> Handle {
+    ((generation as u64) << 32) | index as u64
+}
+
+impl<T> HandleTable<T> {
+    pub const fn new() -> Self {
+        HandleTable {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle {
+        self.insert_shared(Arc::new(value))
+    }
+
+    // Inserts a value that is already shared outside the table.
+    pub fn insert_shared(&mut self, value: Arc<T>) -> Handle {
+        let value = Some(value);
+
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.value = value;
+
+            return join(index as usize, slot.generation);
+        }
+
+        self.slots.push(Slot { generation: 1, value });
+
+        join(self.slots.len() - 1, 1)
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<Arc<T>> {
+        let (index, generation) = split(handle);
+
+        match self.slots.get(index) {
+            Some(slot) if slot.generation == generation => slot.value.clone(),
+            _ => None,
+        }
+    }
+
+    // Removes the value and retires the handle. The value itself is dropped
+    // once the last in-flight user releases it.
+    pub fn remove(&mut self, handle: Handle) -> Option<Arc<T>> {
+        let (index, generation) = split(handle);
+
+        let slot = match self.slots.get_mut(index) {
+            Some(slot) if slot.generation == generation => slot,
+            _ => return None,
+        };
+
+        let value = slot.value.take()?;
+
+        // Skip generation 0 on wrap-around so INVALID_HANDLE stays invalid.
+        slot.generation = slot.generation.checked_add(1).unwrap_or(1);
+        self.free.push(index as u32);
+
+        Some(value)
+    }
+
+    pub fn live_handles(&self) -> Vec<Handle> {
+        self.slots.iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.value.is_some())
+            .map(|(index, slot)| join(index, slot.generation))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handle::{HandleTable, INVALID_HANDLE};
+
+    #[test]
+    fn test_handle_table_generations() {
+        let mut table = HandleTable::new();
+
+        let first = table.insert(1);
+        assert_ne!(first, INVALID_HANDLE);
+        assert_eq!(*table.get(first).unwrap(), 1);
+
+        assert!(table.remove(first).is_some());
+        assert!(table.remove(first).is_none());
+        assert!(table.get(first).is_none());
+
+        let second = table.insert(2);
+        assert_ne!(first, second);
+        assert!(table.get(first).is_none());
+        assert_eq!(*table.get(second).unwrap(), 2);
+
+        assert!(table.get(INVALID_HANDLE).is_none());
+        assert_eq!(table.live_handles(), vec![second]);
+    }
+}
@@ -1,86 +1,1088 @@
+//! A stack-based virtual machine, usable from Rust and, through the C API
+//! declared in `C_headers/stack_vm.h`, from any language with a C FFI.
+//!
+//! Rust code builds VMs with [`VmBuilder`]:
+//!
+//! ```
+//! use rust_stack_vm::{OpCode, StackValue, VmBuilder};
+//!
+//! let builder = VmBuilder::new().stack_size(16).max_steps(1_000);
+//!
+//! let mut vm = builder.build(vec![OpCode::PUSHINT(6), OpCode::PUSHINT(7), OpCode::MUL, OpCode::HALT])?;
+//! assert_eq!(vm.run()?, vec![StackValue::Integer(42)]);
+//!
+//! // A program loaded once can be shared by any number of VMs.
+//! let program = builder.load(vec![OpCode::ADD, OpCode::HALT])?;
+//! let mut vm = builder.build_from_program(program)?;
+//! assert_eq!(vm.run_with_inputs(vec![StackValue::Integer(1), StackValue::Integer(2)])?, vec![StackValue::Integer(3)]);
+//! # Ok::<(), rust_stack_vm::VmError>(())
+//! ```
+
 mod vm;
 mod stack;
+mod guard;
+mod handle;
+mod pool;
+mod batch;
+mod api;
+
+pub use api::{Backend, InterruptHandle, Vm, VmBuilder, VmError, DEFAULT_STACK_SIZE};
+pub use stack::bytes::Bytes;
+pub use stack::composite_stack::StackValue;
+pub use stack::stack::StackError;
+pub use vm::asm::{
+    assemble, decode_binary, disassemble, encode_binary, format_instruction, parse_instruction, AsmError,
+    BINARY_INSTRUCTION_SIZE,
+};
+pub use vm::error::ErrorReport;
+pub use vm::op::OpCode;
+pub use vm::receipt::ExecutionReceipt;
+pub use vm::snapshot::SnapshotError;
+pub use vm::verify::{verify, Verification, VerifyError};
+pub use vm::vm::{Program, MAX_STACK_SIZE};
 
+use std::collections::HashSet;
+use std::ffi::{c_char, CString};
+use std::ptr;
 use std::slice;
-use vm::vm::VM;
-use vm::op::{OpCode, Operation};
-use stack::stack::StackError;
-use crate::stack::composite_stack::StackValue;
-use crate::vm::result::VMResult;
-use crate::vm::vm::VirtualMachine;
+use std::sync::{Arc, Mutex, MutexGuard};
+use vm::op::Operation;
+use crate::guard::{guard, last_ffi_error, set_last_ffi_error};
+use crate::handle::{Handle, HandleTable, INVALID_HANDLE};
+use crate::pool::VMPool;
+use crate::batch::{run_batch, Job, VMJob};
+use crate::vm::result::{
+    VMErrorReport, VMHandleArray, VMResult, VMResultArray, VMResultTag, ERROR_INVALID_HANDLE, ERROR_NULL_POINTER,
+};
+use crate::vm::config::VMConfig;
+use crate::vm::result::ByteArrayPtr;
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn create_vm(
-    stack_size: usize,
+// The C API. Every function is a thin wrapper over the Rust API above that
+// maps handles to VMs and programs, and errors to result codes.
+//
+// Every exported function runs its body inside guard, so a panic anywhere
+// in the VM becomes a Panic tagged result (or INVALID_HANDLE for
+// constructors) and its message is available from last_ffi_error_message.
+
+// Live VMs. The host only ever sees generation-checked handles into this
+// table, so double frees and use-after-free are reported instead of
+// corrupting memory. The table lock is held just long enough to look a VM
+// up; execution only locks the VM itself.
+static VMS: Mutex<HandleTable<VMEntry>> = Mutex::new(HandleTable::new());
+
+fn vms() -> MutexGuard<'static, HandleTable<VMEntry>> {
+    VMS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// The interrupt flag is kept next to the VM lock, so interrupt_vm can reach
+// a VM while another thread is executing it.
+struct VMEntry {
+    vm: Mutex<Vm>,
+    interrupt: InterruptHandle,
+}
+
+impl VMEntry {
+    fn new(vm: Vm) -> Self {
+        VMEntry {
+            interrupt: vm.interrupt_handle(),
+            vm: Mutex::new(vm),
+        }
+    }
+}
+
+// Registers a newly built VM, or records why it could not be built.
+fn insert_vm(vm: Result<Vm, VmError>) -> Handle {
+    match vm {
+        Ok(vm) => vms().insert(VMEntry::new(vm)),
+        Err(e) => {
+            set_last_ffi_error(e.to_string());
+            INVALID_HANDLE
+        },
+    }
+}
+
+// A pool together with the VM handles it has handed out, so only those can
+// be checked back into it. The pool locks itself; checked_out is locked
+// only to record or retire a handle.
+struct PoolEntry {
+    pool: VMPool,
+    checked_out: Mutex<HashSet<Handle>>,
+}
+
+impl PoolEntry {
+    fn checked_out(&self) -> MutexGuard<'_, HashSet<Handle>> {
+        self.checked_out.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+static POOLS: Mutex<HandleTable<PoolEntry>> = Mutex::new(HandleTable::new());
+
+fn pools() -> MutexGuard<'static, HandleTable<PoolEntry>> {
+    POOLS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Loaded programs. VMs created from a program hold their own reference
+// to it, so freeing the handle does not affect them.
+static PROGRAMS: Mutex<HandleTable<Program>> = Mutex::new(HandleTable::new());
+
+fn programs() -> MutexGuard<'static, HandleTable<Program>> {
+    PROGRAMS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Decodes host supplied operations, recording the reason on rejection.
+unsafe fn decode_instructions(
     instruction_ptr: *const Operation,
     instruction_len: usize,
-) -> *mut VM {
+) -> Option<Vec<OpCode>> {
+    if instruction_ptr.is_null() {
+        set_last_ffi_error("instruction pointer is null".to_string());
+        return None;
+    }
+
     let instruction_slice = unsafe {
         slice::from_raw_parts(instruction_ptr, instruction_len)
     };
-    
-    let instructions: Vec<OpCode> = instruction_slice.iter().map(|opcode| {
-        match opcode.kind {
-            0x00 => OpCode::HALT,
-            0x01 => OpCode::PUSHINT( unsafe { opcode.val.int_val } ),
-            0x02 => OpCode::PUSHFLOAT( unsafe { opcode.val.float_val } ),
-            0x03 => OpCode::PUSHBYTE( unsafe { opcode.val.byte_val } ),
-            0x04 => OpCode::PACK( unsafe { opcode.val.uint_val } ),
-            0x05 => OpCode::POP,
-            0x06 => OpCode::ADD,
-            0x07 => OpCode::SUB,
-            0x08 => OpCode::MUL,
-            0x09 => OpCode::DIV,
-            0x0A => OpCode::EQ,
-            0x0B => OpCode::GT,
-            0x0C => OpCode::GTE,
-            0x0D => OpCode::LT,
-            0x0E => OpCode::LTE,
-            0x0F => OpCode::CONCAT,
-            _ => panic!("Unknown opcode: {}", opcode.kind),
-        }
-    }).collect();
-
-    let vm = VM::new(stack_size, instructions);
-
-    Box::into_raw(Box::new(vm))
-}
-
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn run_vm(vm_ptr: *mut VM) -> VMResult {
-    let vm = unsafe {
-        &mut *vm_ptr
-    };
 
-    match vm.execute() {
-        Ok(stack_value) => match stack_value {
-            StackValue::Integer(i) => VMResult::ok_int(i),
-            StackValue::Float(f) => VMResult::ok_float(f),
-            StackValue::Byte(b) => VMResult::ok_byte(b),
-            StackValue::ByteArray(vec) => VMResult::ok_byte_array(vec),
-            StackValue::Bool(b) => VMResult::ok_bool(b),
+    let mut instructions: Vec<OpCode> = Vec::with_capacity(instruction_len);
+
+    for operation in instruction_slice {
+        match operation.decode() {
+            Some(opcode) => instructions.push(opcode),
+            None => {
+                set_last_ffi_error(format!("Unknown opcode: {}", operation.kind));
+                return None;
+            }
+        }
+    }
+
+    Some(instructions)
+}
+
+/// Returns `INVALID_HANDLE` (0) if an argument is rejected; the reason is
+/// available from `last_ffi_error_message`.
+///
+/// # Safety
+///
+/// `instruction_ptr` must point to `instruction_len` valid `Operation`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn create_vm(
+    stack_size: usize,
+    instruction_ptr: *const Operation,
+    instruction_len: usize,
+) -> Handle {
+    unsafe { create_vm_with_config(stack_size, instruction_ptr, instruction_len, VMConfig::default()) }
+}
+
+/// Returns `INVALID_HANDLE` (0) if an argument is rejected; the reason is
+/// available from `last_ffi_error_message`.
+///
+/// # Safety
+///
+/// `instruction_ptr` must point to `instruction_len` valid `Operation`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn create_vm_with_config(
+    stack_size: usize,
+    instruction_ptr: *const Operation,
+    instruction_len: usize,
+    config: VMConfig,
+) -> Handle {
+    guard(|| {
+        let Some(instructions) = (unsafe { decode_instructions(instruction_ptr, instruction_len) }) else {
+            return INVALID_HANDLE;
+        };
+
+        insert_vm(VmBuilder::with_config(config).stack_size(stack_size).build(instructions))
+    }, || INVALID_HANDLE)
+}
+
+/// Decodes and prepares a program once for `config`, so that any number of
+/// VMs can be created from it with `create_vm_from_program`. Returns a
+/// program handle, to be released with `free_program`, or `INVALID_HANDLE`
+/// (0) if an argument is rejected; the reason is available from
+/// `last_ffi_error_message`.
+///
+/// # Safety
+///
+/// `instruction_ptr` must point to `instruction_len` valid `Operation`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn load_program(
+    instruction_ptr: *const Operation,
+    instruction_len: usize,
+    config: VMConfig,
+) -> Handle {
+    guard(|| {
+        let Some(instructions) = (unsafe { decode_instructions(instruction_ptr, instruction_len) }) else {
+            return INVALID_HANDLE;
+        };
+
+        match VmBuilder::with_config(config).load(instructions) {
+            Ok(program) => programs().insert_shared(program),
+            Err(e) => {
+                set_last_ffi_error(e.to_string());
+                INVALID_HANDLE
+            },
+        }
+    }, || INVALID_HANDLE)
+}
+
+/// Releases the program handle. VMs already created from the program keep
+/// running it. Returns false if the handle is unknown or was already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn free_program(program: Handle) -> bool {
+    guard(|| {
+        let removed = programs().remove(program);
+
+        removed.is_some()
+    }, || false)
+}
+
+/// Creates a VM running a program from `load_program`, with the config the
+/// program was loaded with. The program is shared, not copied. Returns
+/// `INVALID_HANDLE` (0) if an argument is rejected; the reason is available
+/// from `last_ffi_error_message`.
+#[unsafe(no_mangle)]
+pub extern "C" fn create_vm_from_program(stack_size: usize, program: Handle) -> Handle {
+    guard(|| {
+        let Some(program) = programs().get(program) else {
+            set_last_ffi_error("invalid program handle".to_string());
+            return INVALID_HANDLE;
+        };
+
+        insert_vm(VmBuilder::new().stack_size(stack_size).build_from_program(program))
+    }, || INVALID_HANDLE)
+}
+
+fn top_result(result: Result<Vec<StackValue>, VmError>) -> VMResult {
+    match result {
+        Ok(mut values) => match values.pop() {
+            Some(stack_value) => VMResult::from(stack_value),
+            None => VMResult::from(StackError::StackUnderFlow),
         },
-        Err(e) => {
-            match e {
-                StackError::StackUnderFlow => VMResult::err(0),
-                StackError::StackOverFlow => VMResult::err(1),
-                StackError::StackInvalidType => VMResult::err(2),
-                StackError::DivisionByZero => VMResult::err(3),
+        Err(e) => VMResult::from(e),
+    }
+}
+
+// Runs f against the VM behind handle, or returns on_invalid if the handle
+// is stale or unknown. A panic leaves the VM Faulted so it cannot be rerun
+// from a half-updated state without a reset.
+fn with_vm<T>(
+    handle: Handle,
+    f: impl FnOnce(&mut Vm) -> T,
+    on_invalid: impl FnOnce() -> T,
+    on_panic: impl FnOnce() -> T,
+) -> T {
+    let entry = match guard(|| vms().get(handle), || None) {
+        Some(entry) => entry,
+        None => return on_invalid(),
+    };
+
+    let lock = || entry.vm.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    guard(|| f(&mut lock()), || {
+        guard(|| lock().mark_faulted(), || ());
+
+        on_panic()
+    })
+}
+
+// Result of a call on an unknown handle whose return value cannot say so
+// itself, such as an empty buffer or an all-zero receipt. The host tells
+// it apart through last_ffi_error_message.
+fn invalid_vm<T>(value: T) -> T {
+    set_last_ffi_error("invalid vm handle".to_string());
+    value
+}
+
+/// Runs the VM and returns the top of the returned values.
+///
+/// A VM runs once; further calls return an `InvalidState` error until the
+/// VM is reset with `reset_vm` or rerun with `run_vm_with_inputs`. A VM
+/// suspended by `interrupt_vm` resumes where it stopped.
+///
+/// An unknown or freed handle yields an `ERROR_INVALID_HANDLE` error.
+#[unsafe(no_mangle)]
+pub extern "C" fn run_vm(handle: Handle) -> VMResult {
+    with_vm(
+        handle,
+        |vm| top_result(vm.run()),
+        || VMResult::err(ERROR_INVALID_HANDLE),
+        VMResult::panic,
+    )
+}
+
+/// Resets the VM and reruns the loaded program with `inputs` pushed onto
+/// the stack in order. Byte array inputs are copied, so the host keeps
+/// ownership of them.
+///
+/// # Safety
+///
+/// `inputs_ptr` must point to `inputs_len` valid values. It may be null
+/// only when `inputs_len` is 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn run_vm_with_inputs(
+    handle: Handle,
+    inputs_ptr: *const VMResult,
+    inputs_len: usize,
+) -> VMResult {
+    if inputs_ptr.is_null() && inputs_len > 0 {
+        return VMResult::err(ERROR_NULL_POINTER);
+    }
+
+    with_vm(handle, |vm| {
+        let input_slice = if inputs_ptr.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(inputs_ptr, inputs_len) }
+        };
+
+        let inputs: Result<Vec<StackValue>, StackError> = input_slice.iter()
+            .map(|input| unsafe { input.to_stack_value() })
+            .collect();
+
+        match inputs {
+            Ok(inputs) => top_result(vm.run_with_inputs(inputs)),
+            Err(e) => VMResult::from(e),
+        }
+    }, || VMResult::err(ERROR_INVALID_HANDLE), VMResult::panic)
+}
+
+/// Runs a batch of jobs, each a program from `load_program` and its
+/// inputs, and returns the top of every job's returned values in job order,
+/// as `run_vm_with_inputs` would. Every job gets a fresh run of its program
+/// on a VM with `stack_size` slots; VMs are reused between jobs internally.
+///
+/// With `threads` above 1 the jobs run in parallel on up to that many
+/// threads: the calling one and workers of an internal pool, which are
+/// started on first use and reused by later batches. Results do not depend
+/// on the thread count.
+///
+/// A job with an unknown program handle yields an `ERROR_INVALID_HANDLE`
+/// error and a job that panicked yields a `Panic` result, whose message is
+/// available from `last_ffi_error_message`. The array and every byte array
+/// in it are released with a single `free_vm_result_array` call. Returns an
+/// empty array if an argument is rejected; the reason is available from
+/// `last_ffi_error_message`.
+///
+/// # Safety
+///
+/// `jobs_ptr` must point to `jobs_len` jobs, and each job's `inputs_ptr` to
+/// `inputs_len` valid values. Either may be null when its length is 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn run_vm_batch(
+    jobs_ptr: *const VMJob,
+    jobs_len: usize,
+    stack_size: usize,
+    threads: usize,
+) -> VMResultArray {
+    guard(|| {
+        let builder = VmBuilder::new().stack_size(stack_size);
+
+        if let Err(e) = builder.validate() {
+            set_last_ffi_error(e.to_string());
+            return VMResultArray::new(Vec::new());
+        }
+
+        if jobs_ptr.is_null() && jobs_len > 0 {
+            set_last_ffi_error("jobs pointer is null".to_string());
+            return VMResultArray::new(Vec::new());
+        }
+
+        let job_slice = if jobs_ptr.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(jobs_ptr, jobs_len) }
+        };
+
+        // Jobs rejected here get their result right away; the rest run.
+        let mut results: Vec<Option<VMResult>> = Vec::with_capacity(jobs_len);
+        let mut jobs = Vec::with_capacity(jobs_len);
+
+        for job in job_slice {
+            let Some(program) = programs().get(job.program) else {
+                results.push(Some(VMResult::err(ERROR_INVALID_HANDLE)));
+                continue;
+            };
+
+            if job.inputs_ptr.is_null() && job.inputs_len > 0 {
+                results.push(Some(VMResult::err(ERROR_NULL_POINTER)));
+                continue;
+            }
+
+            let input_slice = if job.inputs_ptr.is_null() {
+                &[]
+            } else {
+                unsafe { slice::from_raw_parts(job.inputs_ptr, job.inputs_len) }
+            };
+
+            let inputs: Result<Vec<StackValue>, StackError> = input_slice.iter()
+                .map(|input| unsafe { input.to_stack_value() })
+                .collect();
+
+            match inputs {
+                Ok(inputs) => {
+                    results.push(None);
+                    jobs.push(Job { program, inputs });
+                },
+                Err(e) => results.push(Some(VMResult::from(e))),
             }
         }
+
+        let mut outcomes = run_batch(jobs, builder, threads).into_iter();
+
+        let results = results.into_iter()
+            .map(|result| result.unwrap_or_else(|| match outcomes.next().expect("one outcome per job") {
+                Ok(result) => top_result(result),
+                Err(message) => {
+                    set_last_ffi_error(message);
+                    VMResult::panic()
+                },
+            }))
+            .collect();
+
+        VMResultArray::new(results)
+    }, || VMResultArray::new(Vec::new()))
+}
+
+/// Clears the stack and instruction pointer so the VM can run again.
+/// Returns false if the handle is unknown or already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn reset_vm(handle: Handle) -> bool {
+    with_vm(handle, |vm| {
+        vm.reset();
+        true
+    }, || false, || false)
+}
+
+/// Runs the VM and returns every value produced by `HALT` or `RETURN n`,
+/// in push order. The array must be released with `free_vm_result_array`.
+///
+/// A failed run, or an unknown or freed handle, yields an array holding a
+/// single `Error` result with the error code, `ERROR_INVALID_HANDLE` for
+/// the handle.
+#[unsafe(no_mangle)]
+pub extern "C" fn run_vm_multi(handle: Handle) -> VMResultArray {
+    with_vm(handle, |vm| match vm.run() {
+        Ok(values) => VMResultArray::ok(values),
+        Err(e) => VMResultArray::err(e),
+    }, || VMResultArray::err_code(ERROR_INVALID_HANDLE), VMResultArray::panic)
+}
+
+/// # Safety
+///
+/// `bytes` must come from a byte array result or snapshot that has not
+/// been freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_byte_array(bytes: ByteArrayPtr) {
+    guard(|| unsafe { bytes.release() }, || ());
+}
+
+/// Frees a result array together with every byte array it owns.
+///
+/// # Safety
+///
+/// `ptr`, `len` and `capacity` must come from `run_vm_multi` or
+/// `run_vm_batch` and must not be freed twice. Byte arrays inside it must
+/// not be freed separately.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_vm_result_array(ptr: *mut VMResult, len: usize, capacity: usize) {
+    if ptr.is_null() {
+        return;
     }
+
+    guard(|| {
+        let results = unsafe { Vec::from_raw_parts(ptr, len, capacity) };
+
+        for result in results {
+            if let VMResultTag::ByteArray = result.tag {
+                unsafe { free_byte_array(result.value.bytes_array_val) };
+            }
+        }
+    }, || ());
+}
+
+/// Returns the report of the last failed run, or a report with `has_error`
+/// unset when the last run succeeded. An unknown or freed handle yields a
+/// report with the `ERROR_INVALID_HANDLE` code. The report must be released
+/// with `free_vm_error_report`.
+#[unsafe(no_mangle)]
+pub extern "C" fn vm_last_error(handle: Handle) -> VMErrorReport {
+    with_vm(handle, |vm| match vm.last_error() {
+        Some(report) => VMErrorReport::from(report),
+        None => VMErrorReport::none(),
+    }, || invalid_vm(VMErrorReport::err_code(ERROR_INVALID_HANDLE)), VMErrorReport::none)
 }
 
+/// # Safety
+///
+/// `report` must come from `vm_last_error` and must not be freed twice.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn free_byte_array(ptr: *mut u8, len: usize, capacity: usize) {
+pub unsafe extern "C" fn free_vm_error_report(report: VMErrorReport) {
+    guard(|| {
+        if !report.operand_types.is_null() {
+            let _ = unsafe { CString::from_raw(report.operand_types) };
+        }
+
+        if !report.message.is_null() {
+            let _ = unsafe { CString::from_raw(report.message) };
+        }
+
+        unsafe { free_vm_result_array(report.stack.ptr, report.stack.len, report.stack.capacity) };
+    }, || ());
+}
+
+/// Returns the message of the last panic or rejected argument on the
+/// calling thread, or null if there was none. The string must be released
+/// with `free_ffi_string`.
+#[unsafe(no_mangle)]
+pub extern "C" fn last_ffi_error_message() -> *mut c_char {
+    guard(|| match last_ffi_error() {
+        Some(message) => CString::new(message).unwrap_or_default().into_raw(),
+        None => ptr::null_mut(),
+    }, ptr::null_mut)
+}
+
+/// # Safety
+///
+/// `s` must be null or come from `last_ffi_error_message` and must not be
+/// freed twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_ffi_string(s: *mut c_char) {
+    if !s.is_null() {
+        guard(|| {
+            let _ = unsafe { CString::from_raw(s) };
+        }, || ());
+    }
+}
+
+/// Frees the VM behind the handle. Returns false, without touching any
+/// other VM, if the handle is unknown or was already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn free_vm(handle: Handle) -> bool {
+    guard(|| {
+        // Drop outside the table lock so a slow drop does not block others.
+        let removed = vms().remove(handle);
+
+        removed.is_some()
+    }, || false)
+}
+
+/// Serializes the VM into a versioned snapshot, typically taken while it
+/// is Suspended after `interrupt_vm`. The buffer must be released with
+/// `free_byte_array`. Returns an empty buffer for an unknown handle, with
+/// the reason available from `last_ffi_error_message`.
+#[unsafe(no_mangle)]
+pub extern "C" fn snapshot_vm(handle: Handle) -> ByteArrayPtr {
+    let empty = || ByteArrayPtr::new(Vec::new());
+
+    with_vm(handle, |vm| ByteArrayPtr::new(vm.snapshot()), || invalid_vm(empty()), empty)
+}
+
+/// Rebuilds a VM from a `snapshot_vm` buffer, possibly in another process.
+/// The program must be the one the snapshot was taken from. Returns
+/// `INVALID_HANDLE` (0) if the snapshot or program is rejected; the reason
+/// is available from `last_ffi_error_message`.
+///
+/// # Safety
+///
+/// `snapshot_ptr` must point to `snapshot_len` bytes and `instruction_ptr`
+/// to `instruction_len` valid `Operation`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn restore_vm(
+    snapshot_ptr: *const u8,
+    snapshot_len: usize,
+    instruction_ptr: *const Operation,
+    instruction_len: usize,
+) -> Handle {
+    guard(|| {
+        if snapshot_ptr.is_null() {
+            set_last_ffi_error("snapshot pointer is null".to_string());
+            return INVALID_HANDLE;
+        }
+
+        let Some(instructions) = (unsafe { decode_instructions(instruction_ptr, instruction_len) }) else {
+            return INVALID_HANDLE;
+        };
+
+        let bytes = unsafe { slice::from_raw_parts(snapshot_ptr, snapshot_len) };
+
+        insert_vm(Vm::restore(bytes, instructions))
+    }, || INVALID_HANDLE)
+}
+
+/// Turns printing the stack to stdout after every instruction on or off.
+/// VMs start with it off; it is meant for debugging a single VM, as the
+/// output of VMs running concurrently interleaves. Returns false if the
+/// handle is unknown or already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_vm_trace(handle: Handle, enabled: bool) -> bool {
+    with_vm(handle, |vm| {
+        vm.set_trace(enabled);
+        true
+    }, || false, || false)
+}
+
+// Formats accepted by vm_profile_report.
+pub const PROFILE_FORMAT_TEXT: u8 = 0;
+pub const PROFILE_FORMAT_FOLDED: u8 = 1;
+
+/// Turns per-instruction profiling on or off. Enabling starts a fresh
+/// profile that accumulates over every run. Returns false if the handle is
+/// unknown or already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_vm_profiling(handle: Handle, enabled: bool) -> bool {
+    with_vm(handle, |vm| {
+        vm.set_profiling(enabled);
+        true
+    }, || false, || false)
+}
+
+/// Returns the profile as a text hot-spot table (`PROFILE_FORMAT_TEXT`) or
+/// as folded stacks for flame graph tools (`PROFILE_FORMAT_FOLDED`). The
+/// string must be released with `free_ffi_string`. Returns null if
+/// profiling is disabled, the format is unknown or the handle is invalid;
+/// only the invalid handle sets `last_ffi_error_message`.
+#[unsafe(no_mangle)]
+pub extern "C" fn vm_profile_report(handle: Handle, format: u8) -> *mut c_char {
+    let folded = match format {
+        PROFILE_FORMAT_TEXT => false,
+        PROFILE_FORMAT_FOLDED => true,
+        _ => return ptr::null_mut(),
+    };
+
+    with_vm(handle, |vm| match vm.profile_report(folded) {
+        Some(report) => CString::new(report).unwrap_or_default().into_raw(),
+        None => ptr::null_mut(),
+    }, || invalid_vm(ptr::null_mut()), ptr::null_mut)
+}
+
+/// Returns the statistics of the runs since the VM was last reset, or an
+/// all-zero receipt for an unknown handle, with the reason available from
+/// `last_ffi_error_message`.
+#[unsafe(no_mangle)]
+pub extern "C" fn vm_receipt(handle: Handle) -> ExecutionReceipt {
+    with_vm(handle, |vm| vm.receipt(), || invalid_vm(ExecutionReceipt::default()), ExecutionReceipt::default)
+}
+
+/// Returns the highest number of bytes the VM held in byte arrays and
+/// linear memory since it was last reset, or 0 for an unknown handle, with
+/// the reason available from `last_ffi_error_message`.
+#[unsafe(no_mangle)]
+pub extern "C" fn vm_peak_memory(handle: Handle) -> u64 {
+    with_vm(handle, |vm| vm.peak_memory() as u64, || invalid_vm(0), || 0)
+}
+
+/// Asks the VM to stop. Unlike every other call this does not wait for a
+/// running program: the dispatch loop notices the request within a few
+/// thousand instructions and the run returns an `Interrupted` error. The
+/// VM is then suspended: `run_vm` resumes it and `snapshot_vm` can persist
+/// it. A pending request is cleared when the VM is reset. Returns false if the
+/// handle is unknown or already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn interrupt_vm(handle: Handle) -> bool {
+    guard(|| match vms().get(handle) {
+        Some(entry) => {
+            entry.interrupt.interrupt();
+            true
+        },
+        None => false,
+    }, || false)
+}
+
+/// Debug helper for leak detection: lists the handles of every VM that has
+/// not been freed. The array must be released with `free_vm_handle_array`.
+#[unsafe(no_mangle)]
+pub extern "C" fn vm_live_handles() -> VMHandleArray {
+    guard(|| VMHandleArray::new(vms().live_handles()), || VMHandleArray::new(Vec::new()))
+}
+
+/// # Safety
+///
+/// `ptr`, `len` and `capacity` must come from `vm_live_handles` and must
+/// not be freed twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_vm_handle_array(ptr: *mut Handle, len: usize, capacity: usize) {
     if !ptr.is_null() {
-        let _ = unsafe { Vec::from_raw_parts(ptr, len, capacity) };
+        guard(|| {
+            let _ = unsafe { Vec::from_raw_parts(ptr, len, capacity) };
+        }, || ());
     }
 }
 
+/// Creates a pool of `capacity` pre-allocated VMs for concurrent use.
+/// Pooled VMs never print execution traces. Returns `INVALID_HANDLE` (0)
+/// if an argument is rejected; the reason is available from
+/// `last_ffi_error_message`.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn free_vm(vm_ptr: *mut VM) {
-    if !vm_ptr.is_null() {
-        let _ = unsafe { Box::from_raw(vm_ptr) };
+pub extern "C" fn create_vm_pool(capacity: usize, stack_size: usize, config: VMConfig) -> Handle {
+    guard(|| {
+        match VMPool::new(capacity, VmBuilder::with_config(config).stack_size(stack_size)) {
+            Ok(pool) => pools().insert(PoolEntry {
+                pool,
+                checked_out: Mutex::new(HashSet::new()),
+            }),
+            Err(e) => {
+                set_last_ffi_error(e.to_string());
+                INVALID_HANDLE
+            },
+        }
+    }, || INVALID_HANDLE)
+}
+
+/// Takes a VM out of the pool with the given program loaded and returns a
+/// VM handle usable with every `run_vm*` function. The VM belongs to the
+/// caller until it is handed back with `vm_pool_checkin`. Safe to call from
+/// any number of threads at once. Returns `INVALID_HANDLE` (0) if the pool
+/// handle or the program is rejected.
+///
+/// # Safety
+///
+/// `instruction_ptr` must point to `instruction_len` valid `Operation`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vm_pool_checkout(
+    pool: Handle,
+    instruction_ptr: *const Operation,
+    instruction_len: usize,
+) -> Handle {
+    guard(|| {
+        let Some(entry) = pools().get(pool) else {
+            set_last_ffi_error("invalid vm pool handle".to_string());
+            return INVALID_HANDLE;
+        };
+
+        let Some(instructions) = (unsafe { decode_instructions(instruction_ptr, instruction_len) }) else {
+            return INVALID_HANDLE;
+        };
+
+        let handle = vms().insert(VMEntry::new(entry.pool.checkout(instructions)));
+        entry.checked_out().insert(handle);
+
+        handle
+    }, || INVALID_HANDLE)
+}
+
+/// Hands a checked out VM back to its pool; the VM handle is retired.
+/// Returns false if the VM was not checked out from this pool, or if
+/// either handle is unknown.
+#[unsafe(no_mangle)]
+pub extern "C" fn vm_pool_checkin(pool: Handle, handle: Handle) -> bool {
+    guard(|| {
+        let Some(entry) = pools().get(pool) else {
+            return false;
+        };
+
+        if !entry.checked_out().remove(&handle) {
+            return false;
+        }
+
+        let Some(vm) = vms().remove(handle) else {
+            return false;
+        };
+
+        // A VM still in use on another thread is dropped once that call
+        // returns instead of being reused.
+        if let Ok(vm) = Arc::try_unwrap(vm) {
+            entry.pool.checkin(vm.vm.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        }
+
+        true
+    }, || false)
+}
+
+/// Frees the pool and its idle VMs. VMs that are still checked out stay
+/// valid and must be released with `free_vm`. Returns false if the handle
+/// is unknown or was already freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn free_vm_pool(pool: Handle) -> bool {
+    guard(|| {
+        let removed = pools().remove(pool);
+
+        removed.is_some()
+    }, || false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::slice;
+    use std::thread;
+    use crate::handle::INVALID_HANDLE;
+    use crate::vm::op::{Operation, OperationValue};
+    use crate::vm::config::VMConfig;
+    use crate::stack::stack::StackError;
+    use crate::vm::result::{error_code, VMResultTag, ERROR_INVALID_HANDLE};
+    use crate::vm::vm::INTERRUPT_CHECK_INTERVAL;
+    use crate::{
+        create_vm, create_vm_from_program, create_vm_pool, free_byte_array, free_ffi_string, free_program, free_vm,
+        free_vm_error_report, free_vm_handle_array, free_vm_pool, free_vm_result_array, interrupt_vm,
+        last_ffi_error_message, load_program, reset_vm, run_vm, run_vm_batch, set_vm_trace, vm_last_error, vm_live_handles,
+        vm_peak_memory, vm_pool_checkin, vm_pool_checkout, vm_receipt, with_vm,
+    };
+    use crate::guard::{last_ffi_error, set_last_ffi_error};
+    use crate::vm::receipt::ExecutionReceipt;
+    use crate::batch::VMJob;
+    use crate::vm::result::{ByteArrayPtr, VMResult, VMResultValue};
+    use crate::vm::config::BACKEND_REGISTER;
+
+    fn live_handles() -> Vec<u64> {
+        let handles = vm_live_handles();
+        let live = unsafe { slice::from_raw_parts(handles.ptr, handles.len) }.to_vec();
+
+        unsafe { free_vm_handle_array(handles.ptr, handles.len, handles.capacity) };
+
+        live
+    }
+
+    #[test]
+    fn test_ffi_rejects_bad_arguments() {
+        let operations = [Operation { kind: 0xFF, val: OperationValue { int_val: 0 } }];
+
+        unsafe {
+            let handle = create_vm(4, operations.as_ptr(), operations.len());
+            assert_eq!(handle, INVALID_HANDLE);
+
+            let message = last_ffi_error_message();
+            assert_eq!(CStr::from_ptr(message).to_str(), Ok("Unknown opcode: 255"));
+            free_ffi_string(message);
+
+            let zero_stack = create_vm(0, operations.as_ptr(), 0);
+            assert_eq!(zero_stack, INVALID_HANDLE);
+
+            let unknown_backend = create_vm_pool(1, 4, VMConfig { backend: 2, ..VMConfig::default() });
+            assert_eq!(unknown_backend, INVALID_HANDLE);
+
+            let message = last_ffi_error_message();
+            assert_eq!(CStr::from_ptr(message).to_str(), Ok("Unknown backend: 2"));
+            free_ffi_string(message);
+        }
+    }
+
+    #[test]
+    fn test_ffi_byte_array_results() {
+        let operations = [
+            Operation { kind: 0x03, val: OperationValue { byte_val: 7 } },
+            Operation { kind: 0x03, val: OperationValue { byte_val: 8 } },
+            Operation { kind: 0x04, val: OperationValue { uint_val: 2 } },
+            Operation { kind: 0x00, val: OperationValue { int_val: 0 } },
+        ];
+
+        let handle = unsafe { create_vm(4, operations.as_ptr(), operations.len()) };
+        let result = run_vm(handle);
+        assert!(matches!(result.tag, VMResultTag::ByteArray));
+
+        // The bytes outlive the VM until the host frees them.
+        assert!(free_vm(handle));
+
+        unsafe {
+            let bytes = result.value.bytes_array_val;
+            assert_eq!(slice::from_raw_parts(bytes.ptr, bytes.len), &[7, 8]);
+
+            free_byte_array(bytes);
+        }
+    }
+
+    #[test]
+    fn test_ffi_handles_are_generation_checked() {
+        let operations = [
+            Operation { kind: 0x01, val: OperationValue { int_val: 42 } },
+            Operation { kind: 0x00, val: OperationValue { int_val: 0 } },
+        ];
+
+        let handle = unsafe { create_vm(4, operations.as_ptr(), operations.len()) };
+        assert_ne!(handle, INVALID_HANDLE);
+        assert!(live_handles().contains(&handle));
+
+        let result = run_vm(handle);
+        assert!(matches!(result.tag, VMResultTag::Integer));
+        assert_eq!(unsafe { result.value.int_val }, 42);
+
+        assert!(free_vm(handle));
+        assert!(!free_vm(handle));
+        assert!(!live_handles().contains(&handle));
+
+        let stale = run_vm(handle);
+        assert!(matches!(stale.tag, VMResultTag::Error));
+        assert_eq!(unsafe { stale.value.int_val }, ERROR_INVALID_HANDLE);
+
+        let report = vm_last_error(handle);
+        assert!(report.has_error);
+        assert_eq!(report.code, ERROR_INVALID_HANDLE);
+        unsafe { free_vm_error_report(report) };
+
+        // Calls whose results cannot carry an error leave a message instead.
+        set_last_ffi_error(String::new());
+        assert_eq!(vm_receipt(handle), ExecutionReceipt::default());
+        assert_eq!(last_ffi_error(), Some("invalid vm handle".to_string()));
+
+        set_last_ffi_error(String::new());
+        assert_eq!(vm_peak_memory(handle), 0);
+        assert_eq!(last_ffi_error(), Some("invalid vm handle".to_string()));
+    }
+
+    #[test]
+    fn test_ffi_shares_loaded_programs() {
+        let operations = [
+            Operation { kind: 0x01, val: OperationValue { int_val: 6 } },
+            Operation { kind: 0x01, val: OperationValue { int_val: 7 } },
+            Operation { kind: 0x08, val: OperationValue { int_val: 0 } },
+            Operation { kind: 0x00, val: OperationValue { int_val: 0 } },
+        ];
+
+        let config = VMConfig { backend: BACKEND_REGISTER, ..VMConfig::default() };
+        let program = unsafe { load_program(operations.as_ptr(), operations.len(), config) };
+        assert_ne!(program, INVALID_HANDLE);
+
+        let workers: Vec<_> = (0..4).map(|_| thread::spawn(move || {
+            for _ in 0..100 {
+                let handle = create_vm_from_program(4, program);
+                assert_ne!(handle, INVALID_HANDLE);
+
+                let result = run_vm(handle);
+                assert!(matches!(result.tag, VMResultTag::Integer));
+                assert_eq!(unsafe { result.value.int_val }, 42);
+
+                assert!(free_vm(handle));
+            }
+        })).collect();
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        // Tracing must not send a VM to the stack backend.
+        let handle = create_vm_from_program(4, program);
+        assert!(set_vm_trace(handle, true));
+        assert_eq!(unsafe { run_vm(handle).value.int_val }, 42);
+        assert!(with_vm(handle, |vm| vm.ran_register_blocks(), || false, || false));
+        assert!(free_vm(handle));
+
+        // A VM keeps its program after the program handle is freed.
+        let handle = create_vm_from_program(4, program);
+        assert!(free_program(program));
+        assert!(!free_program(program));
+        assert_eq!(unsafe { run_vm(handle).value.int_val }, 42);
+        assert!(free_vm(handle));
+
+        assert_eq!(create_vm_from_program(4, program), INVALID_HANDLE);
+        assert_eq!(create_vm_from_program(0, program), INVALID_HANDLE);
+
+        let unknown_backend = unsafe {
+            load_program(operations.as_ptr(), operations.len(), VMConfig { backend: 2, ..VMConfig::default() })
+        };
+        assert_eq!(unknown_backend, INVALID_HANDLE);
+    }
+
+    #[test]
+    fn test_ffi_run_vm_batch() {
+        let operations = [
+            Operation { kind: 0x06, val: OperationValue { int_val: 0 } },
+            Operation { kind: 0x00, val: OperationValue { int_val: 0 } },
+        ];
+        let add = unsafe { load_program(operations.as_ptr(), operations.len(), VMConfig::default()) };
+        let identity = unsafe { load_program(operations[1..].as_ptr(), 1, VMConfig::default()) };
+
+        let bytes = [1u8, 2, 3];
+        let byte_input = [VMResult::new(VMResultTag::ByteArray, VMResultValue {
+            bytes_array_val: ByteArrayPtr { ptr: bytes.as_ptr().cast_mut(), len: bytes.len(), owner: std::ptr::null() },
+        })];
+        let int_inputs: Vec<[VMResult; 2]> = (0..32).map(|i| [VMResult::ok_int(i), VMResult::ok_int(1)]).collect();
+
+        let mut jobs: Vec<VMJob> = int_inputs.iter()
+            .map(|inputs| VMJob { program: add, inputs_ptr: inputs.as_ptr(), inputs_len: 2 })
+            .collect();
+        jobs.push(VMJob { program: identity, inputs_ptr: byte_input.as_ptr(), inputs_len: 1 });
+        jobs.push(VMJob { program: INVALID_HANDLE, inputs_ptr: std::ptr::null(), inputs_len: 0 });
+        jobs.push(VMJob { program: add, inputs_ptr: std::ptr::null(), inputs_len: 0 });
+
+        for threads in [1, 4] {
+            let results = unsafe { run_vm_batch(jobs.as_ptr(), jobs.len(), 4, threads) };
+            let values = unsafe { slice::from_raw_parts(results.ptr, results.len) };
+            assert_eq!(values.len(), jobs.len());
+
+            unsafe {
+                for (i, result) in values[..32].iter().enumerate() {
+                    assert!(matches!(result.tag, VMResultTag::Integer));
+                    assert_eq!(result.value.int_val, i as i32 + 1);
+                }
+
+                let array = values[32].value.bytes_array_val;
+                assert!(matches!(values[32].tag, VMResultTag::ByteArray));
+                assert_eq!(slice::from_raw_parts(array.ptr, array.len), &bytes);
+
+                assert_eq!(values[33].value.int_val, ERROR_INVALID_HANDLE);
+                assert_eq!(values[34].value.int_val, error_code(StackError::StackUnderFlow));
+
+                free_vm_result_array(results.ptr, results.len, results.capacity);
+            }
+        }
+
+        let rejected = unsafe { run_vm_batch(jobs.as_ptr(), jobs.len(), 0, 1) };
+        assert_eq!(rejected.len, 0);
+        unsafe { free_vm_result_array(rejected.ptr, rejected.len, rejected.capacity) };
+
+        assert!(free_program(add));
+        assert!(free_program(identity));
+    }
+
+    #[test]
+    fn test_ffi_pool_runs_programs_concurrently() {
+        let pool = create_vm_pool(4, 8, VMConfig::default());
+        assert_ne!(pool, INVALID_HANDLE);
+
+        let workers: Vec<_> = (0..8).map(|worker| thread::spawn(move || {
+            for i in 0..500 {
+                let operations = [
+                    Operation { kind: 0x01, val: OperationValue { int_val: worker } },
+                    Operation { kind: 0x01, val: OperationValue { int_val: i } },
+                    Operation { kind: 0x06, val: OperationValue { int_val: 0 } },
+                    Operation { kind: 0x00, val: OperationValue { int_val: 0 } },
+                ];
+
+                let handle = unsafe { vm_pool_checkout(pool, operations.as_ptr(), operations.len()) };
+                assert_ne!(handle, INVALID_HANDLE);
+
+                let result = run_vm(handle);
+                assert!(matches!(result.tag, VMResultTag::Integer));
+                assert_eq!(unsafe { result.value.int_val }, worker + i);
+
+                assert!(vm_pool_checkin(pool, handle));
+                assert!(!vm_pool_checkin(pool, handle));
+            }
+        })).collect();
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert!(free_vm_pool(pool));
+        assert!(!free_vm_pool(pool));
+    }
+
+    #[test]
+    fn test_ffi_interrupt_vm() {
+        let mut operations: Vec<Operation> = (0..INTERRUPT_CHECK_INTERVAL)
+            .flat_map(|_| [
+                Operation { kind: 0x01, val: OperationValue { int_val: 1 } },
+                Operation { kind: 0x05, val: OperationValue { int_val: 0 } },
+            ])
+            .collect();
+        operations.push(Operation { kind: 0x01, val: OperationValue { int_val: 7 } });
+        operations.push(Operation { kind: 0x00, val: OperationValue { int_val: 0 } });
+
+        let handle = unsafe { create_vm(4, operations.as_ptr(), operations.len()) };
+
+        assert!(interrupt_vm(handle));
+
+        let interrupted = run_vm(handle);
+        assert!(matches!(interrupted.tag, VMResultTag::Error));
+        assert_eq!(unsafe { interrupted.value.int_val }, error_code(StackError::Interrupted));
+
+        assert!(reset_vm(handle));
+
+        let result = run_vm(handle);
+        assert!(matches!(result.tag, VMResultTag::Integer));
+        assert_eq!(unsafe { result.value.int_val }, 7);
+
+        assert!(free_vm(handle));
+        assert!(!interrupt_vm(handle));
     }
 }
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::api::{Vm, VmBuilder, VmError};
+use crate::vm::op::OpCode;
+use crate::vm::vm::Program;
+
+// Pre-allocated VMs sharing one builder's settings. A checked out VM is
+// owned by a single caller until it is checked back in, so callers on
+// different threads never share a VM. When every VM is checked out the pool
+// allocates a fresh one instead of blocking, and on check in it keeps at
+// most `capacity` idle VMs.
+//
+// The lock is only held to take or return an idle VM. Preparing a program
+// and resetting a VM happen outside it, so threads checking out programs
+// prepare them in parallel.
+pub struct VMPool {
+    idle: Mutex<Vec<Vm>>,
+    capacity: usize,
+    builder: VmBuilder,
+    // Loaded into idle VMs so they do not keep their last program alive.
+    empty: Arc<Program>,
+}
+
+impl VMPool {
+    pub fn new(capacity: usize, builder: VmBuilder) -> Result<Self, VmError> {
+        builder.validate()?;
+
+        let empty = builder.load(Vec::new())?;
+        let idle = (0..capacity)
+            .map(|_| VMPool::allocate(&builder, Arc::clone(&empty)))
+            .collect();
+
+        Ok(VMPool {
+            idle: Mutex::new(idle),
+            capacity,
+            builder,
+            empty,
+        })
+    }
+
+    fn allocate(builder: &VmBuilder, program: Arc<Program>) -> Vm {
+        builder.build_from_program(program).expect("pool settings were validated")
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Vec<Vm>> {
+        self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Number of VMs waiting to be checked out.
+    #[cfg(test)]
+    pub fn idle(&self) -> usize {
+        self.lock_idle().len()
+    }
+
+    // Hands out a Ready VM with `instructions` loaded.
+    pub fn checkout(&self, instructions: Vec<OpCode>) -> Vm {
+        let program = self.builder.load(instructions).expect("pool settings were validated");
+        let idle = self.lock_idle().pop();
+
+        match idle {
+            Some(mut vm) => {
+                vm.set_program(program);
+                vm
+            },
+            None => VMPool::allocate(&self.builder, program),
+        }
+    }
+
+    pub fn checkin(&self, mut vm: Vm) {
+        vm.set_program(Arc::clone(&self.empty));
+
+        let mut idle = self.lock_idle();
+
+        if idle.len() < self.capacity {
+            idle.push(vm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::VmBuilder;
+    use crate::pool::VMPool;
+    use crate::stack::composite_stack::StackValue;
+    use crate::vm::op::OpCode;
+
+    #[test]
+    fn test_pool_reuses_idle_vms() {
+        let pool = VMPool::new(2, VmBuilder::new().stack_size(4)).unwrap();
+        assert_eq!(pool.idle(), 2);
+
+        let mut first = pool.checkout(vec![OpCode::PUSHINT(1), OpCode::HALT]);
+        let mut second = pool.checkout(vec![OpCode::PUSHINT(2), OpCode::HALT]);
+        let third = pool.checkout(vec![OpCode::HALT]);
+        assert_eq!(pool.idle(), 0);
+
+        assert_eq!(first.run(), Ok(vec![StackValue::Integer(1)]));
+        assert_eq!(second.run(), Ok(vec![StackValue::Integer(2)]));
+
+        pool.checkin(first);
+        pool.checkin(second);
+        pool.checkin(third);
+        assert_eq!(pool.idle(), 2);
+
+        let mut reused = pool.checkout(vec![OpCode::PUSHINT(3), OpCode::HALT]);
+        assert_eq!(reused.run(), Ok(vec![StackValue::Integer(3)]));
+    }
+}
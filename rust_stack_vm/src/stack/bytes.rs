@@ -0,0 +1,197 @@
+use std::fmt::{self, Debug, Formatter};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+// Payload of StackValue::ByteArray: a view of a reference-counted buffer.
+// Clones and most slices share the buffer, so moving an array between the
+// stack, snapshots and the host never copies its bytes. The bytes a view
+// covers never change; CONCAT extends the buffer in place only when no
+// other view shares it and copies it otherwise.
+#[derive(Clone)]
+pub struct Bytes {
+    buffer: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl Bytes {
+    pub fn new() -> Self {
+        Bytes::from(Vec::new())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    // The bytes in range, sharing this view's buffer unless they are less
+    // than half of it: a small view would keep the whole buffer alive, so
+    // it gets a buffer of its own. The range must lie within the view.
+    pub fn slice(&self, range: Range<usize>) -> Bytes {
+        assert!(range.start <= range.end && range.end <= self.len(), "slice {:?} out of bounds", range);
+
+        if 2 * range.len() < self.buffer.len() {
+            return Bytes::from(&self.as_slice()[range]);
+        }
+
+        Bytes {
+            buffer: Arc::clone(&self.buffer),
+            start: self.start + range.start,
+            end: self.start + range.end,
+        }
+    }
+
+    // Length of the buffer this view keeps alive, which may be shared
+    // with other views.
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn extend_from_slice(&mut self, tail: &[u8]) {
+        if let Some(buffer) = Arc::get_mut(&mut self.buffer) {
+            // Nothing else can see past the end of the view.
+            buffer.truncate(self.end);
+            buffer.extend_from_slice(tail);
+        } else {
+            let mut buffer = Vec::with_capacity(self.len() + tail.len());
+            buffer.extend_from_slice(self.as_slice());
+            buffer.extend_from_slice(tail);
+
+            self.buffer = Arc::new(buffer);
+            self.start = 0;
+        }
+
+        self.end = self.buffer.len();
+    }
+
+    // Takes the buffer back without copying when this is its only view and
+    // covers all of it.
+    pub fn into_vec(self) -> Vec<u8> {
+        let (start, end) = (self.start, self.end);
+
+        match Arc::try_unwrap(self.buffer) {
+            Ok(buffer) if start == 0 && end == buffer.len() => buffer,
+            Ok(buffer) => buffer[start..end].to_vec(),
+            Err(buffer) => buffer[start..end].to_vec(),
+        }
+    }
+
+    // Leaks the view for the host. Returns the address and length of its
+    // bytes and an owner that keeps them alive until release_raw.
+    pub fn into_raw(self) -> (*const u8, usize, *const Vec<u8>) {
+        let ptr = self.as_slice().as_ptr();
+        let len = self.len();
+
+        (ptr, len, Arc::into_raw(self.buffer))
+    }
+
+    /// Releases an owner returned by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `owner` must come from `into_raw` and must not be released twice.
+    pub unsafe fn release_raw(owner: *const Vec<u8>) {
+        drop(unsafe { Arc::from_raw(owner) });
+    }
+}
+
+impl Default for Bytes {
+    fn default() -> Self {
+        Bytes::new()
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Debug for Bytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self.as_slice(), f)
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Bytes) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(buffer: Vec<u8>) -> Self {
+        let end = buffer.len();
+
+        Bytes { buffer: Arc::new(buffer), start: 0, end }
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(bytes: &[u8]) -> Self {
+        Bytes::from(bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stack::bytes::Bytes;
+
+    #[test]
+    fn test_bytes_slices_share_the_buffer() {
+        let bytes = Bytes::from(vec![1, 2, 3, 4, 5]);
+        let middle = bytes.slice(1..4);
+
+        assert_eq!(middle.as_slice(), &[2, 3, 4]);
+        assert_eq!(middle.slice(1..3).as_slice(), &[3, 4]);
+        assert_eq!(middle.as_slice().as_ptr(), bytes[1..].as_ptr());
+        assert_eq!(middle, Bytes::from(vec![2, 3, 4]));
+
+        // Less than half of the buffer is copied out instead.
+        let small = bytes.slice(1..3);
+        assert_eq!(small.as_slice(), &[2, 3]);
+        assert_eq!(small.buffer_len(), 2);
+        assert_eq!(middle.buffer_len(), 5);
+    }
+
+    #[test]
+    fn test_bytes_extend_copies_on_write() {
+        let mut head = Bytes::from(vec![1, 2, 3]);
+        let shared = head.clone();
+        let tail = head.slice(1..2);
+
+        head.extend_from_slice(&[4]);
+        assert_eq!(head.as_slice(), &[1, 2, 3, 4]);
+        assert_eq!(shared.as_slice(), &[1, 2, 3]);
+        assert_eq!(tail.as_slice(), &[2]);
+
+        // head now owns its buffer alone and grows it in place.
+        let address = head.as_ptr();
+        head.extend_from_slice(&[]);
+        assert_eq!(head.as_ptr(), address);
+
+        // A view that is the last one left drops the bytes past its end.
+        let mut prefix = head.slice(0..2);
+        drop(head);
+        prefix.extend_from_slice(&[9]);
+        assert_eq!(prefix.as_slice(), &[1, 2, 9]);
+    }
+
+    #[test]
+    fn test_bytes_into_vec_and_raw() {
+        let buffer = vec![1, 2, 3];
+        let address = buffer.as_ptr();
+
+        let unwrapped = Bytes::from(buffer).into_vec();
+        assert_eq!(unwrapped.as_ptr(), address);
+        assert_eq!(Bytes::from(vec![1, 2, 3]).slice(1..3).into_vec(), vec![2, 3]);
+
+        let bytes = Bytes::from(vec![1, 2, 3]);
+        let (ptr, len, owner) = bytes.slice(2..3).into_raw();
+        drop(bytes);
+
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, len) }, &[3]);
+        unsafe { Bytes::release_raw(owner) };
+    }
+}
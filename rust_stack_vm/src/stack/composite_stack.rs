@@ -1,4 +1,6 @@
 use std::fmt::{Debug, Formatter};
+use crate::stack::bytes::Bytes;
+use crate::stack::slot::Slot;
 use crate::stack::stack::StackComponent;
 
 #[derive(Clone, PartialEq)]
@@ -8,10 +10,22 @@ pub enum StackValue {
     Byte(u8),
     // ByteArray is container that include string, data, address,
     // hash, Non UTF-8 encoding (EUC-KR) etc...
-    ByteArray(Vec<u8>),
+    ByteArray(Bytes),
     Bool(bool),
 }
 
+impl StackValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            StackValue::Integer(_) => "Integer",
+            StackValue::Float(_) => "Float",
+            StackValue::Byte(_) => "Byte",
+            StackValue::ByteArray(_) => "ByteArray",
+            StackValue::Bool(_) => "Bool",
+        }
+    }
+}
+
 impl Debug for StackValue {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -33,7 +47,8 @@ impl Debug for StackValue {
     }
 }
 
-pub type CompositeStack = StackComponent<StackValue>;
+// Values are stored as eight byte slots; see slot.rs.
+pub type CompositeStack = StackComponent<Slot>;
 
 #[cfg(test)]
 mod tests {
@@ -94,4 +109,14 @@ mod tests {
         let popped_failure = stack_failure.pop_n(3, true);
         assert_eq!(popped_failure, Err(StackError::StackUnderFlow));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_composite_stack_debug_shows_values() {
+        let mut stack = CompositeStack::new(4);
+        stack.push(Integer(1)).unwrap();
+        stack.push(Integer(2)).unwrap();
+
+        assert_eq!(format!("{:?}", stack),
+            "StackComponent<rust_stack_vm::stack::composite_stack::StackValue> { data: [Integer(1), Integer(2)] }");
+    }
+}
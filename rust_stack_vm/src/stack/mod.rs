@@ -1,2 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod stack;
-pub mod composite_stack;
\ No newline at end of file
+pub mod composite_stack;
+pub mod bytes;
+pub mod slot;
\ No newline at end of file
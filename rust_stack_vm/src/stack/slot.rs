@@ -0,0 +1,218 @@
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr;
+use crate::stack::bytes::Bytes;
+use crate::stack::composite_stack::StackValue;
+use crate::stack::stack::{HeapSize, StackSlot};
+
+// Eight byte encoding of a StackValue, used for the operand stack so that
+// a slot is a quarter of the size of the enum.
+//
+// The low three bits hold the type. Scalars keep their 32 bit payload in
+// the upper half. A byte array is a pointer to its boxed Bytes, whose
+// alignment keeps the low bits clear for TAG_BYTE_ARRAY.
+pub struct Slot(u64);
+
+const TAG_MASK: u64 = 0b111;
+const TAG_BYTE_ARRAY: u64 = 0;
+const TAG_INTEGER: u64 = 1;
+const TAG_FLOAT: u64 = 2;
+const TAG_BYTE: u64 = 3;
+const TAG_BOOL: u64 = 4;
+
+#[repr(align(8))]
+struct Boxed(Bytes);
+
+// The value a slot holds, decoded in place. A byte array is read out of the
+// slot's box without taking a reference to its buffer, so the view never
+// drops it and cannot outlive the slot.
+pub struct ValueRef<'a> {
+    value: ManuallyDrop<StackValue>,
+    slot: PhantomData<&'a Slot>,
+}
+
+impl Slot {
+    fn scalar(tag: u64, payload: u32) -> Slot {
+        Slot((payload as u64) << 32 | tag)
+    }
+
+    fn tag(&self) -> u64 {
+        self.0 & TAG_MASK
+    }
+
+    fn payload(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    fn boxed(&self) -> *mut Boxed {
+        ptr::with_exposed_provenance_mut(self.0 as usize)
+    }
+
+    pub fn get(&self) -> ValueRef<'_> {
+        let value = match self.tag() {
+            TAG_INTEGER => StackValue::Integer(self.payload() as i32),
+            TAG_FLOAT => StackValue::Float(f32::from_bits(self.payload())),
+            TAG_BYTE => StackValue::Byte(self.payload() as u8),
+            TAG_BOOL => StackValue::Bool(self.payload() != 0),
+            // SAFETY: the box lives as long as the slot, and ValueRef never
+            // drops the copy or hands out mutable access to it.
+            _ => StackValue::ByteArray(unsafe { ptr::read(&(*self.boxed()).0) }),
+        };
+
+        ValueRef { value: ManuallyDrop::new(value), slot: PhantomData }
+    }
+
+    pub fn bytes(&self) -> Option<&Bytes> {
+        match self.tag() {
+            // SAFETY: the box lives as long as the slot.
+            TAG_BYTE_ARRAY => Some(unsafe { &(*self.boxed()).0 }),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> StackValue {
+        self.get().clone()
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.get().type_name()
+    }
+
+    pub fn bytes_mut(&mut self) -> Option<&mut Bytes> {
+        match self.tag() {
+            // SAFETY: the slot owns the box and is borrowed mutably.
+            TAG_BYTE_ARRAY => Some(unsafe { &mut (*self.boxed()).0 }),
+            _ => None,
+        }
+    }
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = StackValue;
+
+    fn deref(&self) -> &StackValue {
+        &self.value
+    }
+}
+
+impl From<StackValue> for Slot {
+    fn from(value: StackValue) -> Slot {
+        match value {
+            StackValue::Integer(i) => Slot::scalar(TAG_INTEGER, i as u32),
+            StackValue::Float(f) => Slot::scalar(TAG_FLOAT, f.to_bits()),
+            StackValue::Byte(b) => Slot::scalar(TAG_BYTE, b as u32),
+            StackValue::Bool(b) => Slot::scalar(TAG_BOOL, b as u32),
+            StackValue::ByteArray(bytes) => {
+                let boxed = Box::into_raw(Box::new(Boxed(bytes)));
+
+                Slot(boxed.expose_provenance() as u64)
+            },
+        }
+    }
+}
+
+impl From<Slot> for StackValue {
+    fn from(slot: Slot) -> StackValue {
+        let slot = ManuallyDrop::new(slot);
+
+        match slot.tag() {
+            // SAFETY: the slot owned the box and is not dropped.
+            TAG_BYTE_ARRAY => StackValue::ByteArray(unsafe { Box::from_raw(slot.boxed()) }.0),
+            _ => slot.to_value(),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.tag() == TAG_BYTE_ARRAY {
+            // SAFETY: the slot owns the box.
+            drop(unsafe { Box::from_raw(self.boxed()) });
+        }
+    }
+}
+
+impl Clone for Slot {
+    fn clone(&self) -> Slot {
+        Slot::from(self.to_value())
+    }
+}
+
+// SAFETY: a slot owns its scalar or its boxed Bytes outright, and Bytes is
+// Send and Sync.
+unsafe impl Send for Slot {}
+unsafe impl Sync for Slot {}
+
+// A byte array counts the whole buffer its view keeps alive, even when
+// other values share it, so a slice cannot pin more memory than the quota
+// sees.
+impl HeapSize for Slot {
+    fn heap_size(&self) -> usize {
+        self.bytes().map_or(0, |bytes| bytes.buffer_len())
+    }
+}
+
+impl StackSlot for Slot {
+    type Value = StackValue;
+
+    fn pack(value: StackValue) -> Slot {
+        Slot::from(value)
+    }
+
+    fn unpack(self) -> StackValue {
+        StackValue::from(self)
+    }
+}
+
+impl Debug for Slot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.get(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use crate::stack::composite_stack::StackValue;
+    use crate::stack::slot::Slot;
+
+    #[test]
+    fn test_slot_round_trips_values() {
+        assert_eq!(size_of::<Slot>(), 8);
+
+        let values = [
+            StackValue::Integer(-1),
+            StackValue::Integer(i32::MIN),
+            StackValue::Float(f32::from_bits(0x7FC0_0001)),
+            StackValue::Float(-0.0),
+            StackValue::Byte(0xFF),
+            StackValue::Bool(true),
+            StackValue::Bool(false),
+            StackValue::ByteArray(vec![1, 2, 3].into()),
+            StackValue::ByteArray(Vec::new().into()),
+        ];
+
+        for value in values {
+            let slot = Slot::from(value.clone());
+
+            assert_eq!(format!("{:?}", slot), format!("{:?}", value));
+            assert_eq!(slot.type_name(), value.type_name());
+            assert_eq!(format!("{:?}", *slot.clone().get()), format!("{:?}", value));
+            assert_eq!(format!("{:?}", StackValue::from(slot)), format!("{:?}", value));
+        }
+    }
+
+    #[test]
+    fn test_slot_owns_its_byte_array() {
+        let mut slot = Slot::from(StackValue::ByteArray(vec![1, 2].into()));
+        let copy = slot.clone();
+
+        slot.bytes_mut().unwrap().extend_from_slice(&[3]);
+
+        assert_eq!(*slot.get(), StackValue::ByteArray(vec![1, 2, 3].into()));
+        assert_eq!(*copy.get(), StackValue::ByteArray(vec![1, 2].into()));
+        assert!(Slot::from(StackValue::Integer(1)).bytes_mut().is_none());
+    }
+}
@@ -1,12 +1,58 @@
-use std::fmt::{self, Debug, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::any::type_name;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StackError {
     StackUnderFlow,
     StackOverFlow,
     StackInvalidType,
-    DivisionByZero
+    DivisionByZero,
+    InvalidState,
+    MemoryOutOfBounds,
+    InvalidConversion,
+    IntegerOverflow,
+    NonDeterministicOperation,
+    StepLimitExceeded,
+    Interrupted,
+    MemoryLimitExceeded,
+}
+
+impl Display for StackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            StackError::StackUnderFlow => "stack underflow",
+            StackError::StackOverFlow => "stack overflow",
+            StackError::StackInvalidType => "invalid type for operation",
+            StackError::DivisionByZero => "division by zero",
+            StackError::InvalidState => "vm must be reset before running again",
+            StackError::MemoryOutOfBounds => "memory access out of bounds",
+            StackError::InvalidConversion => "invalid conversion",
+            StackError::IntegerOverflow => "integer overflow",
+            StackError::NonDeterministicOperation => "operation is not deterministic",
+            StackError::StepLimitExceeded => "step limit exceeded",
+            StackError::Interrupted => "execution interrupted by host",
+            StackError::MemoryLimitExceeded => "memory limit exceeded",
+        };
+
+        f.write_str(message)
+    }
+}
+
+// Bytes a value owns outside of its stack slot, such as a byte array's
+// payload. Uses the length rather than the capacity so the figure does not
+// depend on the allocator's growth policy.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+// How a StackComponent stores its values. Slots may use a more compact
+// encoding than the values they hold; values are packed on the way in and
+// unpacked on the way out.
+pub trait StackSlot: HeapSize {
+    type Value;
+
+    fn pack(value: Self::Value) -> Self;
+    fn unpack(self) -> Self::Value;
 }
 
 pub trait Stack<T> {
@@ -15,6 +61,7 @@ pub trait Stack<T> {
     fn pop_n(&mut self, n: usize, rev: bool) -> Result<Vec<T>, StackError>;
 }
 
+#[allow(dead_code)]
 pub trait FrameStack<T> {
     fn push_frame(&mut self, value: Box<[T]>) -> Result<(), StackError>;
     fn pop_frame(&mut self) -> Result<Box<[T]>, StackError>;
@@ -24,6 +71,11 @@ pub trait FrameStack<T> {
 pub struct StackComponent<T> {
     data: Vec<T>,
     frames: Vec<Box<[T]>>,
+    // Total heap_size of the values in data.
+    heap_bytes: usize,
+    // Execution statistics since the last clear.
+    peak_len: usize,
+    frames_pushed: usize,
 }
 
 impl<T> StackComponent<T> {
@@ -31,13 +83,124 @@ impl<T> StackComponent<T> {
         StackComponent {
             data: Vec::with_capacity(size),
             frames: Vec::with_capacity(size),
+            heap_bytes: 0,
+            peak_len: 0,
+            frames_pushed: 0,
         }
     }
+
+    // Maximum number of values the stack holds.
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn frames(&self) -> &[Box<[T]>] {
+        &self.frames
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.heap_bytes
+    }
+
+    // Most values held at once since the last clear.
+    pub fn peak_len(&self) -> usize {
+        self.peak_len
+    }
+
+    pub fn frames_pushed(&self) -> usize {
+        self.frames_pushed
+    }
+
+    // Sets the statistics a restored stack carries over from the run it was
+    // saved from, once its values and frames are back.
+    pub fn restore_statistics(&mut self, peak_len: usize, frames_pushed: usize) {
+        self.peak_len = peak_len;
+        self.frames_pushed = frames_pushed;
+    }
+
+    // Accounts for a depth the stack would have reached had values been
+    // pushed one at a time, for callers that batch their pushes.
+    pub fn record_len(&mut self, len: usize) {
+        self.peak_len = self.peak_len.max(len);
+    }
+
+    // Up to n values from the top of the stack, bottom first.
+    pub fn top(&self, n: usize) -> &[T] {
+        &self.data[self.data.len().saturating_sub(n)..]
+    }
+
+    // The value must keep its heap size, or heap_bytes drifts.
+    pub fn peek_mut(&mut self) -> Option<&mut T> {
+        self.data.last_mut()
+    }
+
+    // Drops every value and frame while keeping the allocated capacity.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.frames.clear();
+        self.heap_bytes = 0;
+        self.peak_len = 0;
+        self.frames_pushed = 0;
+    }
 }
 
-impl<T: Debug> Debug for StackComponent<T> {
+// In-place operations on the top of the stack. They let the VM's fast path
+// inspect operands by reference and only mutate once an instruction is sure
+// to succeed, instead of popping and pushing values by move.
+impl<T: StackSlot> StackComponent<T> {
+    // The top two values as (below, top).
+    pub fn top2(&self) -> Option<(&T, &T)> {
+        match self.data.as_slice() {
+            [.., lhs, rhs] => Some((lhs, rhs)),
+            _ => None,
+        }
+    }
+
+    // Overwrites the top value. The stack must not be empty.
+    pub fn replace_top(&mut self, value: T::Value) {
+        let top = self.data.last_mut().expect("replace_top on an empty stack");
+        let value = T::pack(value);
+
+        self.heap_bytes = self.heap_bytes - top.heap_size() + value.heap_size();
+        *top = value;
+    }
+
+    // Replaces the top two values with one. The stack must hold at least two.
+    pub fn replace_top2(&mut self, value: T::Value) {
+        self.discard(1);
+        self.replace_top(value);
+    }
+
+    // Mutates the top value in place, keeping heap_bytes in step with any
+    // change to its size.
+    pub fn update_top<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let top = self.data.last_mut()?;
+        let before = top.heap_size();
+        let result = f(top);
+
+        self.heap_bytes = self.heap_bytes - before + top.heap_size();
+
+        Some(result)
+    }
+
+    // Drops the top n values. The stack must hold at least n.
+    pub fn discard(&mut self, n: usize) {
+        let len = self.data.len() - n;
+
+        self.heap_bytes -= self.data[len..].iter().map(HeapSize::heap_size).sum::<usize>();
+        self.data.truncate(len);
+    }
+}
+
+// Slots format as the values they hold, so the stack is named after the
+// value type too.
+impl<T: StackSlot + Debug> Debug for StackComponent<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let type_name = type_name::<T>();
+        let type_name = type_name::<T::Value>();
         let struct_name = format!("StackComponent<{}>", type_name);
 
         let mut debug_struct = f.debug_struct(&struct_name);
@@ -55,30 +218,41 @@ impl<T: Debug> Debug for StackComponent<T> {
 }
 
 
-impl<T> Stack<T> for StackComponent<T> {
-    fn push(&mut self, value: T) -> Result<(), StackError> {
+impl<T: StackSlot> Stack<T::Value> for StackComponent<T> {
+    fn push(&mut self, value: T::Value) -> Result<(), StackError> {
         if self.data.len() == self.data.capacity() {
             return Err(StackError::StackOverFlow);
         }
 
+        let value = T::pack(value);
+
+        self.heap_bytes += value.heap_size();
         self.data.push(value);
+        self.peak_len = self.peak_len.max(self.data.len());
 
         Ok(())
     }
 
-    fn pop(&mut self) -> Result<T, StackError> {
+    fn pop(&mut self) -> Result<T::Value, StackError> {
         match self.data.pop() {
-            Some(v) => Ok(v),
+            Some(v) => {
+                self.heap_bytes -= v.heap_size();
+                Ok(v.unpack())
+            },
             None => Err(StackError::StackUnderFlow),
         }
     }
 
-    fn pop_n(&mut self, n: usize, rev: bool) -> Result<Vec<T>, StackError> {
+    fn pop_n(&mut self, n: usize, rev: bool) -> Result<Vec<T::Value>, StackError> {
         if self.data.len() < n {
             return Err(StackError::StackUnderFlow)
         }
 
-        let mut chunks: Vec<T> = self.data.drain(self.data.len() - n..).collect();
+        let len = self.data.len() - n;
+
+        self.heap_bytes -= self.data[len..].iter().map(HeapSize::heap_size).sum::<usize>();
+
+        let mut chunks: Vec<T::Value> = self.data.drain(len..).map(T::unpack).collect();
 
         if rev {
             chunks.reverse();
@@ -88,30 +262,33 @@ impl<T> Stack<T> for StackComponent<T> {
     }
 }
 
-impl<T> FrameStack<T> for StackComponent<T> {
-    fn push_frame(&mut self, value: Box<[T]>) -> Result<(), StackError> {
+impl<T: StackSlot> FrameStack<T::Value> for StackComponent<T> {
+    fn push_frame(&mut self, value: Box<[T::Value]>) -> Result<(), StackError> {
         if self.frames.len() == self.frames.capacity() {
             return Err(StackError::StackOverFlow);
         }
 
-        self.frames.push(value);
+        self.frames.push(value.into_iter().map(T::pack).collect());
+        self.frames_pushed += 1;
 
         Ok(())
     }
 
-    fn pop_frame(&mut self) -> Result<Box<[T]>, StackError> {
+    fn pop_frame(&mut self) -> Result<Box<[T::Value]>, StackError> {
         match self.frames.pop() {
-            Some(v) => Ok(v),
+            Some(v) => Ok(v.into_iter().map(T::unpack).collect()),
             None => Err(StackError::StackUnderFlow),
         }
     }
 
-    fn pop_n_frame(&mut self, n: usize, rev: bool) -> Result<Vec<Box<[T]>>, StackError> {
+    fn pop_n_frame(&mut self, n: usize, rev: bool) -> Result<Vec<Box<[T::Value]>>, StackError> {
         if self.data.len() < n {
             return Err(StackError::StackUnderFlow)
         }
 
-        let mut chunks: Vec<Box<[T]>> = self.frames.drain(self.data.len() - n..).collect();
+        let mut chunks: Vec<Box<[T::Value]>> = self.frames.drain(self.data.len() - n..)
+            .map(|frame| frame.into_iter().map(T::unpack).collect())
+            .collect();
 
         if rev {
             chunks.reverse();
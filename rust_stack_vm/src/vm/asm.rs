@@ -0,0 +1,320 @@
+use std::fmt::{self, Display, Formatter};
+use crate::stack::bytes::Bytes;
+use crate::vm::op::{Operation, OperationValue, OpCode, FROMBYTES_BYTE, FROMBYTES_FLOAT, FROMBYTES_INTEGER};
+
+// Text and binary encodings of programs.
+//
+// The text form has one instruction per line: the mnemonic, then the
+// immediate operand if it takes one. Everything after a ';' is a comment.
+// Mnemonics are case insensitive and integers may be written in hex with a
+// 0x prefix. FROMBYTESLE / FROMBYTESBE take int, float or byte. Floats that
+// are not canonical NaNs keep their exact bits as bits:0x7FC00001.
+// PUSHBYTES takes its bytes in hex, as in PUSHBYTES 0x0102.
+//
+// The binary form is the array of C Operation structs the host passes to
+// create_vm, as laid out on a little-endian machine: 8 bytes per
+// instruction, the kind in byte 0 and the operand in bytes 4 to 7. An
+// Operation cannot hold a PUSHBYTES, so it is written as the PUSHBYTE and
+// PACK run that builds the same array.
+
+/// Size of one instruction in the binary form.
+pub const BINARY_INSTRUCTION_SIZE: usize = 8;
+
+/// Why a program could not be assembled or decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmError {
+    /// A line of a text program, counting from 1.
+    Syntax { line: usize, message: String },
+    /// An instruction of a binary program, counting from 0.
+    Binary { index: usize, message: String },
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            AsmError::Binary { index, message } => write!(f, "instruction {}: {}", index, message),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// The opcode with the given kind and an operand of zero.
+fn template(kind: u8) -> Option<OpCode> {
+    Operation { kind, val: OperationValue { uint_val: 0 } }.decode()
+}
+
+fn with_operand(template: OpCode, bits: u32) -> OpCode {
+    match template {
+        OpCode::PUSHINT(_) => OpCode::PUSHINT(bits as i32),
+        OpCode::PUSHFLOAT(_) => OpCode::PUSHFLOAT(f32::from_bits(bits)),
+        OpCode::PUSHBYTE(_) => OpCode::PUSHBYTE(bits as u8),
+        OpCode::PACK(_) => OpCode::PACK(bits),
+        OpCode::RETURN(_) => OpCode::RETURN(bits),
+        OpCode::FROMBYTESLE(_) => OpCode::FROMBYTESLE(bits as u8),
+        OpCode::FROMBYTESBE(_) => OpCode::FROMBYTESBE(bits as u8),
+        op => op,
+    }
+}
+
+fn has_operand(op: &OpCode) -> bool {
+    matches!(op,
+        OpCode::PUSHINT(_) | OpCode::PUSHFLOAT(_) | OpCode::PUSHBYTE(_) | OpCode::PACK(_) | OpCode::RETURN(_)
+        | OpCode::FROMBYTESLE(_) | OpCode::FROMBYTESBE(_) | OpCode::PUSHBYTES(_))
+}
+
+fn parse_integer<T: TryFrom<i64>>(text: &str) -> Result<T, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    }.map_err(|_| format!("invalid integer '{}'", text))?;
+
+    T::try_from(if negative { -value } else { value }).map_err(|_| format!("{} is out of range", text))
+}
+
+fn parse_float(text: &str) -> Result<f32, String> {
+    match text.strip_prefix("bits:") {
+        Some(bits) => parse_integer::<u32>(bits).map(f32::from_bits),
+        None => text.parse::<f32>().map_err(|_| format!("invalid float '{}'", text)),
+    }
+}
+
+fn parse_bytes(text: &str) -> Result<Bytes, String> {
+    let invalid = || format!("invalid bytes '{}'", text);
+
+    let hex = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).ok_or_else(invalid)?;
+
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>, _>>()
+        .map(Bytes::from)
+}
+
+fn parse_target(text: &str) -> Result<u8, String> {
+    match text.to_ascii_lowercase().as_str() {
+        "int" => Ok(FROMBYTES_INTEGER),
+        "float" => Ok(FROMBYTES_FLOAT),
+        "byte" => Ok(FROMBYTES_BYTE),
+        _ => parse_integer(text),
+    }
+}
+
+/// Parses one line of assembly. Returns None for blank and comment-only
+/// lines.
+pub fn parse_instruction(line: &str) -> Result<Option<OpCode>, String> {
+    let code = line.split(';').next().unwrap_or_default();
+    let mut words = code.split_whitespace();
+
+    let Some(mnemonic) = words.next() else {
+        return Ok(None);
+    };
+
+    // PUSHBYTES has no Operation kind to find it by.
+    let template = (0..=u8::MAX)
+        .filter_map(template)
+        .chain([OpCode::PUSHBYTES(Bytes::new())])
+        .find(|op| op.name().eq_ignore_ascii_case(mnemonic))
+        .ok_or_else(|| format!("unknown instruction '{}'", mnemonic))?;
+
+    let operand = words.next();
+
+    if let Some(extra) = words.next() {
+        return Err(format!("unexpected '{}' after the operand", extra));
+    }
+
+    let op = match (template, operand) {
+        (op, None) if !has_operand(&op) => op,
+        (op, Some(_)) if !has_operand(&op) => return Err(format!("{} takes no operand", op.name())),
+        (op, None) => return Err(format!("{} needs an operand", op.name())),
+        (OpCode::PUSHINT(_), Some(text)) => OpCode::PUSHINT(parse_integer(text)?),
+        (OpCode::PUSHFLOAT(_), Some(text)) => OpCode::PUSHFLOAT(parse_float(text)?),
+        (OpCode::PUSHBYTE(_), Some(text)) => OpCode::PUSHBYTE(parse_integer(text)?),
+        (OpCode::PACK(_), Some(text)) => OpCode::PACK(parse_integer(text)?),
+        (OpCode::RETURN(_), Some(text)) => OpCode::RETURN(parse_integer(text)?),
+        (OpCode::FROMBYTESLE(_), Some(text)) => OpCode::FROMBYTESLE(parse_target(text)?),
+        (OpCode::FROMBYTESBE(_), Some(text)) => OpCode::FROMBYTESBE(parse_target(text)?),
+        (OpCode::PUSHBYTES(_), Some(text)) => OpCode::PUSHBYTES(parse_bytes(text)?),
+        (op, Some(_)) => unreachable!("{} has an operand", op.name()),
+    };
+
+    Ok(Some(op))
+}
+
+/// Assembles a text program.
+pub fn assemble(source: &str) -> Result<Vec<OpCode>, AsmError> {
+    source.lines()
+        .enumerate()
+        .filter_map(|(i, line)| parse_instruction(line)
+            .map_err(|message| AsmError::Syntax { line: i + 1, message })
+            .transpose())
+        .collect()
+}
+
+fn format_target(target: u8) -> String {
+    match target {
+        FROMBYTES_INTEGER => "int".to_string(),
+        FROMBYTES_FLOAT => "float".to_string(),
+        FROMBYTES_BYTE => "byte".to_string(),
+        target => target.to_string(),
+    }
+}
+
+/// Formats one instruction the way [`parse_instruction`] reads it.
+pub fn format_instruction(op: &OpCode) -> String {
+    match op {
+        OpCode::PUSHINT(i) => format!("PUSHINT {}", i),
+        OpCode::PUSHFLOAT(f) if f.is_nan() && f.to_bits() != f32::NAN.to_bits() => {
+            format!("PUSHFLOAT bits:0x{:08X}", f.to_bits())
+        },
+        OpCode::PUSHFLOAT(f) => format!("PUSHFLOAT {:?}", f),
+        OpCode::PUSHBYTE(b) => format!("PUSHBYTE {}", b),
+        OpCode::PACK(n) => format!("PACK {}", n),
+        OpCode::RETURN(n) => format!("RETURN {}", n),
+        OpCode::FROMBYTESLE(target) => format!("FROMBYTESLE {}", format_target(*target)),
+        OpCode::FROMBYTESBE(target) => format!("FROMBYTESBE {}", format_target(*target)),
+        OpCode::PUSHBYTES(bytes) => {
+            format!("PUSHBYTES 0x{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        },
+        op => op.name().to_string(),
+    }
+}
+
+/// Formats a program as assembly, with each instruction's index in a
+/// comment so it can be matched against error reports.
+pub fn disassemble(program: &[OpCode]) -> String {
+    program.iter()
+        .enumerate()
+        .map(|(ip, op)| format!("{:<24}; {}\n", format_instruction(op), ip))
+        .collect()
+}
+
+/// Encodes a program in the binary form.
+pub fn encode_binary(program: &[OpCode]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(program.len() * BINARY_INSTRUCTION_SIZE);
+
+    let mut write = |op: &OpCode| {
+        bytes.extend_from_slice(&[op.kind(), 0, 0, 0]);
+        bytes.extend_from_slice(&op.operand_bits().to_le_bytes());
+    };
+
+    for op in program {
+        match op {
+            OpCode::PUSHBYTES(pushed) => {
+                pushed.iter().for_each(|b| write(&OpCode::PUSHBYTE(*b)));
+                write(&OpCode::PACK(pushed.len() as u32));
+            },
+            op => write(op),
+        }
+    }
+
+    bytes
+}
+
+/// Decodes a program in the binary form.
+pub fn decode_binary(bytes: &[u8]) -> Result<Vec<OpCode>, AsmError> {
+    if !bytes.len().is_multiple_of(BINARY_INSTRUCTION_SIZE) {
+        return Err(AsmError::Binary {
+            index: bytes.len() / BINARY_INSTRUCTION_SIZE,
+            message: format!("truncated: {} bytes is not a multiple of {}", bytes.len(), BINARY_INSTRUCTION_SIZE),
+        });
+    }
+
+    bytes.chunks_exact(BINARY_INSTRUCTION_SIZE)
+        .enumerate()
+        .map(|(index, record)| {
+            let template = template(record[0]).ok_or_else(|| AsmError::Binary {
+                index,
+                message: format!("Unknown opcode: {}", record[0]),
+            })?;
+
+            let bits = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+
+            Ok(with_operand(template, bits))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::asm::{assemble, decode_binary, disassemble, encode_binary, parse_instruction, AsmError};
+    use crate::vm::op::{OpCode, FROMBYTES_FLOAT};
+
+    #[test]
+    fn test_asm_round_trips_programs() {
+        let program = assemble("
+            ; comments and blank lines are skipped
+            pushint -0x10
+            PUSHFLOAT 1.5   ; trailing comment
+            PUSHFLOAT bits:0x7FC00001
+            PUSHBYTE 255
+            FROMBYTESLE float
+            RETURN 2
+        ").unwrap();
+
+        assert_eq!(format!("{:?}", program), format!("{:?}", vec![
+            OpCode::PUSHINT(-16),
+            OpCode::PUSHFLOAT(1.5),
+            OpCode::PUSHFLOAT(f32::from_bits(0x7FC0_0001)),
+            OpCode::PUSHBYTE(255),
+            OpCode::FROMBYTESLE(FROMBYTES_FLOAT),
+            OpCode::RETURN(2),
+        ]));
+
+        let text = disassemble(&program);
+        assert!(text.starts_with(&format!("{:<24}; 0\n", "PUSHINT -16")));
+
+        let reassembled = assemble(&text).unwrap();
+        assert_eq!(format!("{:?}", reassembled), format!("{:?}", program));
+        assert_eq!(reassembled[2].operand_bits(), 0x7FC0_0001);
+
+        let decoded = decode_binary(&encode_binary(&program)).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", program));
+    }
+
+    #[test]
+    fn test_asm_handles_pushbytes() {
+        let program = assemble("PUSHBYTES 0x01fF\nPUSHBYTES 0x").unwrap();
+        assert_eq!(program, vec![OpCode::PUSHBYTES(vec![1, 255].into()), OpCode::PUSHBYTES(vec![].into())]);
+        assert_eq!(disassemble(&program).lines().next(), Some(format!("{:<24}; 0", "PUSHBYTES 0x01ff").as_str()));
+        assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+
+        // The binary form spells the array out.
+        assert_eq!(decode_binary(&encode_binary(&program)).unwrap(), vec![
+            OpCode::PUSHBYTE(1),
+            OpCode::PUSHBYTE(255),
+            OpCode::PACK(2),
+            OpCode::PACK(0),
+        ]);
+
+        assert_eq!(parse_instruction("PUSHBYTES 0x123"), Err("invalid bytes '0x123'".to_string()));
+        assert_eq!(parse_instruction("PUSHBYTES 12"), Err("invalid bytes '12'".to_string()));
+        assert_eq!(parse_instruction("PUSHBYTES 0x+f"), Err("invalid bytes '0x+f'".to_string()));
+    }
+
+    #[test]
+    fn test_asm_reports_errors() {
+        assert_eq!(parse_instruction("   ; nothing"), Ok(None));
+        assert_eq!(parse_instruction("JUMP 3"), Err("unknown instruction 'JUMP'".to_string()));
+        assert_eq!(parse_instruction("ADD 1"), Err("ADD takes no operand".to_string()));
+        assert_eq!(parse_instruction("PUSHBYTE 256"), Err("256 is out of range".to_string()));
+
+        assert_eq!(assemble("HALT\nPUSHINT"), Err(AsmError::Syntax {
+            line: 2,
+            message: "PUSHINT needs an operand".to_string(),
+        }));
+
+        assert_eq!(decode_binary(&[0; 9]).unwrap_err().to_string(), "instruction 1: truncated: 9 bytes is not a multiple of 8");
+        assert_eq!(decode_binary(&[0xFF, 0, 0, 0, 0, 0, 0, 0]).unwrap_err().to_string(), "instruction 0: Unknown opcode: 255");
+    }
+}
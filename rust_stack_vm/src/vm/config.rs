@@ -0,0 +1,34 @@
+// Interpreters a VM can run its program with, selected by VMConfig::backend.
+//
+// The stack backend executes each instruction against the operand stack.
+// The register backend compiles runs of scalar instructions into register
+// code that only touches the stack at the ends of each run; see
+// vm/vm/registers.rs. Both give identical results, errors and statistics.
+pub const BACKEND_STACK: u8 = 0;
+pub const BACKEND_REGISTER: u8 = 1;
+
+// Execution options fixed at VM creation. The layout is shared with the
+// host through stack_vm_config.h, so fields are plain C types.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VMConfig {
+    // Canonicalizes every NaN produced by the program and rejects float
+    // operations whose result may differ between platforms.
+    pub deterministic_float: bool,
+    // Float DIV, MOD and REM by zero yield IEEE infinities / NaN instead of
+    // StackError::DivisionByZero. Integer division by zero always errors.
+    pub ieee_float_division: bool,
+    // Maximum number of instructions a single run may execute before it
+    // fails with StackError::StepLimitExceeded. 0 means unlimited.
+    pub max_steps: u64,
+    // Maximum number of bytes the program may hold in byte arrays and linear
+    // memory combined before it fails with StackError::MemoryLimitExceeded.
+    // 0 means unlimited.
+    pub max_memory_bytes: u64,
+    // Runs the constant folding and peephole pass in optimize.rs when the
+    // program is loaded.
+    pub optimize: bool,
+    // One of the BACKEND_ constants above.
+    pub backend: u8,
+}
+
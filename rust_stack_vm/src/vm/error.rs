@@ -0,0 +1,44 @@
+use crate::stack::composite_stack::StackValue;
+use crate::stack::stack::StackError;
+use crate::vm::op::OpCode;
+
+// Number of values from the top of the stack kept in an ErrorReport.
+pub const ERROR_STACK_DEPTH: usize = 8;
+
+// Largest number of operands recorded for the failing instruction.
+pub const MAX_REPORTED_OPERANDS: usize = 3;
+
+// Context captured when VM::execute fails. ip and opcode are None when the
+// error did not come from a decoded instruction, e.g. the implicit HALT at
+// the end of the program or running a VM that was not reset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    pub error: StackError,
+    pub ip: Option<usize>,
+    pub opcode: Option<OpCode>,
+    // Types of the operands on the stack right before the failing
+    // instruction ran, bottom first.
+    pub operand_types: Vec<&'static str>,
+    // Up to ERROR_STACK_DEPTH values left on the stack after the failure,
+    // bottom first.
+    pub stack: Vec<StackValue>,
+}
+
+impl ErrorReport {
+    pub fn message(&self) -> String {
+        let mut message = self.error.to_string();
+
+        match (self.ip, &self.opcode) {
+            (Some(ip), Some(opcode)) => message.push_str(&format!(" at instruction {} ({:?})", ip, opcode)),
+            // Raised before the run started, so there is no location.
+            _ if self.error == StackError::InvalidState => {},
+            _ => message.push_str(" at end of program"),
+        }
+
+        if !self.operand_types.is_empty() {
+            message.push_str(&format!(" with operands [{}]", self.operand_types.join(", ")));
+        }
+
+        message
+    }
+}
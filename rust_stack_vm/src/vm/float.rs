@@ -0,0 +1,113 @@
+use crate::stack::stack::StackError;
+
+// Quiet NaN with an empty payload. In deterministic mode every NaN is
+// replaced by this value so its bit pattern never depends on the platform.
+pub const CANONICAL_NAN: f32 = f32::from_bits(0x7FC0_0000);
+
+pub fn canonicalize(f: f32) -> f32 {
+    if f.is_nan() { CANONICAL_NAN } else { f }
+}
+
+// Integer power by square-and-multiply. Each step is a single IEEE-754
+// multiplication (or one final division), so the result is bit-exact on
+// every platform, unlike f32::powi and f32::powf.
+pub fn deterministic_powi(base: f32, exp: i32) -> f32 {
+    let mut result = 1.0f32;
+    let mut factor = base;
+    let mut n = exp.unsigned_abs();
+
+    while n > 0 {
+        if n & 1 == 1 {
+            result *= factor;
+        }
+
+        factor *= factor;
+        n >>= 1;
+    }
+
+    if exp < 0 { 1.0 / result } else { result }
+}
+
+// Float power in deterministic mode. Only integral exponents have a
+// platform independent implementation.
+pub fn deterministic_powf(base: f32, exp: f32) -> Result<f32, StackError> {
+    if exp.fract() != 0.0 || !(-2_147_483_648.0..2_147_483_648.0).contains(&exp) {
+        return Err(StackError::NonDeterministicOperation);
+    }
+
+    Ok(deterministic_powi(base, exp as i32))
+}
+
+// MIN and MAX for floats. f32::min and f32::max may return either zero
+// when the operands are zeros of opposite sign, depending on how they were
+// compiled, so MIN picks -0.0 and MAX picks +0.0. A NaN operand is ignored
+// as f32::min and f32::max do.
+pub fn min(lhs: f32, rhs: f32) -> f32 {
+    if lhs == rhs {
+        if lhs.is_sign_negative() { lhs } else { rhs }
+    } else {
+        lhs.min(rhs)
+    }
+}
+
+pub fn max(lhs: f32, rhs: f32) -> f32 {
+    if lhs == rhs {
+        if lhs.is_sign_positive() { lhs } else { rhs }
+    } else {
+        lhs.max(rhs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stack::stack::StackError;
+    use crate::vm::float::{self, canonicalize, deterministic_powf, deterministic_powi, CANONICAL_NAN};
+
+    #[test]
+    fn test_canonicalize_nan_payloads() {
+        let payload_nan = f32::from_bits(0xFFC0_1234);
+
+        assert!(payload_nan.is_nan());
+        assert_eq!(canonicalize(payload_nan).to_bits(), CANONICAL_NAN.to_bits());
+        assert_eq!(canonicalize(f32::NAN).to_bits(), 0x7FC0_0000);
+        assert_eq!(canonicalize(-0.0).to_bits(), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_deterministic_pow_vectors() {
+        // (base, exponent, expected bits)
+        let vectors = [
+            (2.0f32, 10, 0x4480_0000u32),
+            (1.5, 3, 0x4058_0000),
+            (-3.0, 3, 0xC1D8_0000),
+            (2.0, -2, 0x3E80_0000),
+            (10.0, 0, 0x3F80_0000),
+            (1.1, 2, 0x3F9A_E148),
+        ];
+
+        for (base, exp, bits) in vectors {
+            assert_eq!(deterministic_powi(base, exp).to_bits(), bits, "{base}^{exp}");
+        }
+
+        assert_eq!(deterministic_powf(2.0, 3.0), Ok(8.0));
+        assert_eq!(deterministic_powf(2.0, 0.5), Err(StackError::NonDeterministicOperation));
+        assert_eq!(deterministic_powf(2.0, f32::NAN), Err(StackError::NonDeterministicOperation));
+    }
+
+    #[test]
+    fn test_min_max_signed_zeros() {
+        // (lhs, rhs, min bits, max bits)
+        let vectors = [
+            (-0.0f32, 0.0f32, 0x8000_0000u32, 0x0000_0000u32),
+            (0.0, -0.0, 0x8000_0000, 0x0000_0000),
+            (-0.0, -0.0, 0x8000_0000, 0x8000_0000),
+            (1.0, -2.0, 0xC000_0000, 0x3F80_0000),
+            (f32::NAN, 1.0, 0x3F80_0000, 0x3F80_0000),
+        ];
+
+        for (lhs, rhs, min, max) in vectors {
+            assert_eq!(float::min(lhs, rhs).to_bits(), min, "min({lhs}, {rhs})");
+            assert_eq!(float::max(lhs, rhs).to_bits(), max, "max({lhs}, {rhs})");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::stack::stack::StackError;
+
+pub const PAGE_SIZE: usize = 4096;
+pub const DEFAULT_MAX_PAGES: usize = 256;
+
+// Byte-addressable memory owned by a single VM. It starts empty and grows
+// in whole pages whenever an access touches bytes past the current size,
+// up to max_pages. Every access is bounds-checked against that limit.
+pub struct LinearMemory {
+    data: Vec<u8>,
+    max_pages: usize,
+}
+
+impl LinearMemory {
+    pub fn new(max_pages: usize) -> Self {
+        LinearMemory {
+            data: Vec::new(),
+            max_pages,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn pages(&self) -> usize {
+        self.data.len() / PAGE_SIZE
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    // Grows memory so that [offset, offset + len) is addressable and
+    // returns the number of pages that had to be added.
+    pub fn ensure(&mut self, offset: usize, len: usize) -> Result<usize, StackError> {
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let end = offset.checked_add(len).ok_or(StackError::MemoryOutOfBounds)?;
+
+        if end <= self.data.len() {
+            return Ok(0);
+        }
+
+        let required_pages = end.div_ceil(PAGE_SIZE);
+
+        if required_pages > self.max_pages {
+            return Err(StackError::MemoryOutOfBounds);
+        }
+
+        let grown_pages = required_pages - self.pages();
+
+        self.data.resize(required_pages * PAGE_SIZE, 0);
+
+        Ok(grown_pages)
+    }
+
+    // Zero length accesses are valid at any offset, matching ensure, which
+    // does not grow memory for them.
+    pub fn read(&self, offset: usize, len: usize) -> &[u8] {
+        if len == 0 {
+            return &[];
+        }
+
+        &self.data[offset..offset + len]
+    }
+
+    pub fn write(&mut self, offset: usize, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn copy_within(&mut self, dst: usize, src: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        self.data.copy_within(src..src + len, dst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stack::stack::StackError;
+    use crate::vm::memory::{LinearMemory, PAGE_SIZE};
+
+    #[test]
+    fn test_linear_memory_grows_in_pages() {
+        let mut memory = LinearMemory::new(2);
+
+        assert_eq!(memory.size(), 0);
+        assert_eq!(memory.ensure(0, 0), Ok(0));
+        assert_eq!(memory.read(64, 0), &[] as &[u8]);
+        memory.write(64, &[]);
+        memory.copy_within(64, 128, 0);
+        assert_eq!(memory.ensure(10, 4), Ok(1));
+        assert_eq!(memory.size(), PAGE_SIZE);
+        assert_eq!(memory.ensure(0, PAGE_SIZE), Ok(0));
+        assert_eq!(memory.ensure(PAGE_SIZE - 1, 2), Ok(1));
+        assert_eq!(memory.pages(), 2);
+    }
+
+    #[test]
+    fn test_linear_memory_out_of_bounds() {
+        let mut memory = LinearMemory::new(1);
+
+        assert_eq!(memory.ensure(PAGE_SIZE, 1), Err(StackError::MemoryOutOfBounds));
+        assert_eq!(memory.ensure(usize::MAX, 2), Err(StackError::MemoryOutOfBounds));
+        assert_eq!(memory.size(), 0);
+    }
+}
@@ -1,3 +1,16 @@
+#[allow(clippy::module_inception)]
 pub mod vm;
 pub mod op;
-pub mod result;
\ No newline at end of file
+pub mod result;
+pub mod memory;
+pub mod config;
+pub mod float;
+pub mod error;
+pub mod snapshot;
+pub mod receipt;
+pub mod profile;
+pub mod optimize;
+pub mod asm;
+pub mod verify;
+#[cfg(test)]
+pub mod testing;
@@ -1,4 +1,7 @@
-#[derive(Debug, Clone)]
+use crate::stack::bytes::Bytes;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
     PUSHINT(i32),
     PUSHFLOAT(f32),
@@ -16,8 +19,199 @@ pub enum OpCode {
     LTE,
     HALT,
     CONCAT,
+    RETURN(u32),
+    MLOAD,
+    MSTORE,
+    MSTORE8,
+    MCOPY,
+    MSIZE,
+    MLOADBYTES,
+    MSTOREBYTES,
+    I2F,
+    F2I,
+    I2B,
+    B2I,
+    BOOL2I,
+    TOBYTESLE,
+    TOBYTESBE,
+    FROMBYTESLE(u8),
+    FROMBYTESBE(u8),
+    MOD,
+    REM,
+    NEG,
+    ABS,
+    MIN,
+    MAX,
+    POW,
+    SQRT,
+    FLOOR,
+    CEIL,
+    ROUND,
+    TRUNC,
+    SLICE,
+    // Pushes a byte array. The optimizer makes it from PUSHBYTE and PACK
+    // runs; an Operation cannot carry the bytes, so hosts never send it.
+    PUSHBYTES(Bytes),
+}
+
+impl OpCode {
+    // Encoding of the opcode in Operation::kind.
+    pub fn kind(&self) -> u8 {
+        match self {
+            OpCode::HALT => 0x00,
+            OpCode::PUSHINT(_) => 0x01,
+            OpCode::PUSHFLOAT(_) => 0x02,
+            OpCode::PUSHBYTE(_) => 0x03,
+            OpCode::PACK(_) => 0x04,
+            OpCode::POP => 0x05,
+            OpCode::ADD => 0x06,
+            OpCode::SUB => 0x07,
+            OpCode::MUL => 0x08,
+            OpCode::DIV => 0x09,
+            OpCode::EQ => 0x0A,
+            OpCode::GT => 0x0B,
+            OpCode::GTE => 0x0C,
+            OpCode::LT => 0x0D,
+            OpCode::LTE => 0x0E,
+            OpCode::CONCAT => 0x0F,
+            OpCode::RETURN(_) => 0x10,
+            OpCode::MLOAD => 0x11,
+            OpCode::MSTORE => 0x12,
+            OpCode::MSTORE8 => 0x13,
+            OpCode::MCOPY => 0x14,
+            OpCode::MSIZE => 0x15,
+            OpCode::MLOADBYTES => 0x16,
+            OpCode::MSTOREBYTES => 0x17,
+            OpCode::I2F => 0x18,
+            OpCode::F2I => 0x19,
+            OpCode::I2B => 0x1A,
+            OpCode::B2I => 0x1B,
+            OpCode::BOOL2I => 0x1C,
+            OpCode::TOBYTESLE => 0x1D,
+            OpCode::TOBYTESBE => 0x1E,
+            OpCode::FROMBYTESLE(_) => 0x1F,
+            OpCode::FROMBYTESBE(_) => 0x20,
+            OpCode::MOD => 0x21,
+            OpCode::REM => 0x22,
+            OpCode::NEG => 0x23,
+            OpCode::ABS => 0x24,
+            OpCode::MIN => 0x25,
+            OpCode::MAX => 0x26,
+            OpCode::POW => 0x27,
+            OpCode::SQRT => 0x28,
+            OpCode::FLOOR => 0x29,
+            OpCode::CEIL => 0x2A,
+            OpCode::ROUND => 0x2B,
+            OpCode::TRUNC => 0x2C,
+            OpCode::SLICE => 0x2D,
+            OpCode::PUSHBYTES(_) => 0x2E,
+        }
+    }
+
+    // Mnemonic without the immediate operand.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::HALT => "HALT",
+            OpCode::PUSHINT(_) => "PUSHINT",
+            OpCode::PUSHFLOAT(_) => "PUSHFLOAT",
+            OpCode::PUSHBYTE(_) => "PUSHBYTE",
+            OpCode::PACK(_) => "PACK",
+            OpCode::POP => "POP",
+            OpCode::ADD => "ADD",
+            OpCode::SUB => "SUB",
+            OpCode::MUL => "MUL",
+            OpCode::DIV => "DIV",
+            OpCode::EQ => "EQ",
+            OpCode::GT => "GT",
+            OpCode::GTE => "GTE",
+            OpCode::LT => "LT",
+            OpCode::LTE => "LTE",
+            OpCode::CONCAT => "CONCAT",
+            OpCode::RETURN(_) => "RETURN",
+            OpCode::MLOAD => "MLOAD",
+            OpCode::MSTORE => "MSTORE",
+            OpCode::MSTORE8 => "MSTORE8",
+            OpCode::MCOPY => "MCOPY",
+            OpCode::MSIZE => "MSIZE",
+            OpCode::MLOADBYTES => "MLOADBYTES",
+            OpCode::MSTOREBYTES => "MSTOREBYTES",
+            OpCode::I2F => "I2F",
+            OpCode::F2I => "F2I",
+            OpCode::I2B => "I2B",
+            OpCode::B2I => "B2I",
+            OpCode::BOOL2I => "BOOL2I",
+            OpCode::TOBYTESLE => "TOBYTESLE",
+            OpCode::TOBYTESBE => "TOBYTESBE",
+            OpCode::FROMBYTESLE(_) => "FROMBYTESLE",
+            OpCode::FROMBYTESBE(_) => "FROMBYTESBE",
+            OpCode::MOD => "MOD",
+            OpCode::REM => "REM",
+            OpCode::NEG => "NEG",
+            OpCode::ABS => "ABS",
+            OpCode::MIN => "MIN",
+            OpCode::MAX => "MAX",
+            OpCode::POW => "POW",
+            OpCode::SQRT => "SQRT",
+            OpCode::FLOOR => "FLOOR",
+            OpCode::CEIL => "CEIL",
+            OpCode::ROUND => "ROUND",
+            OpCode::TRUNC => "TRUNC",
+            OpCode::SLICE => "SLICE",
+            OpCode::PUSHBYTES(_) => "PUSHBYTES",
+        }
+    }
+
+    // Raw bits of the immediate operand as stored in Operation::val, or 0
+    // for opcodes without one.
+    pub fn operand_bits(&self) -> u32 {
+        match self {
+            OpCode::PUSHINT(i) => *i as u32,
+            OpCode::PUSHFLOAT(f) => f.to_bits(),
+            OpCode::PUSHBYTE(b) | OpCode::FROMBYTESLE(b) | OpCode::FROMBYTESBE(b) => *b as u32,
+            OpCode::PACK(n) | OpCode::RETURN(n) => *n,
+            _ => 0,
+        }
+    }
+
+    // Number of operands the instruction pops from the stack.
+    pub fn arity(&self) -> usize {
+        match self {
+            OpCode::PUSHINT(_) | OpCode::PUSHFLOAT(_) | OpCode::PUSHBYTE(_) | OpCode::PUSHBYTES(_) | OpCode::MSIZE => 0,
+            OpCode::PACK(n) | OpCode::RETURN(n) => *n as usize,
+            OpCode::HALT | OpCode::POP | OpCode::MLOAD
+            | OpCode::I2F | OpCode::F2I | OpCode::I2B | OpCode::B2I | OpCode::BOOL2I
+            | OpCode::TOBYTESLE | OpCode::TOBYTESBE | OpCode::FROMBYTESLE(_) | OpCode::FROMBYTESBE(_)
+            | OpCode::NEG | OpCode::ABS | OpCode::SQRT | OpCode::FLOOR | OpCode::CEIL
+            | OpCode::ROUND | OpCode::TRUNC => 1,
+            OpCode::ADD | OpCode::SUB | OpCode::MUL | OpCode::DIV
+            | OpCode::EQ | OpCode::GT | OpCode::GTE | OpCode::LT | OpCode::LTE | OpCode::CONCAT
+            | OpCode::MSTORE | OpCode::MSTORE8 | OpCode::MLOADBYTES | OpCode::MSTOREBYTES
+            | OpCode::MOD | OpCode::REM | OpCode::MIN | OpCode::MAX | OpCode::POW => 2,
+            OpCode::MCOPY | OpCode::SLICE => 3,
+        }
+    }
+
+    // Number of values the instruction pushes. HALT and RETURN end the run
+    // instead.
+    pub fn results(&self) -> usize {
+        match self {
+            OpCode::HALT | OpCode::RETURN(_) | OpCode::POP
+            | OpCode::MSTORE | OpCode::MSTORE8 | OpCode::MCOPY | OpCode::MSTOREBYTES => 0,
+            _ => 1,
+        }
+    }
+
+    // Whether the run ends after this instruction.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, OpCode::HALT | OpCode::RETURN(_))
+    }
 }
 
+// Target type operand of FROMBYTESLE / FROMBYTESBE.
+pub const FROMBYTES_INTEGER: u8 = 0x00;
+pub const FROMBYTES_FLOAT: u8 = 0x01;
+pub const FROMBYTES_BYTE: u8 = 0x02;
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub union OperationValue {
@@ -33,3 +227,60 @@ pub struct Operation {
     pub kind: u8,
     pub val: OperationValue,
 }
+
+impl Operation {
+    // Decodes a host supplied operation. Returns None for unknown kinds.
+    pub fn decode(&self) -> Option<OpCode> {
+        let opcode = match self.kind {
+            0x00 => OpCode::HALT,
+            0x01 => OpCode::PUSHINT( unsafe { self.val.int_val } ),
+            0x02 => OpCode::PUSHFLOAT( unsafe { self.val.float_val } ),
+            0x03 => OpCode::PUSHBYTE( unsafe { self.val.byte_val } ),
+            0x04 => OpCode::PACK( unsafe { self.val.uint_val } ),
+            0x05 => OpCode::POP,
+            0x06 => OpCode::ADD,
+            0x07 => OpCode::SUB,
+            0x08 => OpCode::MUL,
+            0x09 => OpCode::DIV,
+            0x0A => OpCode::EQ,
+            0x0B => OpCode::GT,
+            0x0C => OpCode::GTE,
+            0x0D => OpCode::LT,
+            0x0E => OpCode::LTE,
+            0x0F => OpCode::CONCAT,
+            0x10 => OpCode::RETURN( unsafe { self.val.uint_val } ),
+            0x11 => OpCode::MLOAD,
+            0x12 => OpCode::MSTORE,
+            0x13 => OpCode::MSTORE8,
+            0x14 => OpCode::MCOPY,
+            0x15 => OpCode::MSIZE,
+            0x16 => OpCode::MLOADBYTES,
+            0x17 => OpCode::MSTOREBYTES,
+            0x18 => OpCode::I2F,
+            0x19 => OpCode::F2I,
+            0x1A => OpCode::I2B,
+            0x1B => OpCode::B2I,
+            0x1C => OpCode::BOOL2I,
+            0x1D => OpCode::TOBYTESLE,
+            0x1E => OpCode::TOBYTESBE,
+            0x1F => OpCode::FROMBYTESLE( unsafe { self.val.byte_val } ),
+            0x20 => OpCode::FROMBYTESBE( unsafe { self.val.byte_val } ),
+            0x21 => OpCode::MOD,
+            0x22 => OpCode::REM,
+            0x23 => OpCode::NEG,
+            0x24 => OpCode::ABS,
+            0x25 => OpCode::MIN,
+            0x26 => OpCode::MAX,
+            0x27 => OpCode::POW,
+            0x28 => OpCode::SQRT,
+            0x29 => OpCode::FLOOR,
+            0x2A => OpCode::CEIL,
+            0x2B => OpCode::ROUND,
+            0x2C => OpCode::TRUNC,
+            0x2D => OpCode::SLICE,
+            _ => return None,
+        };
+
+        Some(opcode)
+    }
+}
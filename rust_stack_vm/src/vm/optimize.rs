@@ -0,0 +1,323 @@
+use crate::stack::composite_stack::StackValue;
+use crate::vm::config::VMConfig;
+use crate::vm::op::OpCode;
+use crate::vm::vm::{VirtualMachine, VM};
+
+// Load-time optimization pass, enabled with VMConfig::optimize.
+//
+// Runs of constant pushes are tracked symbolically. A pure instruction whose
+// operands are all constants is evaluated once, by a scratch VM with the
+// program's config, and replaced by a push of its result; this covers
+// arithmetic, comparisons and conversions. PUSHBYTE* + PACK runs, and CONCATs
+// of them, collapse into a single PUSHBYTES. A constant followed by POP is
+// dropped. Any instruction that fails, such as
+// a DIV by zero, is left in place so the program still fails the same way
+// at run time.
+//
+// Folding lowers the peak stack depth, memory use and step count, so it must
+// not let a program that would hit a limit finish. Byte arrays are not
+// folded under a memory quota, Program skips the pass for programs that could
+// reach their step limit, and runs that could overflow the stack fall back to
+// the unoptimized program. Returns the instructions along with the index of
+// the instruction each one came from, which errors and profiles report.
+pub fn optimize(instructions: &[OpCode], config: VMConfig) -> (Vec<OpCode>, Vec<usize>) {
+    let mut out = Vec::with_capacity(instructions.len());
+    let mut pending: Vec<Constant> = Vec::new();
+
+    for (index, op) in instructions.iter().enumerate() {
+        if let Some(value) = push_value(op) {
+            pending.push(Constant { value, code: vec![(op.clone(), index)] });
+            continue;
+        }
+
+        if *op == OpCode::POP && !pending.is_empty() {
+            pending.pop();
+            continue;
+        }
+
+        if is_pure(op) && pending.len() >= op.arity() {
+            let first = pending.len() - op.arity();
+            let args = pending[first..].iter().map(|constant| constant.value.clone()).collect();
+
+            if let Some(value) = evaluate(op, args, config)
+                && !(config.max_memory_bytes != 0 && matches!(value, StackValue::ByteArray(_)))
+            {
+                let mut code: Vec<(OpCode, usize)> = pending.drain(first..).flat_map(Constant::emit).collect();
+                code.push((op.clone(), index));
+
+                pending.push(Constant { value, code });
+                continue;
+            }
+        }
+
+        out.extend(pending.drain(..).flat_map(Constant::emit));
+        out.push((op.clone(), index));
+    }
+
+    out.extend(pending.drain(..).flat_map(Constant::emit));
+
+    out.into_iter().unzip()
+}
+
+// A value known at load time and the shortest code found that produces it,
+// each instruction with the index it came from.
+struct Constant {
+    value: StackValue,
+    code: Vec<(OpCode, usize)>,
+}
+
+impl Constant {
+    // Emits a single push when the value has one, else the code. Bool has
+    // no push instruction. Folded code comes from the instruction that
+    // completed the value.
+    fn emit(self) -> Vec<(OpCode, usize)> {
+        let folded = match &self.value {
+            StackValue::Integer(i) => vec![OpCode::PUSHINT(*i)],
+            StackValue::Float(f) => vec![OpCode::PUSHFLOAT(*f)],
+            StackValue::Byte(b) => vec![OpCode::PUSHBYTE(*b)],
+            StackValue::ByteArray(bytes) => vec![OpCode::PUSHBYTES(bytes.clone())],
+            StackValue::Bool(_) => return self.code,
+        };
+
+        let code: Vec<OpCode> = self.code.iter().map(|(op, _)| op.clone()).collect();
+
+        if folded.len() <= code.len() && peak_depth(&folded) <= peak_depth(&code) {
+            let origin = self.code[self.code.len() - 1].1;
+
+            folded.into_iter().map(|op| (op, origin)).collect()
+        } else {
+            self.code
+        }
+    }
+}
+
+fn push_value(op: &OpCode) -> Option<StackValue> {
+    match op {
+        OpCode::PUSHINT(i) => Some(StackValue::Integer(*i)),
+        OpCode::PUSHFLOAT(f) => Some(StackValue::Float(*f)),
+        OpCode::PUSHBYTE(b) => Some(StackValue::Byte(*b)),
+        OpCode::PUSHBYTES(bytes) => Some(StackValue::ByteArray(bytes.clone())),
+        _ => None,
+    }
+}
+
+// Instructions whose result depends only on their operands and the config.
+fn is_pure(op: &OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::PACK(_) | OpCode::ADD | OpCode::SUB | OpCode::MUL | OpCode::DIV
+        | OpCode::EQ | OpCode::GT | OpCode::GTE | OpCode::LT | OpCode::LTE | OpCode::CONCAT
+        | OpCode::I2F | OpCode::F2I | OpCode::I2B | OpCode::B2I | OpCode::BOOL2I
+        | OpCode::TOBYTESLE | OpCode::TOBYTESBE | OpCode::FROMBYTESLE(_) | OpCode::FROMBYTESBE(_)
+        | OpCode::MOD | OpCode::REM | OpCode::NEG | OpCode::ABS | OpCode::MIN | OpCode::MAX
+        | OpCode::POW | OpCode::SQRT | OpCode::FLOOR | OpCode::CEIL | OpCode::ROUND | OpCode::TRUNC
+        | OpCode::SLICE
+    )
+}
+
+// Runs `op` on `args` exactly as the program would, or None if it fails.
+fn evaluate(op: &OpCode, args: Vec<StackValue>, config: VMConfig) -> Option<StackValue> {
+    let config = VMConfig { optimize: false, max_steps: 0, ..config };
+
+    let mut vm = VM::with_config(args.len().max(1), vec![op.clone(), OpCode::HALT], config);
+    vm.set_trace(false);
+
+    vm.load_inputs(args).ok()?;
+    vm.execute().ok()?.pop()
+}
+
+// Highest stack depth reached by self-contained constant code.
+fn peak_depth(code: &[OpCode]) -> usize {
+    let mut depth = 0usize;
+    let mut peak = 0usize;
+
+    for op in code {
+        depth = depth + 1 - op.arity();
+        peak = peak.max(depth);
+    }
+
+    peak
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::config::VMConfig;
+    use crate::vm::error::ErrorReport;
+    use crate::vm::op::{OpCode, FROMBYTES_BYTE, FROMBYTES_INTEGER};
+    use crate::vm::optimize::optimize;
+    use crate::vm::testing::Lcg;
+    use crate::vm::vm::{VirtualMachine, VM};
+
+    fn optimized(program: Vec<OpCode>) -> Vec<OpCode> {
+        optimize(&program, VMConfig::default()).0
+    }
+
+    #[test]
+    fn test_optimize_folds_constants() {
+        assert_eq!(
+            optimized(vec![OpCode::PUSHINT(2), OpCode::PUSHINT(3), OpCode::ADD, OpCode::HALT]),
+            vec![OpCode::PUSHINT(5), OpCode::HALT]
+        );
+
+        assert_eq!(
+            optimized(vec![OpCode::PUSHINT(1), OpCode::POP, OpCode::PUSHINT(7), OpCode::HALT]),
+            vec![OpCode::PUSHINT(7), OpCode::HALT]
+        );
+
+        assert_eq!(
+            optimized(vec![
+                OpCode::PUSHBYTE(1), OpCode::PACK(1),
+                OpCode::PUSHBYTE(2), OpCode::PUSHBYTE(3), OpCode::PACK(2),
+                OpCode::CONCAT,
+                OpCode::HALT,
+            ]),
+            vec![OpCode::PUSHBYTES(vec![1, 2, 3].into()), OpCode::HALT]
+        );
+
+        assert_eq!(
+            optimized(vec![OpCode::PUSHBYTE(1), OpCode::PUSHBYTE(2), OpCode::PACK(2), OpCode::HALT]),
+            vec![OpCode::PUSHBYTES(vec![1, 2].into()), OpCode::HALT]
+        );
+
+        // Bool has no push, so a folded comparison is only visible once it
+        // feeds another constant operation.
+        assert_eq!(
+            optimized(vec![OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::LT, OpCode::BOOL2I, OpCode::HALT]),
+            vec![OpCode::PUSHINT(1), OpCode::HALT]
+        );
+        assert_eq!(
+            optimized(vec![OpCode::PUSHINT(2), OpCode::PUSHINT(3), OpCode::ADD, OpCode::PUSHINT(4), OpCode::LT, OpCode::HALT]),
+            vec![OpCode::PUSHINT(5), OpCode::PUSHINT(4), OpCode::LT, OpCode::HALT]
+        );
+    }
+
+    #[test]
+    fn test_optimize_keeps_failing_and_runtime_operations() {
+        for program in [
+            vec![OpCode::PUSHINT(1), OpCode::PUSHINT(0), OpCode::DIV, OpCode::HALT],
+            vec![OpCode::PUSHINT(i32::MAX), OpCode::PUSHINT(1), OpCode::ADD, OpCode::HALT],
+            vec![OpCode::PUSHINT(1), OpCode::ADD, OpCode::HALT],
+            vec![OpCode::PUSHINT(0), OpCode::MLOAD, OpCode::HALT],
+        ] {
+            assert_eq!(optimized(program.clone()), program);
+        }
+
+        // Each array fits the quota but both together do not, so they are
+        // built at run time where the second PACK fails.
+        let quota = VMConfig { max_memory_bytes: 1, ..VMConfig::default() };
+        let packs = vec![
+            OpCode::PUSHBYTE(1), OpCode::PACK(1), OpCode::PUSHBYTE(2), OpCode::PACK(1),
+            OpCode::POP, OpCode::POP, OpCode::PUSHINT(0),
+            OpCode::HALT,
+        ];
+        assert_eq!(optimize(&packs, quota).0, packs);
+    }
+
+    #[test]
+    fn test_optimize_maps_instructions_to_their_origin() {
+        let program = vec![
+            OpCode::PUSHINT(2), OpCode::PUSHINT(3), OpCode::ADD,
+            OpCode::PUSHINT(0), OpCode::DIV,
+            OpCode::HALT,
+        ];
+
+        assert_eq!(
+            optimize(&program, VMConfig::default()),
+            (vec![OpCode::PUSHINT(5), OpCode::PUSHINT(0), OpCode::DIV, OpCode::HALT], vec![2, 3, 4, 5])
+        );
+    }
+
+    fn random_program(rng: &mut Lcg) -> Vec<OpCode> {
+        let pushes = [
+            OpCode::PUSHINT(0), OpCode::PUSHINT(1), OpCode::PUSHINT(-1), OpCode::PUSHINT(7),
+            OpCode::PUSHINT(i32::MAX), OpCode::PUSHINT(i32::MIN),
+            OpCode::PUSHFLOAT(0.0), OpCode::PUSHFLOAT(1.5), OpCode::PUSHFLOAT(-2.0),
+            OpCode::PUSHFLOAT(f32::NAN), OpCode::PUSHFLOAT(f32::INFINITY),
+            OpCode::PUSHBYTE(0), OpCode::PUSHBYTE(1), OpCode::PUSHBYTE(0xFF),
+        ];
+        let ops = [
+            OpCode::POP, OpCode::PACK(0), OpCode::PACK(1), OpCode::PACK(2),
+            OpCode::ADD, OpCode::SUB, OpCode::MUL, OpCode::DIV,
+            OpCode::EQ, OpCode::GT, OpCode::GTE, OpCode::LT, OpCode::LTE, OpCode::CONCAT,
+            OpCode::I2F, OpCode::F2I, OpCode::I2B, OpCode::B2I, OpCode::BOOL2I,
+            OpCode::TOBYTESLE, OpCode::TOBYTESBE,
+            OpCode::FROMBYTESLE(FROMBYTES_INTEGER), OpCode::FROMBYTESBE(FROMBYTES_BYTE),
+            OpCode::MOD, OpCode::REM, OpCode::NEG, OpCode::ABS, OpCode::MIN, OpCode::MAX,
+            OpCode::POW, OpCode::SQRT, OpCode::FLOOR, OpCode::CEIL, OpCode::ROUND, OpCode::TRUNC,
+            OpCode::MSIZE,
+        ];
+
+        let len = 1 + rng.next(12);
+        let mut program: Vec<OpCode> = (0..len)
+            .map(|_| match rng.next(2) {
+                0 => pushes[rng.next(pushes.len())].clone(),
+                _ => ops[rng.next(ops.len())].clone(),
+            })
+            .collect();
+
+        program.push(match rng.next(3) {
+            0 => OpCode::HALT,
+            1 => OpCode::RETURN(2),
+            _ => OpCode::RETURN(0),
+        });
+
+        program
+    }
+
+    // The result and the error report's message.
+    fn run(program: Vec<OpCode>, config: VMConfig, stack_size: usize) -> (String, Option<String>) {
+        let mut vm = VM::with_config(stack_size, program, config);
+        vm.set_trace(false);
+
+        // Debug output compares NaN payloads too, which == would not.
+        (format!("{:?}", vm.execute()), vm.last_error().map(ErrorReport::message))
+    }
+
+    #[test]
+    fn test_optimize_differential() {
+        let configs = [
+            VMConfig::default(),
+            VMConfig { deterministic_float: true, ..VMConfig::default() },
+            VMConfig { ieee_float_division: true, ..VMConfig::default() },
+        ];
+
+        let mut rng = Lcg(0x5EED);
+        let mut changed = 0;
+
+        let limited = VMConfig { max_steps: 4, max_memory_bytes: 2, ..VMConfig::default() };
+
+        for _ in 0..2000 {
+            let program = random_program(&mut rng);
+
+            for config in configs {
+                let (optimized, _) = optimize(&program, config);
+
+                if optimized != program {
+                    changed += 1;
+                }
+
+                // Error reports point into the optimized program here.
+                assert_eq!(
+                    run(optimized.clone(), config, 64).0,
+                    run(program.clone(), config, 64).0,
+                    "program {:?} optimized to {:?}", program, optimized
+                );
+            }
+
+            // Loaded programs also fail the same way, at the same
+            // instruction, on stacks small enough to overflow and within
+            // the limits.
+            for config in configs.into_iter().chain([limited]) {
+                for stack_size in [1, 2, 3, 64] {
+                    assert_eq!(
+                        run(program.clone(), VMConfig { optimize: true, ..config }, stack_size),
+                        run(program.clone(), config, stack_size),
+                        "program {:?} on a stack of {}", program, stack_size
+                    );
+                }
+            }
+        }
+
+        assert!(changed > 1000);
+    }
+}
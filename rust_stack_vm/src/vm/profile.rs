@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+use crate::vm::op::OpCode;
+
+// Number of instructions listed in the hot-spot section of the text report.
+pub const HOT_INSTRUCTIONS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ProfileStats {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl ProfileStats {
+    fn add(&mut self, other: ProfileStats) {
+        self.count += other.count;
+        self.time += other.time;
+    }
+}
+
+// Execution counts and time per instruction index, gathered by the VM while
+// profiling is enabled. Per-opcode figures are summed when a report is
+// built, so recording is a single indexed update.
+pub struct Profiler {
+    by_ip: Vec<ProfileStats>,
+}
+
+impl Profiler {
+    pub fn new(instruction_count: usize) -> Self {
+        Profiler {
+            by_ip: vec![ProfileStats::default(); instruction_count],
+        }
+    }
+
+    pub fn record(&mut self, ip: usize, elapsed: Duration) {
+        let stats = &mut self.by_ip[ip];
+
+        stats.count += 1;
+        stats.time += elapsed;
+    }
+
+    pub fn by_opcode(&self, instructions: &[OpCode]) -> BTreeMap<&'static str, ProfileStats> {
+        let mut by_opcode: BTreeMap<&'static str, ProfileStats> = BTreeMap::new();
+
+        for (stats, opcode) in self.executed(instructions) {
+            by_opcode.entry(opcode.name()).or_default().add(*stats);
+        }
+
+        by_opcode
+    }
+
+    fn executed<'a>(&'a self, instructions: &'a [OpCode]) -> impl Iterator<Item = (&'a ProfileStats, &'a OpCode)> {
+        self.by_ip.iter()
+            .zip(instructions)
+            .filter(|(stats, _)| stats.count > 0)
+    }
+
+    // Opcode totals followed by the HOT_INSTRUCTIONS slowest instructions,
+    // both sorted by total time.
+    pub fn report_text(&self, instructions: &[OpCode]) -> String {
+        let mut report = String::new();
+
+        let mut by_opcode: Vec<_> = self.by_opcode(instructions).into_iter().collect();
+        by_opcode.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+
+        let _ = writeln!(report, "{:<12} {:>12} {:>14} {:>10}", "opcode", "count", "total_ns", "avg_ns");
+
+        for (name, stats) in by_opcode {
+            let total = stats.time.as_nanos();
+
+            let _ = writeln!(report, "{:<12} {:>12} {:>14} {:>10}", name, stats.count, total, total / stats.count as u128);
+        }
+
+        let mut hot: Vec<_> = self.by_ip.iter().enumerate().filter(|(_, stats)| stats.count > 0).collect();
+        hot.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+
+        let _ = writeln!(report);
+        let _ = writeln!(report, "{:<8} {:<20} {:>12} {:>14}", "ip", "instruction", "count", "total_ns");
+
+        for (ip, stats) in hot.into_iter().take(HOT_INSTRUCTIONS) {
+            let instruction = format!("{:?}", instructions[ip]);
+
+            let _ = writeln!(report, "{:<8} {:<20} {:>12} {:>14}", ip, instruction, stats.count, stats.time.as_nanos());
+        }
+
+        report
+    }
+
+    // One `opcode;ip:instruction nanoseconds` line per executed instruction,
+    // the folded-stack input of flame graph tools such as flamegraph.pl.
+    pub fn report_folded(&self, instructions: &[OpCode]) -> String {
+        let mut report = String::new();
+
+        for (ip, (stats, opcode)) in self.by_ip.iter().zip(instructions).enumerate() {
+            if stats.count > 0 {
+                let _ = writeln!(report, "{};{}:{:?} {}", opcode.name(), ip, opcode, stats.time.as_nanos());
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::vm::op::OpCode;
+    use crate::vm::profile::{ProfileStats, Profiler};
+
+    #[test]
+    fn test_profiler_reports() {
+        let instructions = vec![OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::ADD, OpCode::HALT];
+
+        let mut profiler = Profiler::new(instructions.len());
+        profiler.record(0, Duration::from_nanos(10));
+        profiler.record(1, Duration::from_nanos(30));
+        profiler.record(2, Duration::from_nanos(50));
+        profiler.record(0, Duration::from_nanos(10));
+
+        let by_opcode = profiler.by_opcode(&instructions);
+        assert_eq!(by_opcode["PUSHINT"], ProfileStats { count: 3, time: Duration::from_nanos(50) });
+        assert_eq!(by_opcode["ADD"], ProfileStats { count: 1, time: Duration::from_nanos(50) });
+        assert!(!by_opcode.contains_key("HALT"));
+
+        assert_eq!(
+            profiler.report_folded(&instructions),
+            "PUSHINT;0:PUSHINT(1) 20\nPUSHINT;1:PUSHINT(2) 30\nADD;2:ADD 50\n"
+        );
+
+        let text = profiler.report_text(&instructions);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[1].starts_with("ADD "));
+        assert!(lines[2].starts_with("PUSHINT "));
+        assert!(lines[5].starts_with("2 "));
+        assert_eq!(lines.len(), 8);
+    }
+}
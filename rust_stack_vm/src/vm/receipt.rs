@@ -0,0 +1,17 @@
+// Statistics of the runs since the last reset, for billing and capacity
+// planning. The layout is shared with the host through
+// stack_vm_receipt.h, so fields are plain C types.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ExecutionReceipt {
+    pub instructions_executed: u64,
+    pub gas_used: u64,
+    // Most values held on the stack at once.
+    pub peak_stack_depth: u64,
+    // Most bytes held in byte arrays and linear memory at once.
+    pub peak_memory: u64,
+    pub frames_pushed: u64,
+    // Time spent inside execute, summed over resumed runs. Not carried
+    // over by snapshots.
+    pub wall_time_ns: u64,
+}
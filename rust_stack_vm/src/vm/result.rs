@@ -1,4 +1,11 @@
+use std::ffi::{c_char, CString};
 use std::ptr;
+use crate::api::VmError;
+use crate::stack::bytes::Bytes;
+use crate::stack::composite_stack::StackValue;
+use crate::stack::stack::StackError;
+use crate::vm::error::ErrorReport;
+use crate::handle::Handle;
 
 #[repr(C)]
 pub enum VMResultTag {
@@ -8,14 +15,41 @@ pub enum VMResultTag {
     ByteArray,
     Bool,
     Error,
+    // A panic was caught at the FFI boundary. The message is available
+    // through last_ffi_error_message.
+    Panic,
 }
 
+// Byte arrays handed to the host point straight into the VM's shared
+// buffer; owner keeps that buffer alive until free_byte_array. Byte arrays
+// the host passes in are copied, and their owner is ignored.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct ByteArrayPtr {
     pub ptr: *mut u8,
     pub len: usize,
-    pub capacity: usize,
+    pub owner: *const Vec<u8>,
+}
+
+impl ByteArrayPtr {
+    // Hands the bytes to the host without copying them; they are released
+    // with free_byte_array.
+    pub fn new(bytes: impl Into<Bytes>) -> Self {
+        let (ptr, len, owner) = bytes.into().into_raw();
+
+        ByteArrayPtr { ptr: ptr.cast_mut(), len, owner }
+    }
+
+    /// Releases bytes handed out by `new`.
+    ///
+    /// # Safety
+    ///
+    /// `self` must come from `new` and must not be released twice.
+    pub unsafe fn release(self) {
+        if !self.owner.is_null() {
+            unsafe { Bytes::release_raw(self.owner) };
+        }
+    }
 }
 
 #[repr(C)]
@@ -34,6 +68,34 @@ pub struct VMResult {
     pub value: VMResultValue,
 }
 
+#[repr(C)]
+pub struct VMResultArray {
+    pub ptr: *mut VMResult,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+#[repr(C)]
+pub struct VMHandleArray {
+    pub ptr: *mut Handle,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+// C view of an ErrorReport. Strings are NUL terminated and, like the stack
+// array, owned by the report until free_vm_error_report is called.
+#[repr(C)]
+pub struct VMErrorReport {
+    pub has_error: bool,
+    pub code: i32,
+    pub has_ip: bool,
+    pub ip: usize,
+    pub opcode: u8,
+    pub operand_types: *mut c_char,
+    pub message: *mut c_char,
+    pub stack: VMResultArray,
+}
+
 impl VMResult {
     pub fn new(tag: VMResultTag, value: VMResultValue) -> Self {
         Self { tag, value }
@@ -66,19 +128,11 @@ impl VMResult {
         }
     }
 
-    pub fn ok_byte_array(mut vec: Vec<u8>) -> Self {
-        vec.shrink_to_fit();
-
-        let (ptr, len, capacity) = (vec.as_mut_ptr(), vec.len(), vec.capacity());
-
-        std::mem::forget(vec);
-
-        let byte_array_ptr = ByteArrayPtr { ptr, len, capacity, };
-
+    pub fn ok_byte_array(bytes: Bytes) -> Self {
         Self {
             tag: VMResultTag::ByteArray,
             value: VMResultValue {
-                bytes_array_val: byte_array_ptr,
+                bytes_array_val: ByteArrayPtr::new(bytes),
             },
         }
     }
@@ -100,4 +154,176 @@ impl VMResult {
             }
         }
     }
-}
\ No newline at end of file
+
+    pub fn panic() -> Self {
+        Self {
+            tag: VMResultTag::Panic,
+            value: VMResultValue {
+                int_val: 0
+            }
+        }
+    }
+
+    /// Copies a host supplied tagged value into a `StackValue`.
+    ///
+    /// # Safety
+    ///
+    /// For `ByteArray` values `ptr` must point to `len` readable bytes.
+    pub unsafe fn to_stack_value(&self) -> Result<StackValue, StackError> {
+        let value = unsafe {
+            match self.tag {
+                VMResultTag::Integer => StackValue::Integer(self.value.int_val),
+                VMResultTag::Float => StackValue::Float(self.value.float_val),
+                VMResultTag::Byte => StackValue::Byte(self.value.byte_val),
+                VMResultTag::ByteArray => {
+                    let bytes = self.value.bytes_array_val;
+
+                    if bytes.ptr.is_null() {
+                        StackValue::ByteArray(Bytes::new())
+                    } else {
+                        StackValue::ByteArray(std::slice::from_raw_parts(bytes.ptr, bytes.len).into())
+                    }
+                },
+                VMResultTag::Bool => StackValue::Bool(self.value.bool_val),
+                VMResultTag::Error | VMResultTag::Panic => return Err(StackError::StackInvalidType),
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+impl From<StackValue> for VMResult {
+    fn from(value: StackValue) -> Self {
+        match value {
+            StackValue::Integer(i) => VMResult::ok_int(i),
+            StackValue::Float(f) => VMResult::ok_float(f),
+            StackValue::Byte(b) => VMResult::ok_byte(b),
+            StackValue::ByteArray(bytes) => VMResult::ok_byte_array(bytes),
+            StackValue::Bool(b) => VMResult::ok_bool(b),
+        }
+    }
+}
+
+// Error codes of the FFI layer itself, kept apart from the StackError codes
+// below so both can grow independently.
+pub const ERROR_NULL_POINTER: i32 = 100;
+pub const ERROR_INVALID_HANDLE: i32 = 101;
+
+// Error codes shared with the host through VMResult and VMErrorReport.
+pub fn error_code(e: StackError) -> i32 {
+    match e {
+        StackError::StackUnderFlow => 0,
+        StackError::StackOverFlow => 1,
+        StackError::StackInvalidType => 2,
+        StackError::DivisionByZero => 3,
+        StackError::InvalidState => 4,
+        StackError::MemoryOutOfBounds => 5,
+        StackError::InvalidConversion => 6,
+        StackError::IntegerOverflow => 7,
+        StackError::NonDeterministicOperation => 8,
+        StackError::StepLimitExceeded => 9,
+        StackError::Interrupted => 10,
+        StackError::MemoryLimitExceeded => 11,
+    }
+}
+
+impl From<StackError> for VMResult {
+    fn from(e: StackError) -> Self {
+        VMResult::err(error_code(e))
+    }
+}
+
+// Runs only fail with execution errors; the others are raised when a VM is
+// built or restored, which report through last_ffi_error_message instead.
+impl From<VmError> for VMResult {
+    fn from(e: VmError) -> Self {
+        match e {
+            VmError::Execution(e) => VMResult::from(e),
+            e => unreachable!("a run failed with {}", e),
+        }
+    }
+}
+
+impl VMResultArray {
+    pub fn new(mut results: Vec<VMResult>) -> Self {
+        results.shrink_to_fit();
+
+        let (ptr, len, capacity) = (results.as_mut_ptr(), results.len(), results.capacity());
+
+        std::mem::forget(results);
+
+        Self { ptr, len, capacity }
+    }
+
+    pub fn ok(values: Vec<StackValue>) -> Self {
+        Self::new(values.into_iter().map(VMResult::from).collect())
+    }
+
+    // On failure the array holds a single Error tagged result, so the host
+    // always walks the same structure.
+    pub fn err(e: VmError) -> Self {
+        Self::new(vec![VMResult::from(e)])
+    }
+
+    pub fn err_code(code: i32) -> Self {
+        Self::new(vec![VMResult::err(code)])
+    }
+
+    pub fn panic() -> Self {
+        Self::new(vec![VMResult::panic()])
+    }
+}
+
+impl VMHandleArray {
+    pub fn new(mut handles: Vec<Handle>) -> Self {
+        handles.shrink_to_fit();
+
+        let (ptr, len, capacity) = (handles.as_mut_ptr(), handles.len(), handles.capacity());
+
+        std::mem::forget(handles);
+
+        Self { ptr, len, capacity }
+    }
+}
+
+impl VMErrorReport {
+    pub fn none() -> Self {
+        Self {
+            has_error: false,
+            code: 0,
+            has_ip: false,
+            ip: 0,
+            opcode: 0,
+            operand_types: ptr::null_mut(),
+            message: ptr::null_mut(),
+            stack: VMResultArray::new(Vec::new()),
+        }
+    }
+
+    // A report for a call that failed before reaching a VM.
+    pub fn err_code(code: i32) -> Self {
+        Self { has_error: true, code, ..Self::none() }
+    }
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Messages never contain NUL bytes, but fall back to an empty string
+    // rather than failing the whole report.
+    CString::new(s).unwrap_or_default().into_raw()
+}
+
+impl From<&ErrorReport> for VMErrorReport {
+    fn from(report: &ErrorReport) -> Self {
+        Self {
+            has_error: true,
+            code: error_code(report.error),
+            has_ip: report.ip.is_some(),
+            ip: report.ip.unwrap_or(0),
+            opcode: report.opcode.as_ref().map_or(0, |op| op.kind()),
+            operand_types: into_c_string(report.operand_types.join(", ")),
+            message: into_c_string(report.message()),
+            stack: VMResultArray::ok(report.stack.clone()),
+        }
+    }
+}
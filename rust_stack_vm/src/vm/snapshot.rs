@@ -0,0 +1,381 @@
+use std::fmt::{self, Display, Formatter};
+use crate::stack::composite_stack::StackValue;
+use crate::vm::config::{VMConfig, BACKEND_REGISTER};
+use crate::vm::op::OpCode;
+use crate::vm::vm::VMState;
+
+// Snapshot layout, integers little endian:
+//
+//   magic "SVMS" | version u16 | program hash u64 | stack size u64
+//   deterministic_float u8 | ieee_float_division u8 | max_steps u64
+//   max_memory_bytes u64 | optimize u8 | backend u8 | state u8 | ip u64
+//   gas_used u64 | steps u64 | peak_memory u64 | peak_stack_depth u64
+//   frames_pushed u64 | stack values | frames | linear memory
+//
+// Sequences are prefixed with a u64 count and values are a VMResultTag
+// byte followed by their payload. The program is not stored; it is
+// supplied again on restore and checked against the hash.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"SVMS";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u16),
+    ProgramMismatch,
+    InvalidValue,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Truncated => f.write_str("snapshot is truncated"),
+            SnapshotError::BadMagic => f.write_str("not a vm snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::ProgramMismatch => f.write_str("snapshot was taken from a different program"),
+            SnapshotError::InvalidValue => f.write_str("snapshot contains an invalid value"),
+        }
+    }
+}
+
+// Everything needed to rebuild a VM at the point it was captured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VMSnapshot {
+    pub program_hash: u64,
+    pub stack_size: usize,
+    pub config: VMConfig,
+    pub state: VMState,
+    pub ip: usize,
+    pub gas_used: u64,
+    pub steps: u64,
+    pub peak_memory: usize,
+    pub peak_stack_depth: usize,
+    pub frames_pushed: usize,
+    pub stack: Vec<StackValue>,
+    pub frames: Vec<Vec<StackValue>>,
+    pub memory: Vec<u8>,
+}
+
+// 64-bit FNV-1a over each instruction's kind and operand bits, and the
+// bytes a PUSHBYTES pushes. Used to detect restoring a snapshot against the
+// wrong program, not as a cryptographic digest.
+pub fn program_hash(instructions: &[OpCode]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+
+    for opcode in instructions {
+        let mut encoded = [0u8; 5];
+        encoded[0] = opcode.kind();
+        encoded[1..].copy_from_slice(&opcode.operand_bits().to_le_bytes());
+
+        // Length prefixed, so the bytes cannot run into the next instruction.
+        let pushed = match opcode {
+            OpCode::PUSHBYTES(bytes) => [&(bytes.len() as u64).to_le_bytes()[..], bytes].concat(),
+            _ => Vec::new(),
+        };
+
+        for &byte in encoded.iter().chain(&pushed) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01B3);
+        }
+    }
+
+    hash
+}
+
+const TAG_INTEGER: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_BYTE: u8 = 2;
+const TAG_BYTE_ARRAY: u8 = 3;
+const TAG_BOOL: u8 = 4;
+
+fn state_code(state: VMState) -> u8 {
+    match state {
+        VMState::Ready => 0,
+        VMState::Halted => 1,
+        VMState::Faulted => 2,
+        VMState::Suspended => 3,
+    }
+}
+
+fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_values(out: &mut Vec<u8>, values: &[StackValue]) {
+    write_u64(out, values.len() as u64);
+
+    for value in values {
+        match value {
+            StackValue::Integer(i) => {
+                out.push(TAG_INTEGER);
+                out.extend_from_slice(&i.to_le_bytes());
+            },
+            StackValue::Float(f) => {
+                out.push(TAG_FLOAT);
+                out.extend_from_slice(&f.to_bits().to_le_bytes());
+            },
+            StackValue::Byte(b) => {
+                out.push(TAG_BYTE);
+                out.push(*b);
+            },
+            StackValue::ByteArray(bytes) => {
+                out.push(TAG_BYTE_ARRAY);
+                write_u64(out, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            },
+            StackValue::Bool(b) => {
+                out.push(TAG_BOOL);
+                out.push(*b as u8);
+            },
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::InvalidValue),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::InvalidValue)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.usize()?;
+
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn values(&mut self) -> Result<Vec<StackValue>, SnapshotError> {
+        let count = self.usize()?;
+
+        // Every value takes at least two bytes, so a forged count cannot
+        // make us reserve more than the snapshot could hold.
+        let mut values = Vec::with_capacity(count.min(self.bytes.len() / 2));
+
+        for _ in 0..count {
+            let value = match self.u8()? {
+                TAG_INTEGER => StackValue::Integer(i32::from_le_bytes(self.array()?)),
+                TAG_FLOAT => StackValue::Float(f32::from_bits(u32::from_le_bytes(self.array()?))),
+                TAG_BYTE => StackValue::Byte(self.u8()?),
+                TAG_BYTE_ARRAY => StackValue::ByteArray(self.bytes()?.into()),
+                TAG_BOOL => StackValue::Bool(self.bool()?),
+                _ => return Err(SnapshotError::InvalidValue),
+            };
+
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+}
+
+impl VMSnapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(&SNAPSHOT_MAGIC);
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        write_u64(&mut out, self.program_hash);
+        write_u64(&mut out, self.stack_size as u64);
+
+        out.push(self.config.deterministic_float as u8);
+        out.push(self.config.ieee_float_division as u8);
+        write_u64(&mut out, self.config.max_steps);
+        write_u64(&mut out, self.config.max_memory_bytes);
+        out.push(self.config.optimize as u8);
+        out.push(self.config.backend);
+
+        out.push(state_code(self.state));
+        write_u64(&mut out, self.ip as u64);
+        write_u64(&mut out, self.gas_used);
+        write_u64(&mut out, self.steps);
+        write_u64(&mut out, self.peak_memory as u64);
+        write_u64(&mut out, self.peak_stack_depth as u64);
+        write_u64(&mut out, self.frames_pushed as u64);
+
+        write_values(&mut out, &self.stack);
+
+        write_u64(&mut out, self.frames.len() as u64);
+        for frame in &self.frames {
+            write_values(&mut out, frame);
+        }
+
+        write_u64(&mut out, self.memory.len() as u64);
+        out.extend_from_slice(&self.memory);
+
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes };
+
+        if reader.array::<4>()? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes(reader.array()?);
+
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let program_hash = reader.u64()?;
+        let stack_size = reader.usize()?;
+
+        let config = VMConfig {
+            deterministic_float: reader.bool()?,
+            ieee_float_division: reader.bool()?,
+            max_steps: reader.u64()?,
+            max_memory_bytes: reader.u64()?,
+            optimize: reader.bool()?,
+            backend: reader.u8()?,
+        };
+
+        if config.backend > BACKEND_REGISTER {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        let state = match reader.u8()? {
+            0 => VMState::Ready,
+            1 => VMState::Halted,
+            2 => VMState::Faulted,
+            3 => VMState::Suspended,
+            _ => return Err(SnapshotError::InvalidValue),
+        };
+
+        let ip = reader.usize()?;
+        let gas_used = reader.u64()?;
+        let steps = reader.u64()?;
+        let peak_memory = reader.usize()?;
+        let peak_stack_depth = reader.usize()?;
+        let frames_pushed = reader.usize()?;
+
+        let stack = reader.values()?;
+
+        let frame_count = reader.usize()?;
+        let mut frames = Vec::with_capacity(frame_count.min(reader.bytes.len() / 8));
+        for _ in 0..frame_count {
+            frames.push(reader.values()?);
+        }
+
+        let memory = reader.bytes()?;
+
+        if !reader.bytes.is_empty() {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        Ok(VMSnapshot {
+            program_hash,
+            stack_size,
+            config,
+            state,
+            ip,
+            gas_used,
+            steps,
+            peak_memory,
+            peak_stack_depth,
+            frames_pushed,
+            stack,
+            frames,
+            memory,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stack::composite_stack::StackValue;
+    use crate::vm::config::{VMConfig, BACKEND_REGISTER};
+    use crate::vm::op::OpCode;
+    use crate::vm::snapshot::{program_hash, SnapshotError, VMSnapshot};
+    use crate::vm::vm::VMState;
+
+    #[test]
+    fn test_snapshot_encoding_round_trip() {
+        let snapshot = VMSnapshot {
+            program_hash: program_hash(&[OpCode::PUSHINT(1), OpCode::HALT]),
+            stack_size: 8,
+            config: VMConfig { deterministic_float: true, max_steps: 100, backend: BACKEND_REGISTER, ..VMConfig::default() },
+            state: VMState::Suspended,
+            ip: 1,
+            gas_used: 512,
+            steps: 1,
+            peak_memory: 4096,
+            peak_stack_depth: 6,
+            frames_pushed: 3,
+            stack: vec![
+                StackValue::Integer(-1),
+                StackValue::Float(f32::NAN),
+                StackValue::Byte(0xAB),
+                StackValue::ByteArray(vec![1, 2, 3].into()),
+                StackValue::Bool(true),
+            ],
+            frames: vec![vec![StackValue::Integer(2)]],
+            memory: vec![7; 16],
+        };
+
+        let encoded = snapshot.encode();
+        let decoded = VMSnapshot::decode(&encoded).unwrap();
+
+        // NaN != NaN, so compare the encodings instead of the values.
+        assert_eq!(decoded.encode(), encoded);
+        assert_eq!(decoded.stack[3], StackValue::ByteArray(vec![1, 2, 3].into()));
+
+        assert_eq!(VMSnapshot::decode(&encoded[..encoded.len() - 1]), Err(SnapshotError::Truncated));
+        assert_eq!(VMSnapshot::decode(b"nope"), Err(SnapshotError::BadMagic));
+
+        let mut future = encoded.clone();
+        future[4] = 2;
+        assert_eq!(VMSnapshot::decode(&future), Err(SnapshotError::UnsupportedVersion(2)));
+
+        let optimize_at = 4 + 2 + 8 + 8 + 2 + 8 + 8;
+
+        let mut unknown_backend = encoded.clone();
+        unknown_backend[optimize_at + 1] = 2;
+        assert_eq!(VMSnapshot::decode(&unknown_backend), Err(SnapshotError::InvalidValue));
+    }
+
+    #[test]
+    fn test_program_hash_covers_operands() {
+        assert_eq!(program_hash(&[OpCode::PUSHINT(1)]), program_hash(&[OpCode::PUSHINT(1)]));
+        assert_ne!(program_hash(&[OpCode::PUSHINT(1)]), program_hash(&[OpCode::PUSHINT(2)]));
+        assert_ne!(program_hash(&[OpCode::PUSHINT(0)]), program_hash(&[OpCode::HALT]));
+        assert_ne!(
+            program_hash(&[OpCode::PUSHBYTES(vec![1].into())]),
+            program_hash(&[OpCode::PUSHBYTES(vec![2].into())])
+        );
+    }
+}
@@ -0,0 +1,13 @@
+// Helpers shared by the unit tests.
+
+// Small deterministic generator, so the differential tests need no deps.
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    // A number below n.
+    pub fn next(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+
+        ((self.0 >> 33) as usize) % n
+    }
+}
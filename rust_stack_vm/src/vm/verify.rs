@@ -0,0 +1,120 @@
+use std::fmt::{self, Display, Formatter};
+use crate::vm::op::{OpCode, FROMBYTES_BYTE};
+
+// Static checks of a program. Programs have no control flow, so the stack
+// depth before every instruction is known once the number of inputs is:
+// a run that would certainly underflow or overflow the stack is rejected
+// without running it. Errors that depend on values, such as type errors or
+// division by zero, are left to the VM.
+
+/// What [`verify`] learned about a program that passed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verification {
+    /// Most values on the stack at once.
+    pub max_stack_depth: usize,
+    /// Problems that do not stop the program from running, such as
+    /// instructions after the end of the run.
+    pub warnings: Vec<String>,
+}
+
+/// Why a program cannot run to completion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// Index of the offending instruction, or None for the implicit HALT
+    /// at the end of the program.
+    pub ip: Option<usize>,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "instruction {}: {}", ip, self.message),
+            None => write!(f, "end of program: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks that `program`, started with `inputs` values on a stack of
+/// `stack_size` slots, never underflows or overflows it and only uses
+/// valid operands.
+pub fn verify(program: &[OpCode], inputs: usize, stack_size: usize) -> Result<Verification, VerifyError> {
+    let mut depth = inputs;
+    let mut max_stack_depth = depth;
+    let mut warnings = Vec::new();
+
+    if depth > stack_size {
+        return Err(VerifyError {
+            ip: None,
+            message: format!("{} inputs do not fit a stack of {}", inputs, stack_size),
+        });
+    }
+
+    for (ip, op) in program.iter().enumerate() {
+        let error = |message: String| Err(VerifyError { ip: Some(ip), message });
+
+        if let OpCode::FROMBYTESLE(target) | OpCode::FROMBYTESBE(target) = op
+            && *target > FROMBYTES_BYTE
+        {
+            return error(format!("{} has an unknown target type {}", op.name(), target));
+        }
+
+        // HALT returns the top value, so it needs one like any unary
+        // instruction.
+        let needed = op.arity().max(matches!(op, OpCode::HALT) as usize);
+
+        if depth < needed {
+            return error(format!("{} needs {} operands but the stack holds {}", op.name(), needed, depth));
+        }
+
+        if op.is_terminator() {
+            if ip + 1 < program.len() {
+                warnings.push(format!("instructions {} to {} never run", ip + 1, program.len() - 1));
+            }
+
+            return Ok(Verification { max_stack_depth, warnings });
+        }
+
+        depth = depth - needed + op.results();
+        max_stack_depth = max_stack_depth.max(depth);
+
+        if depth > stack_size {
+            return error(format!("{} overflows a stack of {}", op.name(), stack_size));
+        }
+    }
+
+    // The end of the program halts with the top value.
+    if depth == 0 {
+        return Err(VerifyError { ip: None, message: "the stack is empty when the program ends".to_string() });
+    }
+
+    Ok(Verification { max_stack_depth, warnings })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::op::OpCode;
+    use crate::vm::verify::verify;
+
+    #[test]
+    fn test_verify_tracks_stack_depth() {
+        let program = [OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::PACK(2), OpCode::PUSHINT(0), OpCode::ADD];
+
+        let verification = verify(&program, 1, 4).unwrap();
+        assert_eq!(verification.max_stack_depth, 3);
+        assert!(verification.warnings.is_empty());
+
+        assert_eq!(verify(&program, 2, 3).unwrap_err().to_string(), "instruction 1: PUSHINT overflows a stack of 3");
+        assert_eq!(verify(&[OpCode::PUSHINT(1), OpCode::ADD], 0, 4).unwrap_err().to_string(),
+            "instruction 1: ADD needs 2 operands but the stack holds 1");
+        assert_eq!(verify(&[OpCode::PUSHINT(1), OpCode::POP], 0, 4).unwrap_err().to_string(),
+            "end of program: the stack is empty when the program ends");
+        assert_eq!(verify(&[OpCode::FROMBYTESLE(7)], 1, 4).unwrap_err().to_string(),
+            "instruction 0: FROMBYTESLE has an unknown target type 7");
+
+        let verification = verify(&[OpCode::RETURN(1), OpCode::POP, OpCode::POP], 1, 4).unwrap();
+        assert_eq!(verification.warnings, vec!["instructions 1 to 2 never run".to_string()]);
+    }
+}
@@ -1,278 +1,2268 @@
-use crate::vm::op::OpCode;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::vm::op::{OpCode, FROMBYTES_BYTE, FROMBYTES_FLOAT, FROMBYTES_INTEGER};
+use crate::vm::memory::{LinearMemory, DEFAULT_MAX_PAGES, PAGE_SIZE};
+use crate::vm::config::{VMConfig, BACKEND_STACK};
+use crate::vm::float;
+use crate::vm::error::{ErrorReport, ERROR_STACK_DEPTH, MAX_REPORTED_OPERANDS};
+use crate::vm::snapshot::{program_hash, SnapshotError, VMSnapshot};
+use crate::vm::receipt::ExecutionReceipt;
+use crate::vm::profile::Profiler;
 use crate::stack::composite_stack::{CompositeStack, StackValue};
-use crate::stack::stack::{Stack, StackError};
+use crate::stack::slot::Slot;
+use crate::stack::stack::{FrameStack, Stack, StackError};
+
+mod dispatch;
+mod program;
+mod registers;
+
+use dispatch::Instruction;
+
+pub use program::Program;
 
 pub trait VirtualMachine {
-    fn execute(&mut self) -> Result<StackValue, StackError>;
+    fn execute(&mut self) -> Result<Vec<StackValue>, StackError>;
+    fn reset(&mut self);
+    fn load_inputs(&mut self, inputs: Vec<StackValue>) -> Result<(), StackError>;
+}
+
+// Lifecycle of a VM:
+//
+//   Ready --execute--> Halted | Faulted --reset--> Ready
+//           |
+//           +--interrupt--> Suspended --execute--> (resumes at ip)
+//
+// A VM executes its program at most once per reset. Calling execute on a
+// Halted or Faulted VM returns StackError::InvalidState instead of running
+// past the end of the program against a dirty stack. An interrupted run is
+// Suspended before the instruction at ip, so it can be resumed, or
+// snapshotted and resumed elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VMState {
+    Ready,
+    Halted,
+    Faulted,
+    Suspended,
 }
 
+// Gas charged for every page the linear memory grows by.
+pub const MEMORY_PAGE_GAS: u64 = 512;
+
+// The interrupt flag is polled once every this many steps, keeping the
+// atomic load off the hot path.
+pub const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+// Largest stack a VM may be created or restored with. The whole stack is
+// allocated up front, and a failed allocation aborts the process instead of
+// returning an error.
+pub const MAX_STACK_SIZE: usize = 1 << 20;
+
 pub struct VM {
     stack: CompositeStack,
-    instructions: Vec<OpCode>,
-    ip: usize
+    memory: LinearMemory,
+    program: Arc<Program>,
+    // The program as loaded while a run uses its unoptimized form instead.
+    loaded: Option<Arc<Program>>,
+    // The VM's copies of the register blocks' register files, made on the
+    // first run with the register backend.
+    register_files: Vec<Vec<StackValue>>,
+    ip: usize,
+    state: VMState,
+    gas_used: u64,
+    steps: u64,
+    // Step count at which the run loop next checks the step limit and the
+    // interrupt flag.
+    next_check: u64,
+    // Highest number of bytes held in byte arrays and linear memory since
+    // the last reset.
+    peak_memory: usize,
+    wall_time: Duration,
+    config: VMConfig,
+    // Set by the host from another thread to stop a running program.
+    interrupt: Arc<AtomicBool>,
+    // Index of the instruction being executed and the types of the values
+    // it may consume, kept so a failure can be reported with context.
+    current: Option<usize>,
+    operand_types: [Option<&'static str>; MAX_REPORTED_OPERANDS],
+    last_error: Option<ErrorReport>,
+    // Present only while profiling is enabled. The run loop is compiled
+    // separately for profiled runs, so a disabled profiler costs nothing.
+    profiler: Option<Box<Profiler>>,
+    // Prints the stack after every instruction. Pooled VMs run concurrently,
+    // where interleaved traces are useless, so they turn it off.
+    trace: bool,
 }
 
+// VMs are handed between host threads through the handle table and pools.
+const _: () = {
+    const fn assert_send<T: Send>() {}
+
+    assert_send::<VM>();
+};
+
 impl VM {
+    #[allow(dead_code)]
     pub fn new(stack_size: usize, instructions: Vec<OpCode>) -> Self {
+        VM::with_config(stack_size, instructions, VMConfig::default())
+    }
+
+    pub fn with_config(stack_size: usize, instructions: Vec<OpCode>, config: VMConfig) -> Self {
+        VM::from_program(stack_size, Arc::new(Program::new(instructions, config)))
+    }
+
+    // Creates a VM running a loaded program, with the program's config.
+    pub fn from_program(stack_size: usize, program: Arc<Program>) -> Self {
         assert!(0 < stack_size);
 
         VM {
             stack: CompositeStack::new(stack_size),
-            instructions,
+            memory: LinearMemory::new(DEFAULT_MAX_PAGES),
+            config: program.config,
+            program,
+            loaded: None,
+            register_files: Vec::new(),
             ip: 0,
+            state: VMState::Ready,
+            gas_used: 0,
+            steps: 0,
+            next_check: 0,
+            peak_memory: 0,
+            wall_time: Duration::ZERO,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            current: None,
+            operand_types: [None; MAX_REPORTED_OPERANDS],
+            last_error: None,
+            profiler: None,
+            trace: true,
+        }
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    // Swaps in a new program and resets the VM, keeping the stack and
+    // memory allocations for reuse. A running profile starts over.
+    pub fn load_program(&mut self, instructions: Vec<OpCode>) {
+        self.set_program(Arc::new(Program::new(instructions, self.config)));
+    }
+
+    pub fn program(&self) -> &Arc<Program> {
+        self.loaded.as_ref().unwrap_or(&self.program)
+    }
+
+    // Like load_program for a program that is already loaded. The VM takes
+    // on the program's config.
+    pub fn set_program(&mut self, program: Arc<Program>) {
+        self.config = program.config;
+        self.program = program;
+        self.loaded = None;
+        self.register_files.clear();
+        self.reset();
+
+        if self.profiler.is_some() {
+            self.set_profiling(true);
+        }
+    }
+
+    // Enabling starts a fresh profile that accumulates over every run until
+    // profiling is enabled again or the program changes.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = enabled.then(|| Box::new(Profiler::new(self.program.source_instructions().len())));
+    }
+
+    // The profile as a text table, or as folded stacks for flame graph
+    // tools. None while profiling is disabled.
+    pub fn profile_report(&self, folded: bool) -> Option<String> {
+        let profiler = self.profiler.as_ref()?;
+
+        Some(if folded {
+            profiler.report_folded(self.program.source_instructions())
+        } else {
+            profiler.report_text(self.program.source_instructions())
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> VMState {
+        self.state
+    }
+
+    #[allow(dead_code)]
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    pub fn receipt(&self) -> ExecutionReceipt {
+        ExecutionReceipt {
+            instructions_executed: self.steps,
+            gas_used: self.gas_used,
+            peak_stack_depth: self.stack.peak_len() as u64,
+            peak_memory: self.peak_memory as u64,
+            frames_pushed: self.stack.frames_pushed() as u64,
+            wall_time_ns: self.wall_time.as_nanos() as u64,
+        }
+    }
+
+    // Shared flag that stops the running program with
+    // StackError::Interrupted. It can be set without locking the VM and is
+    // cleared on reset.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    pub fn last_error(&self) -> Option<&ErrorReport> {
+        self.last_error.as_ref()
+    }
+
+    // Used by the FFI layer when execution was aborted by a panic, so the VM
+    // is not run again from a half-updated state without a reset.
+    pub fn mark_faulted(&mut self) {
+        self.state = VMState::Faulted;
+    }
+
+    // Whether a run since the program was set used the register backend.
+    #[cfg(test)]
+    pub fn ran_register_blocks(&self) -> bool {
+        !self.register_files.is_empty()
+    }
+
+    // Values on the stack, bottom first.
+    pub fn stack_values(&self) -> Vec<StackValue> {
+        self.stack.values().iter().map(Slot::to_value).collect()
+    }
+
+    // Runs instructions against the current stack and memory without
+    // resetting them, for the REPL. They replace the program and run
+    // unoptimized through step. Running off the end leaves the stack as it
+    // is instead of halting, and HALT or RETURN hand back their values
+    // without ending the session: the VM stays ready for more instructions
+    // whatever happens, with the stack a failing instruction left behind.
+    pub fn execute_incremental(&mut self, instructions: Vec<OpCode>) -> Result<Option<Vec<StackValue>>, StackError> {
+        if self.state != VMState::Ready {
+            self.current = None;
+            self.last_error = Some(self.report_error(StackError::InvalidState));
+
+            return Err(StackError::InvalidState);
+        }
+
+        let config = VMConfig { optimize: false, backend: BACKEND_STACK, ..self.config };
+
+        self.program = Arc::new(Program::new(instructions, config));
+        self.loaded = None;
+        self.register_files.clear();
+        self.ip = 0;
+        self.last_error = None;
+
+        let started = Instant::now();
+        let mut result = Ok(None);
+
+        while self.ip < self.program.instructions.len() && matches!(result, Ok(None)) {
+            // Only the step limit and interrupts matter here; step checks
+            // the memory quota itself.
+            result = self.check_limits().and_then(|_| {
+                self.steps += 1;
+                self.step()
+            });
+        }
+
+        self.wall_time += started.elapsed();
+
+        if let Err(e) = result {
+            self.last_error = Some(self.report_error(e));
+        }
+
+        result
+    }
+
+    pub fn snapshot(&self) -> VMSnapshot {
+        VMSnapshot {
+            program_hash: program_hash(&self.program.instructions),
+            stack_size: self.stack.capacity(),
+            config: self.config,
+            state: self.state,
+            ip: self.ip,
+            gas_used: self.gas_used,
+            steps: self.steps,
+            peak_memory: self.peak_memory,
+            peak_stack_depth: self.stack.peak_len(),
+            frames_pushed: self.stack.frames_pushed(),
+            stack: self.stack.values().iter().map(Slot::to_value).collect(),
+            frames: self.stack.frames().iter().map(|frame| frame.iter().map(Slot::to_value).collect()).collect(),
+            memory: self.memory.bytes().to_vec(),
+        }
+    }
+
+    // Rebuilds the VM captured by `snapshot`. `instructions` must be the
+    // program the snapshot was taken from, as it was originally loaded; it
+    // is optimized again if the snapshot's config asks for it. The snapshot
+    // may come from a run of the unoptimized form.
+    pub fn restore(snapshot: VMSnapshot, instructions: Vec<OpCode>) -> Result<VM, SnapshotError> {
+        let program = Arc::new(Program::new(instructions, snapshot.config));
+
+        let (running, loaded) = match &program.source {
+            _ if snapshot.program_hash == program_hash(&program.instructions) => (Arc::clone(&program), None),
+            Some(source) if snapshot.program_hash == program_hash(&source.program.instructions) => {
+                (Arc::clone(&source.program), Some(program))
+            },
+            _ => return Err(SnapshotError::ProgramMismatch),
+        };
+
+        if !(1..=MAX_STACK_SIZE).contains(&snapshot.stack_size) || snapshot.ip > running.instructions.len() {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        let mut vm = VM::from_program(snapshot.stack_size, running);
+        vm.loaded = loaded;
+
+        for value in snapshot.stack {
+            vm.stack.push(value).map_err(|_| SnapshotError::InvalidValue)?;
+        }
+
+        for frame in snapshot.frames {
+            vm.stack.push_frame(frame.into_boxed_slice()).map_err(|_| SnapshotError::InvalidValue)?;
+        }
+
+        vm.memory.ensure(0, snapshot.memory.len()).map_err(|_| SnapshotError::InvalidValue)?;
+        vm.memory.write(0, &snapshot.memory);
+
+        if vm.memory.size() != snapshot.memory.len() {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        // Pushing the frames back counted them again, so the statistics are
+        // set as they were saved.
+        if !(vm.stack.values().len()..=snapshot.stack_size).contains(&snapshot.peak_stack_depth)
+            || snapshot.frames_pushed < vm.stack.frames().len()
+        {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        vm.stack.restore_statistics(snapshot.peak_stack_depth, snapshot.frames_pushed);
+
+        vm.state = snapshot.state;
+        vm.ip = snapshot.ip;
+        vm.gas_used = snapshot.gas_used;
+        vm.steps = snapshot.steps;
+        vm.peak_memory = snapshot.peak_memory;
+
+        Ok(vm)
+    }
+
+    fn report_error(&self, error: StackError) -> ErrorReport {
+        // Reported against the program as loaded.
+        let ip = self.current.map(|ip| self.program.origin(ip));
+        let opcode = ip.map(|ip| self.program.source_instructions()[ip].clone());
+
+        let operand_count = opcode.as_ref().map_or(0, |op| op.arity().min(MAX_REPORTED_OPERANDS));
+
+        let operand_types = self.operand_types[MAX_REPORTED_OPERANDS - operand_count..]
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+
+        ErrorReport {
+            error,
+            ip,
+            opcode,
+            operand_types,
+            stack: self.stack.top(ERROR_STACK_DEPTH).iter().map(Slot::to_value).collect(),
         }
     }
 }
 
 impl Drop for VM {
     fn drop(&mut self) {
-        println!("VM memory has been deallocated.");
+        if self.trace {
+            println!("VM memory has been deallocated.");
+        }
     }
 }
 
 impl VirtualMachine for VM {
-    fn execute(&mut self) -> Result<StackValue, StackError> {
+    fn execute(&mut self) -> Result<Vec<StackValue>, StackError> {
+        if self.state != VMState::Ready && self.state != VMState::Suspended {
+            self.current = None;
+            self.last_error = Some(self.report_error(StackError::InvalidState));
+
+            return Err(StackError::InvalidState);
+        }
+
+        self.last_error = None;
+
+        // Folding lowers the stack depth a run needs, so a run that could
+        // overflow uses the unoptimized program to fail where it would.
+        if self.state == VMState::Ready
+            && self.ip == 0
+            && let Some(program) = self.program.unoptimized_for(self.stack.values().len(), self.stack.capacity())
+        {
+            let program = Arc::clone(program);
+            self.loaded = Some(mem::replace(&mut self.program, program));
+            self.register_files.clear();
+        }
+
+        // Forces the limits to be checked before the first instruction.
+        self.next_check = self.steps;
+
+        let started = Instant::now();
+        let result = match (self.trace, self.profiler.is_some()) {
+            (false, false) if self.program.registers.is_some() => self.run_registers::<false>(),
+            (true, false) if self.program.registers.is_some() => self.run_registers::<true>(),
+            (false, false) => self.run::<false, false>(),
+            (false, true) => self.run::<false, true>(),
+            (true, false) => self.run::<true, false>(),
+            (true, true) => self.run::<true, true>(),
+        };
+        self.wall_time += started.elapsed();
+
+        self.state = match &result {
+            Ok(_) => VMState::Halted,
+            Err(StackError::Interrupted) => {
+                self.last_error = Some(self.report_error(StackError::Interrupted));
+
+                VMState::Suspended
+            },
+            Err(e) => {
+                self.last_error = Some(self.report_error(*e));
+
+                VMState::Faulted
+            },
+        };
+
+        result
+    }
+
+    fn reset(&mut self) {
+        if let Some(program) = self.loaded.take() {
+            self.program = program;
+            self.register_files.clear();
+        }
+
+        self.stack.clear();
+        self.memory.clear();
+        self.ip = 0;
+        self.state = VMState::Ready;
+        self.gas_used = 0;
+        self.steps = 0;
+        self.peak_memory = 0;
+        self.wall_time = Duration::ZERO;
+        self.interrupt.store(false, Ordering::Relaxed);
+        self.current = None;
+        self.last_error = None;
+    }
+
+    // Resets the VM and pushes the inputs in order, so the last input is on
+    // top of the stack when the program starts.
+    fn load_inputs(&mut self, inputs: Vec<StackValue>) -> Result<(), StackError> {
+        self.reset();
+
+        for input in inputs {
+            self.stack.push(input)?;
+            self.canonicalize_top();
+        }
+
+        self.track_memory()
+    }
+}
+
+impl VM {
+    // Pops a memory offset or length, which must be a non-negative Integer.
+    fn pop_address(&mut self) -> Result<usize, StackError> {
+        match self.stack.pop()? {
+            StackValue::Integer(i) if i >= 0 => Ok(i as usize),
+            StackValue::Integer(_) => Err(StackError::MemoryOutOfBounds),
+            _ => Err(StackError::StackInvalidType),
+        }
+    }
+
+    fn memory_used(&self) -> usize {
+        self.stack.heap_bytes() + self.memory.size()
+    }
+
+    // Fails if allocating `extra` more bytes would exceed the quota.
+    fn check_memory(&self, extra: usize) -> Result<(), StackError> {
+        let limit = self.config.max_memory_bytes;
+
+        if limit != 0 && (self.memory_used() as u64).saturating_add(extra as u64) > limit {
+            return Err(StackError::MemoryLimitExceeded);
+        }
+
+        Ok(())
+    }
+
+    // Records peak usage and enforces the quota after every instruction.
+    // CONCAT and memory growth, which can allocate without bound, also call
+    // check_memory before allocating, so the remaining overshoot is limited
+    // to small fixed-size results.
+    fn track_memory(&mut self) -> Result<(), StackError> {
+        self.record_peak();
+
+        self.check_memory(0)
+    }
+
+    fn record_peak(&mut self) {
+        self.peak_memory = self.peak_memory.max(self.memory_used());
+    }
+
+    // Grows memory to cover [offset, offset + len) and charges for new pages.
+    fn touch_memory(&mut self, offset: usize, len: usize) -> Result<(), StackError> {
+        let end = offset.checked_add(len).and_then(|end| end.checked_next_multiple_of(PAGE_SIZE));
+
+        if let (true, Some(end)) = (len > 0, end) {
+            self.check_memory(end.saturating_sub(self.memory.size()))?;
+        }
+
+        let grown_pages = self.memory.ensure(offset, len)?;
+
+        self.gas_used += grown_pages as u64 * MEMORY_PAGE_GAS;
+
+        Ok(())
+    }
+
+    // Encodes a scalar as its byte representation in the requested order.
+    fn to_bytes(value: StackValue, little_endian: bool) -> Result<Vec<u8>, StackError> {
+        let bytes = match (value, little_endian) {
+            (StackValue::Integer(i), true) => i.to_le_bytes().to_vec(),
+            (StackValue::Integer(i), false) => i.to_be_bytes().to_vec(),
+            (StackValue::Float(f), true) => f.to_le_bytes().to_vec(),
+            (StackValue::Float(f), false) => f.to_be_bytes().to_vec(),
+            (StackValue::Byte(b), _) => vec![b],
+            _ => return Err(StackError::StackInvalidType),
+        };
+
+        Ok(bytes)
+    }
+
+    // Decodes a byte array into the scalar type selected by `target`. The
+    // array length must match the size of the target type exactly.
+    fn from_bytes(value: StackValue, target: u8, little_endian: bool) -> Result<StackValue, StackError> {
+        let bytes = match value {
+            StackValue::ByteArray(bytes) => bytes,
+            _ => return Err(StackError::StackInvalidType),
+        };
+
+        match target {
+            FROMBYTES_INTEGER => {
+                let word: [u8; 4] = bytes.as_slice().try_into().map_err(|_| StackError::InvalidConversion)?;
+
+                if little_endian {
+                    Ok(StackValue::Integer(i32::from_le_bytes(word)))
+                } else {
+                    Ok(StackValue::Integer(i32::from_be_bytes(word)))
+                }
+            },
+            FROMBYTES_FLOAT => {
+                let word: [u8; 4] = bytes.as_slice().try_into().map_err(|_| StackError::InvalidConversion)?;
+
+                if little_endian {
+                    Ok(StackValue::Float(f32::from_le_bytes(word)))
+                } else {
+                    Ok(StackValue::Float(f32::from_be_bytes(word)))
+                }
+            },
+            FROMBYTES_BYTE => match bytes.as_slice() {
+                [b] => Ok(StackValue::Byte(*b)),
+                _ => Err(StackError::InvalidConversion),
+            },
+            _ => Err(StackError::InvalidConversion),
+        }
+    }
+
+    // Pops a number and pushes the result of a float-only operation on it,
+    // promoting an Integer operand to Float like the binary operators do.
+    fn float_unary(&mut self, op: fn(f32) -> f32) -> Result<(), StackError> {
+        let value = match self.stack.pop()? {
+            StackValue::Integer(i) => i as f32,
+            StackValue::Float(f) => f,
+            _ => return Err(StackError::StackInvalidType),
+        };
+
+        self.stack.push(StackValue::Float(op(value)))
+    }
+
+    // Truncated remainder: the result takes the sign of the dividend.
+    fn rem_i32(lhs: i32, rhs: i32) -> Result<i32, StackError> {
+        if rhs == 0 { return Err(StackError::DivisionByZero); }
+
+        Ok(lhs.wrapping_rem(rhs))
+    }
+
+    // Floored modulo: the result takes the sign of the divisor.
+    fn mod_i32(lhs: i32, rhs: i32) -> Result<i32, StackError> {
+        let r = VM::rem_i32(lhs, rhs)?;
+
+        if r != 0 && (r < 0) != (rhs < 0) { Ok(r + rhs) } else { Ok(r) }
+    }
+
+    fn div_f32(&self, lhs: f32, rhs: f32) -> Result<f32, StackError> {
+        if rhs == 0.0 && !self.config.ieee_float_division { return Err(StackError::DivisionByZero); }
+
+        Ok(lhs / rhs)
+    }
+
+    fn rem_f32(&self, lhs: f32, rhs: f32) -> Result<f32, StackError> {
+        if rhs == 0.0 && !self.config.ieee_float_division { return Err(StackError::DivisionByZero); }
+
+        Ok(lhs % rhs)
+    }
+
+    fn mod_f32(&self, lhs: f32, rhs: f32) -> Result<f32, StackError> {
+        let r = self.rem_f32(lhs, rhs)?;
+
+        if r != 0.0 && (r < 0.0) != (rhs < 0.0) { Ok(r + rhs) } else { Ok(r) }
+    }
+
+    // Integer power. A negative exponent yields the truncated result of the
+    // real power, the same way DIV truncates integer quotients.
+    fn pow_i32(base: i32, exp: i32) -> Result<i32, StackError> {
+        if exp >= 0 {
+            return base.checked_pow(exp as u32).ok_or(StackError::IntegerOverflow);
+        }
+
+        match base {
+            0 => Err(StackError::DivisionByZero),
+            1 => Ok(1),
+            -1 => Ok(if exp % 2 == 0 { 1 } else { -1 }),
+            _ => Ok(0),
+        }
+    }
+
+    fn powf(&self, base: f32, exp: f32) -> Result<f32, StackError> {
+        if self.config.deterministic_float {
+            float::deterministic_powf(base, exp)
+        } else {
+            Ok(base.powf(exp))
+        }
+    }
+
+    fn powi(&self, base: f32, exp: i32) -> f32 {
+        if self.config.deterministic_float {
+            float::deterministic_powi(base, exp)
+        } else {
+            base.powi(exp)
+        }
+    }
+
+    // Every float producing instruction leaves its result on top of the
+    // stack, so canonicalizing the top after each step covers them all.
+    fn canonicalize_top(&mut self) {
+        if !self.config.deterministic_float {
+            return;
+        }
+
+        if let Some(top) = self.stack.peek_mut()
+            && let StackValue::Float(f) = *top.get()
+        {
+            *top = Slot::from(StackValue::Float(float::canonicalize(f)));
+        }
+    }
+
+    fn record_operand_types(&mut self) {
+        let top = self.stack.top(MAX_REPORTED_OPERANDS);
+        let missing = MAX_REPORTED_OPERANDS - top.len();
+
+        for (i, slot) in self.operand_types.iter_mut().enumerate() {
+            *slot = i.checked_sub(missing).map(|j| top[j].type_name());
+        }
+    }
+
+    // Compiled once per combination of tracing and profiling, so neither
+    // costs anything when it is off.
+    fn run<const TRACE: bool, const PROFILE: bool>(&mut self) -> Result<Vec<StackValue>, StackError> {
         loop {
-            if self.ip >= self.instructions.len() {
-                return Ok(self.stack.pop()?)
+            if let Some(values) = self.dispatch::<TRACE, PROFILE>()? {
+                return Ok(values);
             }
+        }
+    }
 
-            let opcode = &self.instructions[self.ip];
-            self.ip += 1;
+    // Executes the instruction at ip on the stack backend. Returns the
+    // program's values once it halts or returns.
+    #[inline(always)]
+    fn dispatch<const TRACE: bool, const PROFILE: bool>(&mut self) -> Result<Option<Vec<StackValue>>, StackError> {
+        let ip = self.ip;
+        let mut fast = true;
 
-            match opcode {
-                OpCode::HALT => {
-                    return Ok(self.stack.pop()?);
-                },
-                OpCode::PUSHINT(i) => {
-                    self.stack.push(StackValue::Integer(*i))?;
-                },
-                OpCode::PUSHBYTE(b) => {
-                    self.stack.push(StackValue::Byte(*b))?;
-                },
-                OpCode::PUSHFLOAT(f) => {
-                    self.stack.push(StackValue::Float(*f))?;
-                },
-                OpCode::PACK(u) => {
-                    let n = *u as usize;
-                    let vals = self.stack.pop_n(n, false)?;
-                    let byte_vec_result: Result<Vec<u8>, StackError> = vals.into_iter().map(|v| {
-                        if let StackValue::Byte(b) = v {
-                            Ok(b)
-                        } else {
-                            Err(StackError::StackInvalidType)
-                        }
-                    }).collect();
-
-                    let byte_vec = byte_vec_result?;
-
-                    self.stack.push(StackValue::ByteArray(byte_vec))?;
-                },
-                OpCode::POP => {
-                    self.stack.pop()?;
-                },
-                OpCode::ADD => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    match (lhs, rhs) {
-                        // i32 + i32
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
-                            self.stack.push(StackValue::Integer(lhs_i32 + rhs_i32))?;
-                        },
-                        // f32 + f32
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
-                            self.stack.push(StackValue::Float(lhs_f32 + rhs_f32))?;
-                        },
-                        // i32 + f32 = f32(i32) + f32
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
-                            self.stack.push(StackValue::Float((lhs_i32 as f32) + rhs_f32))?;
-                        },
-                        // f32 + i32 = f32 + f32(i32)
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
-                            self.stack.push(StackValue::Float(lhs_f32 + (rhs_i32 as f32)))?;
-                        }
-                        _ => {
-                            return Err(StackError::StackInvalidType);
-                        }
+        if self.steps >= self.next_check && ip < self.program.instructions.len() {
+            fast = self.check_limits()?;
+        }
+
+        let Instruction { handler, operand } = self.program.code[ip];
+        self.ip = ip + 1;
+        self.steps += 1;
+
+        let started = PROFILE.then(Instant::now);
+
+        let returned = if fast && handler(self, operand).is_some() {
+            if TRACE {
+                println!("{:?}", self.stack);
+            }
+
+            Ok(None)
+        } else {
+            self.slow_step(ip)
+        };
+
+        if let (Some(started), Some(profiler)) = (started, &mut self.profiler)
+            && ip < self.program.instructions.len()
+        {
+            profiler.record(self.program.origin(ip), started.elapsed());
+        }
+
+        returned
+    }
+
+    // Runs before the instruction at ip whenever steps reaches next_check.
+    // Fails on the step limit or a pending interrupt; otherwise returns
+    // whether the instruction may take the fast path, whose handlers assume
+    // memory use is within its quota.
+    fn check_limits(&mut self) -> Result<bool, StackError> {
+        self.current = Some(self.ip);
+        self.record_operand_types();
+
+        // Both checks run before the instruction is counted, so a
+        // suspended instruction is counted once when it resumes.
+        let max_steps = self.config.max_steps;
+
+        if max_steps != 0 && self.steps >= max_steps {
+            return Err(StackError::StepLimitExceeded);
+        }
+
+        if self.steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupt.swap(false, Ordering::Relaxed) {
+            return Err(StackError::Interrupted);
+        }
+
+        // Checked again before every instruction until usage is back
+        // within the quota.
+        if self.check_memory(0).is_err() {
+            self.next_check = self.steps;
+
+            return Ok(false);
+        }
+
+        let next_poll = (self.steps / INTERRUPT_CHECK_INTERVAL + 1) * INTERRUPT_CHECK_INTERVAL;
+
+        self.next_check = if max_steps != 0 { next_poll.min(max_steps) } else { next_poll };
+
+        Ok(true)
+    }
+
+    // Executes the instruction at ip, already counted by the run loop,
+    // through step when its handler declined it.
+    fn slow_step(&mut self, ip: usize) -> Result<Option<Vec<StackValue>>, StackError> {
+        if ip == self.program.instructions.len() {
+            // Reaching the end is not an instruction, so it is not counted.
+            self.steps -= 1;
+            self.current = None;
+
+            return Ok(Some(vec![self.stack.pop()?]));
+        }
+
+        self.ip = ip;
+        self.current = Some(ip);
+        self.record_operand_types();
+
+        self.step()
+    }
+
+    // Executes the instruction at ip. Returns the program's values once it
+    // halts or returns. This is the reference implementation of every
+    // instruction and the only place errors are raised; the handlers in
+    // dispatch are fast paths for its successful cases.
+    fn step(&mut self) -> Result<Option<Vec<StackValue>>, StackError> {
+        let opcode = &self.program.instructions[self.ip];
+        self.ip += 1;
+
+        match opcode {
+            OpCode::HALT => {
+                return Ok(Some(vec![self.stack.pop()?]));
+            },
+            OpCode::RETURN(u) => {
+                // Values are returned in push order, so the top of the
+                // stack is the last element.
+                return self.stack.pop_n(*u as usize, false).map(Some);
+            },
+            OpCode::PUSHINT(i) => {
+                self.stack.push(StackValue::Integer(*i))?;
+            },
+            OpCode::PUSHBYTE(b) => {
+                self.stack.push(StackValue::Byte(*b))?;
+            },
+            OpCode::PUSHFLOAT(f) => {
+                self.stack.push(StackValue::Float(*f))?;
+            },
+            OpCode::PACK(u) => {
+                let n = *u as usize;
+                let vals = self.stack.pop_n(n, false)?;
+                let byte_vec_result: Result<Vec<u8>, StackError> = vals.into_iter().map(|v| {
+                    if let StackValue::Byte(b) = v {
+                        Ok(b)
+                    } else {
+                        Err(StackError::StackInvalidType)
                     }
-                },
-                OpCode::SUB => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    match (lhs, rhs) {
-                        // i32 - i32
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
-                            self.stack.push(StackValue::Integer(lhs_i32 - rhs_i32))?;
-                        },
-                        // f32 - f32
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
-                            self.stack.push(StackValue::Float(lhs_f32 - rhs_f32))?;
-                        },
-                        // i32 - f32 = f32(i32) - f32
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
-                            self.stack.push(StackValue::Float((lhs_i32 as f32) - rhs_f32))?;
-                        },
-                        // f32 - i32 = f32 - f32(i32)
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
-                            self.stack.push(StackValue::Float(lhs_f32 - (rhs_i32 as f32)))?;
-                        }
-                        _ => {
-                            return Err(StackError::StackInvalidType);
-                        }
+                }).collect();
+
+                let byte_vec = byte_vec_result?;
+
+                self.stack.push(StackValue::ByteArray(byte_vec.into()))?;
+            },
+            OpCode::PUSHBYTES(bytes) => {
+                self.stack.push(StackValue::ByteArray(bytes.clone()))?;
+            },
+            OpCode::POP => {
+                self.stack.pop()?;
+            },
+            OpCode::ADD => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                match (lhs, rhs) {
+                    // i32 + i32
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
+                        let result = lhs_i32.checked_add(rhs_i32).ok_or(StackError::IntegerOverflow)?;
+
+                        self.stack.push(StackValue::Integer(result))?;
+                    },
+                    // f32 + f32
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(lhs_f32 + rhs_f32))?;
+                    },
+                    // i32 + f32 = f32(i32) + f32
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float((lhs_i32 as f32) + rhs_f32))?;
+                    },
+                    // f32 + i32 = f32 + f32(i32)
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Float(lhs_f32 + (rhs_i32 as f32)))?;
                     }
-                },
-                OpCode::MUL => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    match (lhs, rhs) {
-                        // i32 * i32
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
-                            self.stack.push(StackValue::Integer(lhs_i32 * rhs_i32))?;
-                        },
-                        // f32 * f32
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
-                            self.stack.push(StackValue::Float(lhs_f32 * rhs_f32))?;
-                        },
-                        // i32 * f32 = f32(i32) * f32
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
-                            self.stack.push(StackValue::Float((lhs_i32 as f32) * rhs_f32))?;
-                        },
-                        // f32 * i32 = f32 * f32(i32)
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
-                            self.stack.push(StackValue::Float(lhs_f32 * (rhs_i32 as f32)))?;
-                        },
-                        _ => {
-                            return Err(StackError::StackInvalidType);
-                        }
+                    _ => {
+                        return Err(StackError::StackInvalidType);
                     }
-                },
-                OpCode::DIV => {
-                    let rhs = self.stack.pop()?; // dividend
-                    let lhs = self.stack.pop()?; // divisor
-
-                    match (lhs, rhs) {
-                        // i32 / i32
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
-                            if rhs_i32 == 0 { return Err(StackError::DivisionByZero); }
-
-                            self.stack.push(StackValue::Integer(lhs_i32 / rhs_i32))?;
-                        },
-                        // f32 / f32
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
-                            if rhs_f32 == 0.0 { return Err(StackError::DivisionByZero); }
-
-                            self.stack.push(StackValue::Float(lhs_f32 / rhs_f32))?;
-                        },
-                        // i32 / f32 = f32(i32) / i32
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
-                            if rhs_f32 == 0.0 { return Err(StackError::DivisionByZero); }
-
-                            self.stack.push(StackValue::Float((lhs_i32 as f32) / rhs_f32))?;
-                        },
-                        // f32 / i32 = f32 / f32(i32)
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
-                            if rhs_i32 == 0 { return Err(StackError::DivisionByZero); }
-
-                            self.stack.push(StackValue::Float(lhs_f32 / (rhs_i32 as f32)))?;
-                        },
-                        _ => {
-                            return Err(StackError::StackInvalidType);
-                        }
+                }
+            },
+            OpCode::SUB => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                match (lhs, rhs) {
+                    // i32 - i32
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
+                        let result = lhs_i32.checked_sub(rhs_i32).ok_or(StackError::IntegerOverflow)?;
+
+                        self.stack.push(StackValue::Integer(result))?;
+                    },
+                    // f32 - f32
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(lhs_f32 - rhs_f32))?;
+                    },
+                    // i32 - f32 = f32(i32) - f32
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float((lhs_i32 as f32) - rhs_f32))?;
+                    },
+                    // f32 - i32 = f32 - f32(i32)
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Float(lhs_f32 - (rhs_i32 as f32)))?;
+                    }
+                    _ => {
+                        return Err(StackError::StackInvalidType);
                     }
                 }
-                OpCode::EQ => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    let result = match (lhs, rhs) {
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 == rhs_i32,
-                        (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 == rhs_u8,
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 == rhs_f32,
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) == rhs_f32,
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 == (rhs_i32 as f32),
-                        _ => return Err(StackError::StackInvalidType),
-                    };
-
-                    self.stack.push(StackValue::Bool(result))?;
-                },
-                OpCode::LT => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    let result = match (lhs, rhs) {
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 < rhs_i32,
-                        (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 < rhs_u8,
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 < rhs_f32,
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) < rhs_f32,
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 < (rhs_i32 as f32),
-                        _ => return Err(StackError::StackInvalidType),
-                    };
-
-                    self.stack.push(StackValue::Bool(result))?;
-                },
-                OpCode::LTE => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    let result = match (lhs, rhs) {
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 <= rhs_i32,
-                        (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 <= rhs_u8,
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 <= rhs_f32,
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) <= rhs_f32,
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 <= (rhs_i32 as f32),
-                        _ => return Err(StackError::StackInvalidType),
-                    };
-
-                    self.stack.push(StackValue::Bool(result))?;
-                },
-                OpCode::GT => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    let result = match (lhs, rhs) {
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 > rhs_i32,
-                        (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 > rhs_u8,
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 > rhs_f32,
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) > rhs_f32,
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 > (rhs_i32 as f32),
-                        _ => return Err(StackError::StackInvalidType),
-                    };
-
-                    self.stack.push(StackValue::Bool(result))?;
-                },
-                OpCode::GTE => {
-                    let rhs = self.stack.pop()?;
-                    let lhs = self.stack.pop()?;
-
-                    let result = match (lhs, rhs) {
-                        (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 >= rhs_i32,
-                        (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 >= rhs_u8,
-                        (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 >= rhs_f32,
-                        (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) >= rhs_f32,
-                        (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 >= (rhs_i32 as f32),
-                        _ => return Err(StackError::StackInvalidType),
-                    };
-
-                    self.stack.push(StackValue::Bool(result))?;
-                },
-                OpCode::CONCAT => {
-                    let v1 = self.stack.pop()?;
-                    let v2 = self.stack.pop()?;
+            },
+            OpCode::MUL => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
 
-                    if let (StackValue::ByteArray(bytes1), StackValue::ByteArray(mut bytes2)) = (v1, v2) {
-                        bytes2.extend_from_slice(&bytes1);
+                match (lhs, rhs) {
+                    // i32 * i32
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
+                        let result = lhs_i32.checked_mul(rhs_i32).ok_or(StackError::IntegerOverflow)?;
 
-                        self.stack.push(StackValue::ByteArray(bytes2))?;
-                    } else {
+                        self.stack.push(StackValue::Integer(result))?;
+                    },
+                    // f32 * f32
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(lhs_f32 * rhs_f32))?;
+                    },
+                    // i32 * f32 = f32(i32) * f32
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float((lhs_i32 as f32) * rhs_f32))?;
+                    },
+                    // f32 * i32 = f32 * f32(i32)
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Float(lhs_f32 * (rhs_i32 as f32)))?;
+                    },
+                    _ => {
+                        return Err(StackError::StackInvalidType);
+                    }
+                }
+            },
+            OpCode::DIV => {
+                let rhs = self.stack.pop()?; // dividend
+                let lhs = self.stack.pop()?; // divisor
+
+                match (lhs, rhs) {
+                    // i32 / i32
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
+                        if rhs_i32 == 0 { return Err(StackError::DivisionByZero); }
+
+                        let result = lhs_i32.checked_div(rhs_i32).ok_or(StackError::IntegerOverflow)?;
+
+                        self.stack.push(StackValue::Integer(result))?;
+                    },
+                    // f32 / f32
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(self.div_f32(lhs_f32, rhs_f32)?))?;
+                    },
+                    // i32 / f32 = f32(i32) / i32
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(self.div_f32(lhs_i32 as f32, rhs_f32)?))?;
+                    },
+                    // f32 / i32 = f32 / f32(i32)
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Float(self.div_f32(lhs_f32, rhs_i32 as f32)?))?;
+                    },
+                    _ => {
                         return Err(StackError::StackInvalidType);
                     }
                 }
             }
+            OpCode::EQ => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
 
-            println!("{:?}", self.stack);
-        }
-    }
-}
\ No newline at end of file
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 == rhs_i32,
+                    (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 == rhs_u8,
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 == rhs_f32,
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) == rhs_f32,
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 == (rhs_i32 as f32),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(StackValue::Bool(result))?;
+            },
+            OpCode::LT => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 < rhs_i32,
+                    (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 < rhs_u8,
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 < rhs_f32,
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) < rhs_f32,
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 < (rhs_i32 as f32),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(StackValue::Bool(result))?;
+            },
+            OpCode::LTE => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 <= rhs_i32,
+                    (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 <= rhs_u8,
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 <= rhs_f32,
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) <= rhs_f32,
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 <= (rhs_i32 as f32),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(StackValue::Bool(result))?;
+            },
+            OpCode::GT => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 > rhs_i32,
+                    (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 > rhs_u8,
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 > rhs_f32,
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) > rhs_f32,
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 > (rhs_i32 as f32),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(StackValue::Bool(result))?;
+            },
+            OpCode::GTE => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => lhs_i32 >= rhs_i32,
+                    (StackValue::Byte(lhs_u8), StackValue::Byte(rhs_u8)) => lhs_u8 >= rhs_u8,
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => lhs_f32 >= rhs_f32,
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => (lhs_i32 as f32) >= rhs_f32,
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => lhs_f32 >= (rhs_i32 as f32),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(StackValue::Bool(result))?;
+            },
+            OpCode::CONCAT => {
+                let v1 = self.stack.pop()?;
+                let v2 = self.stack.pop()?;
+
+                if let (StackValue::ByteArray(bytes1), StackValue::ByteArray(mut bytes2)) = (v1, v2) {
+                    self.check_memory(bytes1.len() + bytes2.len())?;
+
+                    bytes2.extend_from_slice(&bytes1);
+
+                    self.stack.push(StackValue::ByteArray(bytes2))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            }
+            OpCode::MLOAD => {
+                let offset = self.pop_address()?;
+
+                self.touch_memory(offset, 4)?;
+
+                let mut word = [0u8; 4];
+                word.copy_from_slice(self.memory.read(offset, 4));
+
+                self.stack.push(StackValue::Integer(i32::from_le_bytes(word)))?;
+            },
+            OpCode::MSTORE => {
+                let value = self.stack.pop()?;
+                let offset = self.pop_address()?;
+
+                if let StackValue::Integer(i) = value {
+                    self.touch_memory(offset, 4)?;
+                    self.memory.write(offset, &i.to_le_bytes());
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::MSTORE8 => {
+                let value = self.stack.pop()?;
+                let offset = self.pop_address()?;
+
+                if let StackValue::Byte(b) = value {
+                    self.touch_memory(offset, 1)?;
+                    self.memory.write(offset, &[b]);
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::MCOPY => {
+                let len = self.pop_address()?;
+                let src = self.pop_address()?;
+                let dst = self.pop_address()?;
+
+                self.touch_memory(src, len)?;
+                self.touch_memory(dst, len)?;
+                self.memory.copy_within(dst, src, len);
+            },
+            OpCode::MSIZE => {
+                let size = i32::try_from(self.memory.size()).map_err(|_| StackError::MemoryOutOfBounds)?;
+
+                self.stack.push(StackValue::Integer(size))?;
+            },
+            OpCode::MLOADBYTES => {
+                let len = self.pop_address()?;
+                let offset = self.pop_address()?;
+
+                self.touch_memory(offset, len)?;
+
+                let bytes = self.memory.read(offset, len).into();
+
+                self.stack.push(StackValue::ByteArray(bytes))?;
+            },
+            OpCode::MSTOREBYTES => {
+                let value = self.stack.pop()?;
+                let offset = self.pop_address()?;
+
+                if let StackValue::ByteArray(bytes) = value {
+                    self.touch_memory(offset, bytes.len())?;
+                    self.memory.write(offset, &bytes);
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::I2F => {
+                if let StackValue::Integer(i) = self.stack.pop()? {
+                    self.stack.push(StackValue::Float(i as f32))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::F2I => {
+                // Truncates toward zero. NaN and values outside the i32
+                // range have no integer representation.
+                if let StackValue::Float(f) = self.stack.pop()? {
+                    if !(-2_147_483_648.0..2_147_483_648.0).contains(&f) {
+                        return Err(StackError::InvalidConversion);
+                    }
+
+                    self.stack.push(StackValue::Integer(f as i32))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::I2B => {
+                if let StackValue::Integer(i) = self.stack.pop()? {
+                    let b = u8::try_from(i).map_err(|_| StackError::InvalidConversion)?;
+
+                    self.stack.push(StackValue::Byte(b))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::B2I => {
+                if let StackValue::Byte(b) = self.stack.pop()? {
+                    self.stack.push(StackValue::Integer(b as i32))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::BOOL2I => {
+                if let StackValue::Bool(b) = self.stack.pop()? {
+                    self.stack.push(StackValue::Integer(b as i32))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+            OpCode::TOBYTESLE => {
+                let value = self.stack.pop()?;
+
+                self.stack.push(StackValue::ByteArray(VM::to_bytes(value, true)?.into()))?;
+            },
+            OpCode::TOBYTESBE => {
+                let value = self.stack.pop()?;
+
+                self.stack.push(StackValue::ByteArray(VM::to_bytes(value, false)?.into()))?;
+            },
+            OpCode::FROMBYTESLE(t) => {
+                let value = self.stack.pop()?;
+
+                self.stack.push(VM::from_bytes(value, *t, true)?)?;
+            },
+            OpCode::FROMBYTESBE(t) => {
+                let value = self.stack.pop()?;
+
+                self.stack.push(VM::from_bytes(value, *t, false)?)?;
+            },
+            OpCode::MOD => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Integer(VM::mod_i32(lhs_i32, rhs_i32)?))?;
+                    },
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(self.mod_f32(lhs_f32, rhs_f32)?))?;
+                    },
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(self.mod_f32(lhs_i32 as f32, rhs_f32)?))?;
+                    },
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Float(self.mod_f32(lhs_f32, rhs_i32 as f32)?))?;
+                    },
+                    _ => {
+                        return Err(StackError::StackInvalidType);
+                    }
+                }
+            },
+            OpCode::REM => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Integer(VM::rem_i32(lhs_i32, rhs_i32)?))?;
+                    },
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(self.rem_f32(lhs_f32, rhs_f32)?))?;
+                    },
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => {
+                        self.stack.push(StackValue::Float(self.rem_f32(lhs_i32 as f32, rhs_f32)?))?;
+                    },
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => {
+                        self.stack.push(StackValue::Float(self.rem_f32(lhs_f32, rhs_i32 as f32)?))?;
+                    },
+                    _ => {
+                        return Err(StackError::StackInvalidType);
+                    }
+                }
+            },
+            OpCode::NEG => {
+                match self.stack.pop()? {
+                    StackValue::Integer(i) => {
+                        let neg = i.checked_neg().ok_or(StackError::IntegerOverflow)?;
+
+                        self.stack.push(StackValue::Integer(neg))?;
+                    },
+                    StackValue::Float(f) => {
+                        self.stack.push(StackValue::Float(-f))?;
+                    },
+                    _ => {
+                        return Err(StackError::StackInvalidType);
+                    }
+                }
+            },
+            OpCode::ABS => {
+                match self.stack.pop()? {
+                    StackValue::Integer(i) => {
+                        let abs = i.checked_abs().ok_or(StackError::IntegerOverflow)?;
+
+                        self.stack.push(StackValue::Integer(abs))?;
+                    },
+                    StackValue::Float(f) => {
+                        self.stack.push(StackValue::Float(f.abs()))?;
+                    },
+                    _ => {
+                        return Err(StackError::StackInvalidType);
+                    }
+                }
+            },
+            OpCode::MIN => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => StackValue::Integer(lhs_i32.min(rhs_i32)),
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => StackValue::Float(float::min(lhs_f32, rhs_f32)),
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => StackValue::Float(float::min(lhs_i32 as f32, rhs_f32)),
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => StackValue::Float(float::min(lhs_f32, rhs_i32 as f32)),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(result)?;
+            },
+            OpCode::MAX => {
+                let rhs = self.stack.pop()?;
+                let lhs = self.stack.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => StackValue::Integer(lhs_i32.max(rhs_i32)),
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => StackValue::Float(float::max(lhs_f32, rhs_f32)),
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => StackValue::Float(float::max(lhs_i32 as f32, rhs_f32)),
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => StackValue::Float(float::max(lhs_f32, rhs_i32 as f32)),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(result)?;
+            },
+            OpCode::POW => {
+                let rhs = self.stack.pop()?; // exponent
+                let lhs = self.stack.pop()?; // base
+
+                let result = match (lhs, rhs) {
+                    (StackValue::Integer(lhs_i32), StackValue::Integer(rhs_i32)) => StackValue::Integer(VM::pow_i32(lhs_i32, rhs_i32)?),
+                    (StackValue::Float(lhs_f32), StackValue::Float(rhs_f32)) => StackValue::Float(self.powf(lhs_f32, rhs_f32)?),
+                    (StackValue::Integer(lhs_i32), StackValue::Float(rhs_f32)) => StackValue::Float(self.powf(lhs_i32 as f32, rhs_f32)?),
+                    (StackValue::Float(lhs_f32), StackValue::Integer(rhs_i32)) => StackValue::Float(self.powi(lhs_f32, rhs_i32)),
+                    _ => return Err(StackError::StackInvalidType),
+                };
+
+                self.stack.push(result)?;
+            },
+            OpCode::SQRT => {
+                self.float_unary(f32::sqrt)?;
+            },
+            OpCode::FLOOR => {
+                self.float_unary(f32::floor)?;
+            },
+            OpCode::CEIL => {
+                self.float_unary(f32::ceil)?;
+            },
+            OpCode::ROUND => {
+                // Rounds half away from zero.
+                self.float_unary(f32::round)?;
+            },
+            OpCode::TRUNC => {
+                self.float_unary(f32::trunc)?;
+            },
+            OpCode::SLICE => {
+                let len = self.pop_address()?;
+                let offset = self.pop_address()?;
+
+                if let StackValue::ByteArray(bytes) = self.stack.pop()? {
+                    let end = offset.checked_add(len)
+                        .filter(|end| *end <= bytes.len())
+                        .ok_or(StackError::MemoryOutOfBounds)?;
+
+                    // Shares the array's buffer unless the slice is small.
+                    self.stack.push(StackValue::ByteArray(bytes.slice(offset..end)))?;
+                } else {
+                    return Err(StackError::StackInvalidType);
+                }
+            },
+        }
+
+        self.canonicalize_top();
+        self.track_memory()?;
+
+        if self.trace {
+            println!("{:?}", self.stack);
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stack::bytes::Bytes;
+    use crate::stack::composite_stack::StackValue;
+    use crate::stack::stack::{Stack, StackError};
+    use crate::vm::config::{VMConfig, BACKEND_REGISTER, BACKEND_STACK};
+    use crate::vm::memory::{DEFAULT_MAX_PAGES, PAGE_SIZE};
+    use crate::vm::op::{OpCode, FROMBYTES_FLOAT, FROMBYTES_INTEGER};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use crate::vm::receipt::ExecutionReceipt;
+    use crate::vm::snapshot::{SnapshotError, VMSnapshot};
+    use crate::vm::testing::Lcg;
+    use crate::vm::vm::{
+        Program, VirtualMachine, VMState, VM, INTERRUPT_CHECK_INTERVAL, MAX_STACK_SIZE, MEMORY_PAGE_GAS,
+    };
+
+    #[test]
+    fn test_vm_halt_returns_top() {
+        let mut vm = VM::new(4, vec![
+            OpCode::PUSHINT(1),
+            OpCode::PUSHINT(2),
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(2)]));
+    }
+
+    #[test]
+    fn test_vm_return_multiple_values() {
+        let mut vm = VM::new(4, vec![
+            OpCode::PUSHINT(0),
+            OpCode::PUSHBYTE(0xAB),
+            OpCode::PACK(1),
+            OpCode::PUSHFLOAT(1.5),
+            OpCode::RETURN(3),
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![
+            StackValue::Integer(0),
+            StackValue::ByteArray(vec![0xAB].into()),
+            StackValue::Float(1.5),
+        ]));
+
+        let mut vm_failure = VM::new(4, vec![
+            OpCode::PUSHINT(0),
+            OpCode::RETURN(2),
+        ]);
+
+        assert_eq!(vm_failure.execute(), Err(StackError::StackUnderFlow));
+    }
+
+    #[test]
+    fn test_vm_reset_and_rerun() {
+        let mut vm = VM::new(4, vec![
+            OpCode::PUSHINT(2),
+            OpCode::MUL,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm.load_inputs(vec![StackValue::Integer(3)]), Ok(()));
+        assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(6)]));
+        assert_eq!(vm.execute(), Err(StackError::InvalidState));
+
+        assert_eq!(vm.load_inputs(vec![StackValue::Integer(5)]), Ok(()));
+        assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(10)]));
+
+        vm.reset();
+        assert_eq!(vm.execute(), Err(StackError::StackUnderFlow));
+        assert_eq!(vm.state(), VMState::Faulted);
+    }
+
+    #[test]
+    fn test_vm_linear_memory() {
+        let mut vm = VM::new(8, vec![
+            OpCode::PUSHINT(8),
+            OpCode::PUSHINT(0x0403_0201),
+            OpCode::MSTORE,
+            OpCode::PUSHINT(12),
+            OpCode::PUSHBYTE(0x05),
+            OpCode::MSTORE8,
+            OpCode::PUSHINT(0),
+            OpCode::PUSHINT(8),
+            OpCode::PUSHINT(5),
+            OpCode::MCOPY,
+            OpCode::PUSHINT(0),
+            OpCode::PUSHINT(5),
+            OpCode::MLOADBYTES,
+            OpCode::PUSHINT(1),
+            OpCode::MLOAD,
+            OpCode::MSIZE,
+            OpCode::RETURN(3),
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![
+            StackValue::ByteArray(vec![0x01, 0x02, 0x03, 0x04, 0x05].into()),
+            StackValue::Integer(0x0504_0302),
+            StackValue::Integer(PAGE_SIZE as i32),
+        ]));
+        assert_eq!(vm.gas_used(), MEMORY_PAGE_GAS);
+    }
+
+    #[test]
+    fn test_vm_linear_memory_out_of_bounds() {
+        let mut vm = VM::new(4, vec![
+            OpCode::PUSHINT(-1),
+            OpCode::MLOAD,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm.execute(), Err(StackError::MemoryOutOfBounds));
+
+        let mut vm_limit = VM::new(4, vec![
+            OpCode::PUSHINT((PAGE_SIZE * DEFAULT_MAX_PAGES) as i32),
+            OpCode::PUSHBYTE(0x01),
+            OpCode::MSTORE8,
+            OpCode::MSIZE,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm_limit.execute(), Err(StackError::MemoryOutOfBounds));
+    }
+
+    #[test]
+    fn test_vm_byte_array_slices() {
+        let mut vm = VM::new(4, vec![
+            OpCode::PUSHBYTE(1), OpCode::PUSHBYTE(2), OpCode::PUSHBYTE(3), OpCode::PUSHBYTE(4), OpCode::PACK(4),
+            OpCode::PUSHINT(1),
+            OpCode::PUSHINT(2),
+            OpCode::SLICE,
+            OpCode::PUSHBYTE(9), OpCode::PACK(1),
+            OpCode::CONCAT,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![StackValue::ByteArray(vec![2, 3, 9].into())]));
+
+        for (offset, len) in [(3, 2), (5, 0), (-1, 1), (0, i32::MAX)] {
+            let mut vm = VM::new(4, vec![
+                OpCode::PUSHBYTE(1), OpCode::PUSHBYTE(2), OpCode::PUSHBYTE(3), OpCode::PUSHBYTE(4), OpCode::PACK(4),
+                OpCode::PUSHINT(offset),
+                OpCode::PUSHINT(len),
+                OpCode::SLICE,
+                OpCode::HALT,
+            ]);
+
+            assert_eq!(vm.execute(), Err(StackError::MemoryOutOfBounds));
+        }
+
+        // Slices share the array's buffer, and a CONCAT onto a shared
+        // array leaves the other views unchanged.
+        let mut vm = VM::new(4, vec![OpCode::SLICE, OpCode::CONCAT, OpCode::HALT]);
+        vm.set_trace(false);
+
+        let array = Bytes::from(vec![1, 2, 3, 4]);
+        vm.load_inputs(vec![
+            StackValue::ByteArray(array.clone()),
+            StackValue::ByteArray(array.clone()),
+            StackValue::Integer(2),
+            StackValue::Integer(2),
+        ]).unwrap();
+
+        vm.step().unwrap();
+        let [lhs, rhs] = vm.stack.values() else {
+            panic!("SLICE left {:?}", vm.stack.values());
+        };
+        assert_eq!(rhs.bytes().unwrap().as_ptr(), array[2..].as_ptr());
+        assert_eq!(lhs.bytes().unwrap().as_ptr(), array.as_ptr());
+
+        vm.step().unwrap();
+        assert_eq!(vm.stack.pop(), Ok(StackValue::ByteArray(vec![1, 2, 3, 4, 3, 4].into())));
+        assert_eq!(array.as_slice(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_vm_numeric_conversions() {
+        let mut vm = VM::new(8, vec![
+            OpCode::PUSHINT(7),
+            OpCode::I2F,
+            OpCode::PUSHFLOAT(-2.9),
+            OpCode::F2I,
+            OpCode::PUSHINT(255),
+            OpCode::I2B,
+            OpCode::PUSHBYTE(0x10),
+            OpCode::B2I,
+            OpCode::PUSHINT(1),
+            OpCode::PUSHINT(2),
+            OpCode::LT,
+            OpCode::BOOL2I,
+            OpCode::RETURN(5),
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![
+            StackValue::Float(7.0),
+            StackValue::Integer(-2),
+            StackValue::Byte(0xFF),
+            StackValue::Integer(16),
+            StackValue::Integer(1),
+        ]));
+
+        for program in [
+            vec![OpCode::PUSHFLOAT(f32::NAN), OpCode::F2I, OpCode::HALT],
+            vec![OpCode::PUSHFLOAT(3.0e9), OpCode::F2I, OpCode::HALT],
+            vec![OpCode::PUSHINT(256), OpCode::I2B, OpCode::HALT],
+        ] {
+            let mut vm_failure = VM::new(4, program);
+
+            assert_eq!(vm_failure.execute(), Err(StackError::InvalidConversion));
+        }
+    }
+
+    #[test]
+    fn test_vm_byte_conversions() {
+        let mut vm = VM::new(8, vec![
+            OpCode::PUSHINT(0x0102_0304),
+            OpCode::TOBYTESBE,
+            OpCode::PUSHFLOAT(1.0),
+            OpCode::TOBYTESLE,
+            OpCode::PUSHINT(0x0102_0304),
+            OpCode::TOBYTESLE,
+            OpCode::FROMBYTESLE(FROMBYTES_INTEGER),
+            OpCode::PUSHFLOAT(-0.5),
+            OpCode::TOBYTESBE,
+            OpCode::FROMBYTESBE(FROMBYTES_FLOAT),
+            OpCode::RETURN(4),
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![
+            StackValue::ByteArray(vec![0x01, 0x02, 0x03, 0x04].into()),
+            StackValue::ByteArray(vec![0x00, 0x00, 0x80, 0x3F].into()),
+            StackValue::Integer(0x0102_0304),
+            StackValue::Float(-0.5),
+        ]));
+
+        let mut vm_failure = VM::new(4, vec![
+            OpCode::PUSHBYTE(0x01),
+            OpCode::PACK(1),
+            OpCode::FROMBYTESLE(FROMBYTES_INTEGER),
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm_failure.execute(), Err(StackError::InvalidConversion));
+    }
+
+    #[test]
+    fn test_vm_mod_rem_sign_semantics() {
+        let cases = [
+            (7, 3, 1, 1),
+            (-7, 3, 2, -1),
+            (7, -3, -2, 1),
+            (-7, -3, -1, -1),
+            (i32::MIN, -1, 0, 0),
+        ];
+
+        for (lhs, rhs, expected_mod, expected_rem) in cases {
+            let mut vm = VM::new(8, vec![
+                OpCode::PUSHINT(lhs),
+                OpCode::PUSHINT(rhs),
+                OpCode::MOD,
+                OpCode::PUSHINT(lhs),
+                OpCode::PUSHINT(rhs),
+                OpCode::REM,
+                OpCode::RETURN(2),
+            ]);
+
+            assert_eq!(vm.execute(), Ok(vec![
+                StackValue::Integer(expected_mod),
+                StackValue::Integer(expected_rem),
+            ]));
+        }
+
+        let mut vm_float = VM::new(4, vec![
+            OpCode::PUSHFLOAT(-7.5),
+            OpCode::PUSHINT(2),
+            OpCode::MOD,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm_float.execute(), Ok(vec![StackValue::Float(0.5)]));
+
+        let mut vm_failure = VM::new(4, vec![
+            OpCode::PUSHINT(1),
+            OpCode::PUSHINT(0),
+            OpCode::MOD,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm_failure.execute(), Err(StackError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_vm_extended_math() {
+        let mut vm = VM::new(16, vec![
+            OpCode::PUSHINT(5),
+            OpCode::NEG,
+            OpCode::PUSHFLOAT(-1.5),
+            OpCode::ABS,
+            OpCode::PUSHINT(3),
+            OpCode::PUSHFLOAT(2.5),
+            OpCode::MIN,
+            OpCode::PUSHINT(3),
+            OpCode::PUSHINT(9),
+            OpCode::MAX,
+            OpCode::PUSHINT(3),
+            OpCode::PUSHINT(4),
+            OpCode::POW,
+            OpCode::PUSHINT(16),
+            OpCode::SQRT,
+            OpCode::PUSHFLOAT(-1.5),
+            OpCode::FLOOR,
+            OpCode::PUSHFLOAT(1.2),
+            OpCode::CEIL,
+            OpCode::PUSHFLOAT(-2.5),
+            OpCode::ROUND,
+            OpCode::PUSHFLOAT(-2.7),
+            OpCode::TRUNC,
+            OpCode::RETURN(10),
+        ]);
+
+        assert_eq!(vm.execute(), Ok(vec![
+            StackValue::Integer(-5),
+            StackValue::Float(1.5),
+            StackValue::Float(2.5),
+            StackValue::Integer(9),
+            StackValue::Integer(81),
+            StackValue::Float(4.0),
+            StackValue::Float(-2.0),
+            StackValue::Float(2.0),
+            StackValue::Float(-3.0),
+            StackValue::Float(-2.0),
+        ]));
+
+        for program in [
+            vec![OpCode::PUSHINT(2), OpCode::PUSHINT(31), OpCode::POW, OpCode::HALT],
+            vec![OpCode::PUSHINT(i32::MIN), OpCode::NEG, OpCode::HALT],
+            vec![OpCode::PUSHINT(i32::MIN), OpCode::ABS, OpCode::HALT],
+        ] {
+            let mut vm_failure = VM::new(4, program);
+
+            assert_eq!(vm_failure.execute(), Err(StackError::IntegerOverflow));
+        }
+
+        // Zeros of opposite sign are equal: MIN gives -0.0 and MAX +0.0 on
+        // every path, whichever operand comes first.
+        for (lhs, rhs) in [
+            (OpCode::PUSHFLOAT(-0.0), OpCode::PUSHFLOAT(0.0)),
+            (OpCode::PUSHFLOAT(0.0), OpCode::PUSHFLOAT(-0.0)),
+            (OpCode::PUSHFLOAT(-0.0), OpCode::PUSHINT(0)),
+            (OpCode::PUSHINT(0), OpCode::PUSHFLOAT(-0.0)),
+        ] {
+            let program = vec![lhs.clone(), rhs.clone(), OpCode::MIN, lhs, rhs, OpCode::MAX, OpCode::RETURN(2)];
+
+            for backend in [BACKEND_STACK, BACKEND_REGISTER] {
+                let config = VMConfig { backend, ..VMConfig::default() };
+
+                let mut vm = VM::with_config(4, program.clone(), config);
+                vm.set_trace(false);
+                assert_eq!(format!("{:?}", vm.execute()), "Ok([Float(-0.0), Float(0.0)])", "{:?}", program);
+
+                let mut reference = VM::with_config(4, program.clone(), config);
+                reference.set_trace(false);
+                assert_eq!(format!("{:?}", execute_reference(&mut reference)), "Ok([Float(-0.0), Float(0.0)])");
+            }
+        }
+    }
+
+    #[test]
+    fn test_vm_float_division_by_zero_modes() {
+        let program = || vec![
+            OpCode::PUSHFLOAT(1.0),
+            OpCode::PUSHINT(0),
+            OpCode::DIV,
+            OpCode::PUSHFLOAT(-1.0),
+            OpCode::PUSHFLOAT(0.0),
+            OpCode::DIV,
+            OpCode::PUSHFLOAT(0.0),
+            OpCode::PUSHFLOAT(0.0),
+            OpCode::DIV,
+            OpCode::RETURN(3),
+        ];
+
+        let mut vm = VM::new(8, program());
+        assert_eq!(vm.execute(), Err(StackError::DivisionByZero));
+
+        let mut vm_ieee = VM::with_config(8, program(), VMConfig {
+            ieee_float_division: true,
+            ..VMConfig::default()
+        });
+        let values = vm_ieee.execute().unwrap();
+
+        assert_eq!(values[0], StackValue::Float(f32::INFINITY));
+        assert_eq!(values[1], StackValue::Float(f32::NEG_INFINITY));
+        assert!(matches!(values[2], StackValue::Float(f) if f.is_nan()));
+
+        let mut vm_int = VM::with_config(4, vec![
+            OpCode::PUSHINT(1),
+            OpCode::PUSHINT(0),
+            OpCode::DIV,
+            OpCode::HALT,
+        ], VMConfig {
+            ieee_float_division: true,
+            ..VMConfig::default()
+        });
+        assert_eq!(vm_int.execute(), Err(StackError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_vm_deterministic_float_vectors() {
+        let config = VMConfig {
+            deterministic_float: true,
+            ieee_float_division: true,
+            ..VMConfig::default()
+        };
+
+        // (program, expected bits of the returned float)
+        let vectors: Vec<(Vec<OpCode>, u32)> = vec![
+            (vec![OpCode::PUSHFLOAT(f32::INFINITY), OpCode::PUSHFLOAT(f32::INFINITY), OpCode::SUB], 0x7FC0_0000),
+            (vec![OpCode::PUSHFLOAT(0.0), OpCode::PUSHFLOAT(f32::INFINITY), OpCode::MUL], 0x7FC0_0000),
+            (vec![OpCode::PUSHFLOAT(f32::from_bits(0xFFC0_1234))], 0x7FC0_0000),
+            (vec![OpCode::PUSHINT(-4), OpCode::SQRT], 0x7FC0_0000),
+            (vec![OpCode::PUSHINT(16_777_217), OpCode::PUSHFLOAT(0.0), OpCode::ADD], 0x4B80_0000),
+            (vec![OpCode::PUSHFLOAT(0.1), OpCode::PUSHINT(3), OpCode::MUL], 0x3E99_999A),
+            (vec![OpCode::PUSHFLOAT(1.1), OpCode::PUSHINT(2), OpCode::POW], 0x3F9A_E148),
+            (vec![OpCode::PUSHFLOAT(2.0), OpCode::PUSHFLOAT(-2.0), OpCode::POW], 0x3E80_0000),
+        ];
+
+        for (mut program, bits) in vectors {
+            program.push(OpCode::HALT);
+
+            let mut vm = VM::with_config(4, program, config);
+
+            match vm.execute() {
+                Ok(values) => match values.as_slice() {
+                    [StackValue::Float(f)] => assert_eq!(f.to_bits(), bits),
+                    other => panic!("unexpected result: {:?}", other),
+                },
+                Err(e) => panic!("unexpected error: {:?}", e),
+            }
+        }
+
+        let mut vm_failure = VM::with_config(4, vec![
+            OpCode::PUSHFLOAT(2.0),
+            OpCode::PUSHFLOAT(0.5),
+            OpCode::POW,
+            OpCode::HALT,
+        ], config);
+        assert_eq!(vm_failure.execute(), Err(StackError::NonDeterministicOperation));
+    }
+
+    #[test]
+    fn test_vm_error_report() {
+        let mut vm = VM::new(8, vec![
+            OpCode::PUSHINT(7),
+            OpCode::PUSHINT(1),
+            OpCode::PUSHBYTE(0x01),
+            OpCode::PACK(1),
+            OpCode::ADD,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(vm.execute(), Err(StackError::StackInvalidType));
+
+        let report = vm.last_error().unwrap();
+
+        assert_eq!(report.ip, Some(4));
+        assert_eq!(report.opcode, Some(OpCode::ADD));
+        assert_eq!(report.operand_types, vec!["Integer", "ByteArray"]);
+        assert_eq!(report.stack, vec![StackValue::Integer(7)]);
+        assert_eq!(
+            report.message(),
+            "invalid type for operation at instruction 4 (ADD) with operands [Integer, ByteArray]"
+        );
+
+        assert_eq!(vm.execute(), Err(StackError::InvalidState));
+        assert_eq!(vm.last_error().unwrap().message(), "vm must be reset before running again");
+
+        vm.reset();
+        assert_eq!(vm.last_error(), None);
+
+        let mut empty = VM::new(4, Vec::new());
+        assert_eq!(empty.execute(), Err(StackError::StackUnderFlow));
+        assert_eq!(empty.last_error().unwrap().message(), "stack underflow at end of program");
+    }
+
+    #[test]
+    fn test_vm_integer_overflow() {
+        for program in [
+            vec![OpCode::PUSHINT(i32::MAX), OpCode::PUSHINT(1), OpCode::ADD, OpCode::HALT],
+            vec![OpCode::PUSHINT(i32::MIN), OpCode::PUSHINT(1), OpCode::SUB, OpCode::HALT],
+            vec![OpCode::PUSHINT(i32::MAX), OpCode::PUSHINT(2), OpCode::MUL, OpCode::HALT],
+            vec![OpCode::PUSHINT(i32::MIN), OpCode::PUSHINT(-1), OpCode::DIV, OpCode::HALT],
+        ] {
+            let mut vm = VM::new(4, program);
+
+            assert_eq!(vm.execute(), Err(StackError::IntegerOverflow));
+        }
+    }
+
+    #[test]
+    fn test_vm_step_limit_and_interrupt() {
+        let mut program: Vec<OpCode> = (0..INTERRUPT_CHECK_INTERVAL)
+            .flat_map(|_| [OpCode::PUSHINT(1), OpCode::POP])
+            .collect();
+        program.extend([OpCode::PUSHINT(7), OpCode::HALT]);
+
+        let steps = program.len() as u64;
+
+        let mut limited = VM::with_config(4, program.clone(), VMConfig { max_steps: steps - 1, ..VMConfig::default() });
+        assert_eq!(limited.execute(), Err(StackError::StepLimitExceeded));
+        assert_eq!(limited.state(), VMState::Faulted);
+
+        let mut exact = VM::with_config(4, program.clone(), VMConfig { max_steps: steps, ..VMConfig::default() });
+        assert_eq!(exact.execute(), Ok(vec![StackValue::Integer(7)]));
+
+        let mut vm = VM::new(4, program);
+        vm.interrupt_flag().store(true, Ordering::Relaxed);
+        assert_eq!(vm.execute(), Err(StackError::Interrupted));
+        assert_eq!(vm.state(), VMState::Suspended);
+        assert_eq!(vm.last_error().unwrap().ip, Some(0));
+
+        // A suspended run resumes where it stopped and counts every
+        // instruction once.
+        assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(7)]));
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.snapshot().steps, steps);
+
+        vm.interrupt_flag().store(true, Ordering::Relaxed);
+        vm.reset();
+        assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(7)]));
+    }
+
+    #[test]
+    fn test_vm_memory_quota() {
+        let program = vec![
+            OpCode::PUSHBYTE(1), OpCode::PUSHBYTE(2), OpCode::PUSHBYTE(3), OpCode::PUSHBYTE(4),
+            OpCode::PACK(4),
+            OpCode::PUSHBYTE(5), OpCode::PUSHBYTE(6), OpCode::PUSHBYTE(7), OpCode::PUSHBYTE(8),
+            OpCode::PACK(4),
+            OpCode::CONCAT,
+            OpCode::HALT,
+        ];
+
+        let mut unlimited = VM::new(8, program.clone());
+        assert_eq!(unlimited.execute(), Ok(vec![StackValue::ByteArray(vec![1, 2, 3, 4, 5, 6, 7, 8].into())]));
+        assert_eq!(unlimited.peak_memory(), 8);
+
+        let mut exact = VM::with_config(8, program.clone(), VMConfig { max_memory_bytes: 8, ..VMConfig::default() });
+        assert!(exact.execute().is_ok());
+
+        let mut limited = VM::with_config(8, program, VMConfig { max_memory_bytes: 7, ..VMConfig::default() });
+        assert_eq!(limited.execute(), Err(StackError::MemoryLimitExceeded));
+        assert_eq!(limited.last_error().unwrap().ip, Some(9));
+        assert_eq!(limited.peak_memory(), 8);
+
+        let mut memory = VM::with_config(4, vec![
+            OpCode::PUSHINT(0),
+            OpCode::PUSHBYTE(1),
+            OpCode::MSTORE8,
+            OpCode::PUSHINT(0),
+            OpCode::HALT,
+        ], VMConfig { max_memory_bytes: PAGE_SIZE as u64 - 1, ..VMConfig::default() });
+        assert_eq!(memory.execute(), Err(StackError::MemoryLimitExceeded));
+        assert_eq!(memory.peak_memory(), 0);
+
+        let mut inputs = VM::with_config(4, vec![OpCode::HALT], VMConfig { max_memory_bytes: 2, ..VMConfig::default() });
+        assert_eq!(inputs.load_inputs(vec![StackValue::ByteArray(vec![0; 3].into())]), Err(StackError::MemoryLimitExceeded));
+    }
+
+    #[test]
+    fn test_vm_memory_quota_counts_sliced_buffers() {
+        // SLICE consumes the array, so only the slice is left to keep its
+        // buffer alive.
+        let program = |len| vec![
+            OpCode::PUSHINT(0), OpCode::PUSHINT(len), OpCode::SLICE,
+            OpCode::PUSHBYTE(1), OpCode::PACK(1), OpCode::POP,
+            OpCode::HALT,
+        ];
+        let config = VMConfig { max_memory_bytes: 8, ..VMConfig::default() };
+        let array = StackValue::ByteArray(vec![7; 8].into());
+
+        // A slice sharing the buffer counts all of it.
+        let mut shared = VM::with_config(4, program(5), config);
+        shared.load_inputs(vec![array.clone()]).unwrap();
+        assert_eq!(shared.execute(), Err(StackError::MemoryLimitExceeded));
+        assert_eq!(shared.last_error().unwrap().ip, Some(4));
+
+        // A small slice is copied, so the buffer goes with the array.
+        let mut copied = VM::with_config(4, program(1), config);
+        copied.load_inputs(vec![array]).unwrap();
+        assert_eq!(copied.execute(), Ok(vec![StackValue::ByteArray(vec![7].into())]));
+        assert_eq!(copied.peak_memory(), 8);
+    }
+
+    #[test]
+    fn test_vm_snapshot_restore_round_trip() {
+        let mut program: Vec<OpCode> = vec![
+            OpCode::PUSHINT(0),
+            OpCode::PUSHINT(42),
+            OpCode::MSTORE,
+            OpCode::PUSHBYTE(1),
+            OpCode::PUSHBYTE(2),
+            OpCode::PACK(2),
+        ];
+        program.extend((0..INTERRUPT_CHECK_INTERVAL).flat_map(|_| [OpCode::PUSHINT(1), OpCode::POP]));
+        program.extend([OpCode::PUSHINT(0), OpCode::MLOAD, OpCode::RETURN(2)]);
+
+        let mut reference = VM::new(4, program.clone());
+        let expected = reference.execute();
+        assert_eq!(expected, Ok(vec![StackValue::ByteArray(vec![1, 2].into()), StackValue::Integer(42)]));
+
+        // The first interrupt is seen before any instruction runs, the
+        // second one INTERRUPT_CHECK_INTERVAL steps into the program.
+        let mut vm = VM::new(4, program.clone());
+        let interrupt = vm.interrupt_flag();
+        for _ in 0..2 {
+            interrupt.store(true, Ordering::Relaxed);
+            assert_eq!(vm.execute(), Err(StackError::Interrupted));
+        }
+        assert_eq!(vm.state(), VMState::Suspended);
+
+        let encoded = vm.snapshot().encode();
+
+        let mut restored = VM::restore(VMSnapshot::decode(&encoded).unwrap(), program.clone()).unwrap();
+        assert_eq!(restored.snapshot().encode(), encoded);
+        assert_eq!(restored.execute(), expected);
+        assert_eq!(restored.gas_used(), reference.gas_used());
+        assert_eq!(restored.peak_memory(), reference.peak_memory());
+
+        assert_eq!(
+            VM::restore(VMSnapshot::decode(&encoded).unwrap(), vec![OpCode::HALT]).err(),
+            Some(SnapshotError::ProgramMismatch)
+        );
+
+        // A forged stack size is rejected before anything is allocated.
+        let mut forged = VMSnapshot::decode(&encoded).unwrap();
+        forged.stack_size = 1 << 60;
+        assert_eq!(VM::restore(forged, program.clone()).err(), Some(SnapshotError::InvalidValue));
+
+        let mut forged = VMSnapshot::decode(&encoded).unwrap();
+        forged.stack_size = MAX_STACK_SIZE + 1;
+        assert_eq!(VM::restore(forged, program).err(), Some(SnapshotError::InvalidValue));
+    }
+
+    #[test]
+    fn test_vm_optimized_program_fails_like_the_original() {
+        let program = vec![
+            OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::ADD,
+            OpCode::PUSHINT(0), OpCode::DIV,
+            OpCode::HALT,
+        ];
+        let config = VMConfig { optimize: true, ..VMConfig::default() };
+
+        // Errors and profiles point at the instructions as loaded; the
+        // folded push is counted as the ADD it replaced.
+        let mut vm = VM::with_config(2, program.clone(), config);
+        vm.set_trace(false);
+        vm.set_profiling(true);
+        assert_eq!(vm.program().instructions.len(), 4);
+        assert_eq!(vm.execute(), Err(StackError::DivisionByZero));
+
+        let report = vm.last_error().unwrap();
+        assert_eq!((report.ip, report.opcode.clone()), (Some(4), Some(OpCode::DIV)));
+        assert!(vm.profile_report(false).unwrap().contains(&format!("{:<8} {:<20}", 2, "ADD")));
+
+        // On a stack of one the loaded program overflows at its second
+        // push, which the optimized one never runs, so it runs unoptimized.
+        let mut vm = VM::with_config(1, program.clone(), config);
+        vm.set_trace(false);
+        let loaded = Arc::clone(vm.program());
+        assert_eq!(vm.execute(), Err(StackError::StackOverFlow));
+        assert_eq!(vm.last_error().unwrap().ip, Some(1));
+        assert!(Arc::ptr_eq(vm.program(), &loaded));
+
+        let snapshot = vm.snapshot().encode();
+        let restored = VM::restore(VMSnapshot::decode(&snapshot).unwrap(), program).unwrap();
+        assert_eq!(restored.snapshot().encode(), snapshot);
+        assert_eq!(restored.program().instructions.len(), 4);
+
+        vm.reset();
+        assert!(Arc::ptr_eq(&vm.program, &loaded));
+    }
+
+    #[test]
+    fn test_vm_snapshot_restore_keeps_receipt() {
+        let mut program = vec![OpCode::PUSHINT(1); 8];
+        program.extend(vec![OpCode::ADD; 7]);
+        program.extend((0..INTERRUPT_CHECK_INTERVAL).flat_map(|_| [OpCode::PUSHINT(1), OpCode::POP]));
+        program.push(OpCode::HALT);
+
+        let mut reference = VM::new(8, program.clone());
+        reference.set_trace(false);
+        assert_eq!(reference.execute(), Ok(vec![StackValue::Integer(8)]));
+
+        // Interrupted once before the first instruction and once after the
+        // stack has peaked at 8 values.
+        let mut vm = VM::new(8, program.clone());
+        vm.set_trace(false);
+        let interrupt = vm.interrupt_flag();
+        for _ in 0..2 {
+            interrupt.store(true, Ordering::Relaxed);
+            assert_eq!(vm.execute(), Err(StackError::Interrupted));
+        }
+
+        let mut restored = VM::restore(VMSnapshot::decode(&vm.snapshot().encode()).unwrap(), program.clone()).unwrap();
+        restored.set_trace(false);
+        assert_eq!(restored.execute(), Ok(vec![StackValue::Integer(8)]));
+
+        let receipt = |vm: &VM| ExecutionReceipt { wall_time_ns: 0, ..vm.receipt() };
+        assert_eq!(receipt(&restored), receipt(&reference));
+        assert_eq!(receipt(&restored).peak_stack_depth, 8);
+
+        // Frames are put back without being counted again.
+        let mut snapshot = vm.snapshot();
+        snapshot.frames = vec![vec![StackValue::Integer(1)]];
+        snapshot.frames_pushed = 5;
+        let restored = VM::restore(snapshot.clone(), program.clone()).unwrap();
+        assert_eq!(restored.receipt().frames_pushed, 5);
+        assert_eq!(restored.snapshot(), snapshot);
+
+        snapshot.frames_pushed = 0;
+        assert_eq!(VM::restore(snapshot.clone(), program.clone()).err(), Some(SnapshotError::InvalidValue));
+
+        snapshot.frames_pushed = 1;
+        snapshot.peak_stack_depth = 9;
+        assert_eq!(VM::restore(snapshot, program).err(), Some(SnapshotError::InvalidValue));
+    }
+
+    #[test]
+    fn test_vm_execution_receipt() {
+        let mut vm = VM::new(4, vec![
+            OpCode::PUSHINT(0),
+            OpCode::PUSHINT(42),
+            OpCode::MSTORE,
+            OpCode::PUSHINT(1),
+            OpCode::PUSHINT(2),
+            OpCode::PUSHINT(3),
+            OpCode::POP,
+            OpCode::POP,
+            OpCode::HALT,
+        ]);
+
+        vm.execute().unwrap();
+
+        let receipt = vm.receipt();
+        assert_eq!(receipt, ExecutionReceipt {
+            instructions_executed: 9,
+            gas_used: MEMORY_PAGE_GAS,
+            peak_stack_depth: 3,
+            peak_memory: PAGE_SIZE as u64,
+            frames_pushed: 0,
+            wall_time_ns: receipt.wall_time_ns,
+        });
+
+        vm.reset();
+        assert_eq!(vm.receipt(), ExecutionReceipt::default());
+    }
+
+    #[test]
+    fn test_vm_profiling() {
+        let mut vm = VM::new(4, vec![OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::ADD, OpCode::HALT]);
+        assert_eq!(vm.profile_report(false), None);
+
+        vm.set_profiling(true);
+
+        for _ in 0..3 {
+            vm.reset();
+            assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(3)]));
+        }
+
+        let folded = vm.profile_report(true).unwrap();
+        let counts: Vec<&str> = folded.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+        assert_eq!(counts, vec!["PUSHINT;0:PUSHINT(1)", "PUSHINT;1:PUSHINT(2)", "ADD;2:ADD", "HALT;3:HALT"]);
+
+        let text = vm.profile_report(false).unwrap();
+        assert!(text.lines().any(|line| line.starts_with("PUSHINT") && line.split_whitespace().nth(1) == Some("6")));
+
+        vm.set_profiling(false);
+        assert_eq!(vm.profile_report(true), None);
+    }
+
+    // The run loop as it was before programs were decoded: every
+    // instruction goes through step. Kept as the reference for the dispatch
+    // differential test and benchmark.
+    fn execute_reference(vm: &mut VM) -> Result<Vec<StackValue>, StackError> {
+        let result = loop {
+            if vm.ip >= vm.program.instructions.len() {
+                vm.current = None;
+
+                break vm.stack.pop().map(|value| vec![value]);
+            }
+
+            vm.current = Some(vm.ip);
+            vm.record_operand_types();
+
+            if vm.config.max_steps != 0 && vm.steps >= vm.config.max_steps {
+                break Err(StackError::StepLimitExceeded);
+            }
+
+            vm.steps += 1;
+
+            match vm.step() {
+                Ok(None) => {},
+                Ok(Some(values)) => break Ok(values),
+                Err(e) => break Err(e),
+            }
+        };
+
+        vm.state = if result.is_ok() { VMState::Halted } else { VMState::Faulted };
+        vm.last_error = result.as_ref().err().map(|e| vm.report_error(*e));
+
+        result
+    }
+
+    fn random_value(rng: &mut Lcg) -> StackValue {
+        let values = [
+            StackValue::Integer(0), StackValue::Integer(1), StackValue::Integer(-1), StackValue::Integer(4),
+            StackValue::Integer(i32::MAX), StackValue::Integer(i32::MIN),
+            StackValue::Float(0.0), StackValue::Float(-0.0), StackValue::Float(-2.5), StackValue::Float(f32::NAN),
+            StackValue::Float(f32::from_bits(0x7FC0_0001)), StackValue::Float(f32::INFINITY),
+            StackValue::Byte(0), StackValue::Byte(2), StackValue::Byte(0xFF),
+            StackValue::ByteArray(vec![1, 2, 3, 4].into()), StackValue::ByteArray(Vec::new().into()),
+            StackValue::Bool(true),
+        ];
+
+        values[rng.next(values.len())].clone()
+    }
+
+    fn random_program(rng: &mut Lcg) -> Vec<OpCode> {
+        let ops = [
+            OpCode::PUSHINT(0), OpCode::PUSHINT(3), OpCode::PUSHINT(-1), OpCode::PUSHINT(i32::MAX),
+            OpCode::PUSHFLOAT(1.5), OpCode::PUSHFLOAT(f32::NAN), OpCode::PUSHFLOAT(-0.0), OpCode::PUSHFLOAT(0.0),
+            OpCode::PUSHBYTE(7), OpCode::PUSHBYTE(0xFF), OpCode::PUSHBYTES(vec![1, 2].into()),
+            OpCode::POP, OpCode::PACK(0), OpCode::PACK(1), OpCode::PACK(3),
+            OpCode::ADD, OpCode::SUB, OpCode::MUL, OpCode::DIV, OpCode::MOD, OpCode::REM,
+            OpCode::MIN, OpCode::MAX, OpCode::POW,
+            OpCode::EQ, OpCode::LT, OpCode::LTE, OpCode::GT, OpCode::GTE, OpCode::CONCAT,
+            OpCode::MLOAD, OpCode::MSTORE, OpCode::MSTORE8, OpCode::MCOPY, OpCode::MSIZE,
+            OpCode::MLOADBYTES, OpCode::MSTOREBYTES,
+            OpCode::I2F, OpCode::F2I, OpCode::I2B, OpCode::B2I, OpCode::BOOL2I,
+            OpCode::TOBYTESLE, OpCode::FROMBYTESBE(FROMBYTES_INTEGER), OpCode::FROMBYTESLE(FROMBYTES_FLOAT),
+            OpCode::NEG, OpCode::ABS, OpCode::SQRT, OpCode::FLOOR, OpCode::CEIL, OpCode::ROUND, OpCode::TRUNC,
+            OpCode::SLICE,
+            OpCode::HALT, OpCode::RETURN(0), OpCode::RETURN(2),
+        ];
+
+        (0..rng.next(16)).map(|_| ops[rng.next(ops.len())].clone()).collect()
+    }
+
+    // Everything a run can be observed by, with Debug output so NaN
+    // payloads are compared too.
+    fn outcome(vm: &VM, result: Result<Vec<StackValue>, StackError>) -> String {
+        format!(
+            "{:?} {:?} {:?} gas={} steps={} peak={} depth={} stack={:?} memory={}",
+            result, vm.state, vm.last_error, vm.gas_used, vm.steps, vm.peak_memory,
+            vm.stack.peak_len(), vm.stack.values(), vm.memory.size(),
+        )
+    }
+
+    // Covers both backends, as the register backend dispatches everything
+    // outside its blocks through the decoded handlers. Float results can
+    // differ between debug and release code generation, so it is worth
+    // running under --release as well.
+    #[test]
+    fn test_vm_dispatch_matches_reference() {
+        let configs = [
+            VMConfig::default(),
+            VMConfig { deterministic_float: true, ..VMConfig::default() },
+            VMConfig { ieee_float_division: true, ..VMConfig::default() },
+            VMConfig { max_steps: 5, ..VMConfig::default() },
+            VMConfig { max_memory_bytes: PAGE_SIZE as u64 + 4, ..VMConfig::default() },
+            VMConfig { max_memory_bytes: 3, ..VMConfig::default() },
+        ]
+        .into_iter()
+        .flat_map(|config| [BACKEND_STACK, BACKEND_REGISTER].map(|backend| VMConfig { backend, ..config }));
+
+        let mut rng = Lcg(0xD15);
+
+        for _ in 0..20000 {
+            let program = random_program(&mut rng);
+            let inputs: Vec<StackValue> = (0..rng.next(4)).map(|_| random_value(&mut rng)).collect();
+
+            for config in configs.clone() {
+                let mut fast = VM::with_config(4, program.clone(), config);
+                let mut reference = VM::with_config(4, program.clone(), config);
+                fast.set_trace(false);
+                reference.set_trace(false);
+
+                // A failed load leaves the inputs over the quota; the run
+                // must then fail the same way too.
+                let loaded = fast.load_inputs(inputs.clone());
+                assert_eq!(reference.load_inputs(inputs.clone()), loaded);
+
+                let result = fast.execute();
+                let expected = execute_reference(&mut reference);
+
+                assert_eq!(
+                    outcome(&fast, result),
+                    outcome(&reference, expected),
+                    "program {:?} with inputs {:?} and {:?}", program, inputs, config
+                );
+            }
+        }
+    }
+
+    // Compares the decoded loop and the register backend with the reference
+    // loop on a short script:
+    //
+    //   cargo test --release -- --ignored --nocapture bench_vm_dispatch
+    #[test]
+    #[ignore]
+    fn bench_vm_dispatch() {
+        let mut script = vec![OpCode::PUSHINT(1)];
+
+        for i in 0..16 {
+            script.extend([OpCode::PUSHINT(i), OpCode::ADD, OpCode::PUSHFLOAT(0.5), OpCode::MUL, OpCode::F2I]);
+        }
+
+        script.extend([OpCode::PUSHINT(0), OpCode::LT, OpCode::HALT]);
+
+        const RUNS: u32 = 200_000;
+
+        let mut vm = VM::new(8, script);
+        vm.set_trace(false);
+
+        let time = |vm: &mut VM, run: fn(&mut VM) -> Result<Vec<StackValue>, StackError>| {
+            let started = std::time::Instant::now();
+
+            for _ in 0..RUNS {
+                vm.reset();
+                assert!(run(vm).is_ok());
+            }
+
+            started.elapsed() / RUNS
+        };
+
+        // Both loops are timed without execute, whose wall clock reads would
+        // dominate a script this short.
+        let decoded = time(&mut vm, |vm| {
+            vm.next_check = vm.steps;
+            vm.run::<false, false>()
+        });
+        let reference = time(&mut vm, execute_reference);
+
+        vm.set_program(Arc::new(Program::new(vm.program.instructions.clone(), VMConfig { backend: BACKEND_REGISTER, ..vm.config })));
+        let registers = time(&mut vm, |vm| {
+            vm.next_check = vm.steps;
+            vm.run_registers::<false>()
+        });
+
+        println!("reference loop:   {:?} per run", reference);
+        println!("decoded loop:     {:?} per run", decoded);
+        println!("register backend: {:?} per run", registers);
+    }
+}
@@ -0,0 +1,519 @@
+use std::cmp::Ordering;
+use crate::stack::composite_stack::StackValue::{self, Bool, Byte, ByteArray, Float, Integer};
+use crate::stack::slot::Slot;
+use crate::stack::stack::Stack;
+use crate::vm::float;
+use crate::vm::op::OpCode;
+use crate::vm::vm::VM;
+
+// Programs are decoded once at load time into a table of handlers with their
+// operands resolved, so the run loop makes one indirect call per
+// instruction instead of re-matching the OpCode enum.
+//
+// A handler either completes its instruction or returns None without having
+// changed the VM. In that case the run loop executes the instruction again
+// through VM::step, which owns every error path: the error, the report and
+// what is left on the stack stay exactly as step produces them, and
+// handlers only have to get the successful case right. Instructions that
+// end the program or are less common have no handler of their own and
+// always take that path.
+pub(super) type Handler = fn(&mut VM, u32) -> Option<()>;
+
+#[derive(Clone, Copy)]
+pub(super) struct Instruction {
+    pub handler: Handler,
+    // The opcode's operand_bits, reinterpreted by the handler.
+    pub operand: u32,
+}
+
+// The value semantics of the scalar instructions, shared by the handlers
+// below and the register backend. None means the instruction fails on these
+// operands. Float results are not yet canonicalized.
+pub(super) type UnaryOp = fn(&VM, &StackValue) -> Option<StackValue>;
+pub(super) type BinaryOp = fn(&VM, &StackValue, &StackValue) -> Option<StackValue>;
+
+// One instruction per opcode, followed by the implicit end of the program.
+// The sentinel saves the loop from checking ip against the program length
+// on every step.
+pub(super) fn decode(instructions: &[OpCode]) -> Vec<Instruction> {
+    instructions.iter()
+        .map(|op| Instruction { handler: handler(op), operand: op.operand_bits() })
+        .chain([Instruction { handler: slow, operand: 0 }])
+        .collect()
+}
+
+fn handler(op: &OpCode) -> Handler {
+    match op {
+        OpCode::PUSHINT(_) => push_int,
+        OpCode::PUSHBYTE(_) => push_byte,
+        OpCode::PUSHFLOAT(_) => push_float,
+        OpCode::PACK(_) => pack,
+        OpCode::POP => pop,
+        OpCode::ADD => |vm, _| binary(vm, add),
+        OpCode::SUB => |vm, _| binary(vm, sub),
+        OpCode::MUL => |vm, _| binary(vm, mul),
+        OpCode::DIV => |vm, _| binary(vm, div),
+        OpCode::MOD => |vm, _| binary(vm, modulo),
+        OpCode::REM => |vm, _| binary(vm, rem),
+        OpCode::MIN => |vm, _| binary(vm, min),
+        OpCode::MAX => |vm, _| binary(vm, max),
+        OpCode::POW => |vm, _| binary(vm, pow),
+        OpCode::EQ => |vm, _| binary(vm, eq),
+        OpCode::LT => |vm, _| binary(vm, lt),
+        OpCode::LTE => |vm, _| binary(vm, lte),
+        OpCode::GT => |vm, _| binary(vm, gt),
+        OpCode::GTE => |vm, _| binary(vm, gte),
+        OpCode::CONCAT => concat,
+        OpCode::SLICE => slice,
+        OpCode::MLOAD => mload,
+        OpCode::MSTORE => mstore,
+        OpCode::MSTORE8 => mstore8,
+        OpCode::MSIZE => msize,
+        OpCode::I2F => |vm, _| unary(vm, i2f),
+        OpCode::F2I => |vm, _| unary(vm, f2i),
+        OpCode::I2B => |vm, _| unary(vm, i2b),
+        OpCode::B2I => |vm, _| unary(vm, b2i),
+        OpCode::BOOL2I => |vm, _| unary(vm, bool2i),
+        OpCode::NEG => |vm, _| unary(vm, neg),
+        OpCode::ABS => |vm, _| unary(vm, abs),
+        OpCode::SQRT => |vm, _| unary(vm, sqrt),
+        OpCode::FLOOR => |vm, _| unary(vm, floor),
+        OpCode::CEIL => |vm, _| unary(vm, ceil),
+        OpCode::ROUND => |vm, _| unary(vm, round),
+        OpCode::TRUNC => |vm, _| unary(vm, trunc),
+        OpCode::HALT
+        | OpCode::RETURN(_)
+        | OpCode::MCOPY
+        | OpCode::MLOADBYTES
+        | OpCode::MSTOREBYTES
+        | OpCode::TOBYTESLE
+        | OpCode::TOBYTESBE
+        | OpCode::FROMBYTESLE(_)
+        | OpCode::FROMBYTESBE(_)
+        | OpCode::PUSHBYTES(_) => slow,
+    }
+}
+
+pub(super) fn unary_op(op: &OpCode) -> Option<UnaryOp> {
+    let op: UnaryOp = match op {
+        OpCode::I2F => i2f,
+        OpCode::F2I => f2i,
+        OpCode::I2B => i2b,
+        OpCode::B2I => b2i,
+        OpCode::BOOL2I => bool2i,
+        OpCode::NEG => neg,
+        OpCode::ABS => abs,
+        OpCode::SQRT => sqrt,
+        OpCode::FLOOR => floor,
+        OpCode::CEIL => ceil,
+        OpCode::ROUND => round,
+        OpCode::TRUNC => trunc,
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+pub(super) fn binary_op(op: &OpCode) -> Option<BinaryOp> {
+    let op: BinaryOp = match op {
+        OpCode::ADD => add,
+        OpCode::SUB => sub,
+        OpCode::MUL => mul,
+        OpCode::DIV => div,
+        OpCode::MOD => modulo,
+        OpCode::REM => rem,
+        OpCode::MIN => min,
+        OpCode::MAX => max,
+        OpCode::POW => pow,
+        OpCode::EQ => eq,
+        OpCode::LT => lt,
+        OpCode::LTE => lte,
+        OpCode::GT => gt,
+        OpCode::GTE => gte,
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+fn slow(_: &mut VM, _: u32) -> Option<()> {
+    None
+}
+
+fn push_int(vm: &mut VM, i: u32) -> Option<()> {
+    vm.stack.push(Integer(i as i32)).ok()
+}
+
+fn push_byte(vm: &mut VM, b: u32) -> Option<()> {
+    vm.stack.push(Byte(b as u8)).ok()
+}
+
+fn push_float(vm: &mut VM, bits: u32) -> Option<()> {
+    vm.stack.push(Float(f32::from_bits(bits))).ok()?;
+    vm.canonicalize_top();
+
+    Some(())
+}
+
+fn pack(vm: &mut VM, n: u32) -> Option<()> {
+    let n = n as usize;
+
+    // PACK(0) pushes without popping and may overflow; leave it to step.
+    if n == 0 || vm.stack.values().len() < n {
+        return None;
+    }
+
+    let bytes = vm.stack.top(n).iter()
+        .map(|slot| match *slot.get() {
+            Byte(b) => Some(b),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+
+    vm.check_memory(n).ok()?;
+
+    vm.stack.discard(n - 1);
+    vm.stack.replace_top(ByteArray(bytes.into()));
+    vm.record_peak();
+
+    Some(())
+}
+
+fn pop(vm: &mut VM, _: u32) -> Option<()> {
+    vm.stack.pop().ok()?;
+
+    Some(())
+}
+
+// Replaces the top value with `op` of it, when that succeeds.
+fn unary(vm: &mut VM, op: UnaryOp) -> Option<()> {
+    let result = op(vm, &vm.stack.top(1).first()?.get())?;
+
+    vm.stack.replace_top(result);
+    vm.canonicalize_top();
+
+    Some(())
+}
+
+// Replaces the top two values with `op` of them, when that succeeds.
+fn binary(vm: &mut VM, op: BinaryOp) -> Option<()> {
+    let (lhs, rhs) = vm.stack.top2()?;
+    let result = op(vm, &lhs.get(), &rhs.get())?;
+
+    vm.stack.replace_top2(result);
+    vm.canonicalize_top();
+
+    Some(())
+}
+
+// Both operands as floats when at least one of them is a Float, promoting
+// an Integer the way the arithmetic instructions do.
+fn promote(lhs: &StackValue, rhs: &StackValue) -> Option<(f32, f32)> {
+    match (lhs, rhs) {
+        (Float(l), Float(r)) => Some((*l, *r)),
+        (Integer(l), Float(r)) => Some((*l as f32, *r)),
+        (Float(l), Integer(r)) => Some((*l, *r as f32)),
+        _ => None,
+    }
+}
+
+fn add(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => l.checked_add(*r).map(Integer),
+        (l, r) => promote(l, r).map(|(l, r)| Float(l + r)),
+    }
+}
+
+fn sub(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => l.checked_sub(*r).map(Integer),
+        (l, r) => promote(l, r).map(|(l, r)| Float(l - r)),
+    }
+}
+
+fn mul(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => l.checked_mul(*r).map(Integer),
+        (l, r) => promote(l, r).map(|(l, r)| Float(l * r)),
+    }
+}
+
+fn div(vm: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(_), Integer(0)) => None,
+        (Integer(l), Integer(r)) => l.checked_div(*r).map(Integer),
+        (l, r) => {
+            let (l, r) = promote(l, r)?;
+
+            vm.div_f32(l, r).ok().map(Float)
+        },
+    }
+}
+
+fn modulo(vm: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => VM::mod_i32(*l, *r).ok().map(Integer),
+        (l, r) => {
+            let (l, r) = promote(l, r)?;
+
+            vm.mod_f32(l, r).ok().map(Float)
+        },
+    }
+}
+
+fn rem(vm: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => VM::rem_i32(*l, *r).ok().map(Integer),
+        (l, r) => {
+            let (l, r) = promote(l, r)?;
+
+            vm.rem_f32(l, r).ok().map(Float)
+        },
+    }
+}
+
+fn min(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => Some(Integer(*l.min(r))),
+        (l, r) => promote(l, r).map(|(l, r)| Float(float::min(l, r))),
+    }
+}
+
+fn max(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => Some(Integer(*l.max(r))),
+        (l, r) => promote(l, r).map(|(l, r)| Float(float::max(l, r))),
+    }
+}
+
+// A Float base with an Integer exponent uses powi, so it is not promoted.
+fn pow(vm: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    match (lhs, rhs) {
+        (Integer(l), Integer(r)) => VM::pow_i32(*l, *r).ok().map(Integer),
+        (Float(l), Integer(r)) => Some(Float(vm.powi(*l, *r))),
+        (l, r) => {
+            let (l, r) = promote(l, r)?;
+
+            vm.powf(l, r).ok().map(Float)
+        },
+    }
+}
+
+// Comparisons accept the same operand pairs as the arithmetic instructions
+// plus Byte pairs. A NaN operand is unordered, so every comparison with it
+// is false.
+fn compare(lhs: &StackValue, rhs: &StackValue, test: fn(Option<Ordering>) -> bool) -> Option<StackValue> {
+    let ordering = match (lhs, rhs) {
+        (Integer(l), Integer(r)) => l.partial_cmp(r),
+        (Byte(l), Byte(r)) => l.partial_cmp(r),
+        (l, r) => promote(l, r).map(|(l, r)| l.partial_cmp(&r))?,
+    };
+
+    Some(Bool(test(ordering)))
+}
+
+fn eq(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    compare(lhs, rhs, |o| o == Some(Ordering::Equal))
+}
+
+fn lt(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    compare(lhs, rhs, |o| o == Some(Ordering::Less))
+}
+
+fn lte(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    compare(lhs, rhs, |o| matches!(o, Some(Ordering::Less | Ordering::Equal)))
+}
+
+fn gt(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    compare(lhs, rhs, |o| o == Some(Ordering::Greater))
+}
+
+fn gte(_: &VM, lhs: &StackValue, rhs: &StackValue) -> Option<StackValue> {
+    compare(lhs, rhs, |o| matches!(o, Some(Ordering::Greater | Ordering::Equal)))
+}
+
+// Appends the top array to the one below it, which is only copied when its
+// buffer is shared.
+fn concat(vm: &mut VM, _: u32) -> Option<()> {
+    let (head, tail) = vm.stack.top2()?;
+
+    if head.bytes().is_none() || tail.bytes().is_none() {
+        return None;
+    }
+
+    // The result holds exactly the bytes of both operands.
+    vm.check_memory(0).ok()?;
+
+    let Ok(ByteArray(tail)) = vm.stack.pop() else {
+        unreachable!("top2 checked the operand types");
+    };
+
+    vm.stack.update_top(|slot| {
+        if let Some(head) = slot.bytes_mut() {
+            head.extend_from_slice(&tail);
+        }
+    });
+    vm.record_peak();
+
+    Some(())
+}
+
+fn slice(vm: &mut VM, _: u32) -> Option<()> {
+    let [array, offset, len] = vm.stack.top(3) else {
+        return None;
+    };
+
+    let bytes = array.bytes()?;
+    let offset = address(offset)?;
+    let end = offset.checked_add(address(len)?).filter(|end| *end <= bytes.len())?;
+    let slice = bytes.slice(offset..end);
+
+    vm.stack.discard(2);
+    vm.stack.replace_top(ByteArray(slice));
+
+    Some(())
+}
+
+// A memory offset or length, as pop_address would accept it.
+fn address(slot: &Slot) -> Option<usize> {
+    match *slot.get() {
+        Integer(i) if i >= 0 => Some(i as usize),
+        _ => None,
+    }
+}
+
+// Grows memory to cover the access, or fails without growing it.
+fn touch(vm: &mut VM, offset: usize, len: usize) -> Option<()> {
+    vm.touch_memory(offset, len).ok()?;
+    vm.record_peak();
+
+    Some(())
+}
+
+fn mload(vm: &mut VM, _: u32) -> Option<()> {
+    let offset = address(vm.stack.top(1).first()?)?;
+
+    touch(vm, offset, 4)?;
+
+    let mut word = [0u8; 4];
+    word.copy_from_slice(vm.memory.read(offset, 4));
+
+    vm.stack.replace_top(Integer(i32::from_le_bytes(word)));
+
+    Some(())
+}
+
+fn mstore(vm: &mut VM, _: u32) -> Option<()> {
+    let (offset, value) = vm.stack.top2()?;
+    let (offset, value) = match *value.get() {
+        Integer(i) => (address(offset)?, i),
+        _ => return None,
+    };
+
+    touch(vm, offset, 4)?;
+
+    vm.memory.write(offset, &value.to_le_bytes());
+    vm.stack.discard(2);
+
+    Some(())
+}
+
+fn mstore8(vm: &mut VM, _: u32) -> Option<()> {
+    let (offset, value) = vm.stack.top2()?;
+    let (offset, value) = match *value.get() {
+        Byte(b) => (address(offset)?, b),
+        _ => return None,
+    };
+
+    touch(vm, offset, 1)?;
+
+    vm.memory.write(offset, &[value]);
+    vm.stack.discard(2);
+
+    Some(())
+}
+
+fn msize(vm: &mut VM, _: u32) -> Option<()> {
+    let size = i32::try_from(vm.memory.size()).ok()?;
+
+    vm.stack.push(Integer(size)).ok()
+}
+
+fn i2f(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Integer(i) => Some(Float(*i as f32)),
+        _ => None,
+    }
+}
+
+fn f2i(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Float(f) if (-2_147_483_648.0..2_147_483_648.0).contains(f) => Some(Integer(*f as i32)),
+        _ => None,
+    }
+}
+
+fn i2b(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Integer(i) => u8::try_from(*i).ok().map(Byte),
+        _ => None,
+    }
+}
+
+fn b2i(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Byte(b) => Some(Integer(*b as i32)),
+        _ => None,
+    }
+}
+
+fn bool2i(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Bool(b) => Some(Integer(*b as i32)),
+        _ => None,
+    }
+}
+
+fn neg(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Integer(i) => i.checked_neg().map(Integer),
+        Float(f) => Some(Float(-f)),
+        _ => None,
+    }
+}
+
+fn abs(_: &VM, value: &StackValue) -> Option<StackValue> {
+    match value {
+        Integer(i) => i.checked_abs().map(Integer),
+        Float(f) => Some(Float(f.abs())),
+        _ => None,
+    }
+}
+
+fn float_unary(value: &StackValue, op: fn(f32) -> f32) -> Option<StackValue> {
+    match value {
+        Integer(i) => Some(Float(op(*i as f32))),
+        Float(f) => Some(Float(op(*f))),
+        _ => None,
+    }
+}
+
+fn sqrt(_: &VM, value: &StackValue) -> Option<StackValue> {
+    float_unary(value, f32::sqrt)
+}
+
+fn floor(_: &VM, value: &StackValue) -> Option<StackValue> {
+    float_unary(value, f32::floor)
+}
+
+fn ceil(_: &VM, value: &StackValue) -> Option<StackValue> {
+    float_unary(value, f32::ceil)
+}
+
+fn round(_: &VM, value: &StackValue) -> Option<StackValue> {
+    float_unary(value, f32::round)
+}
+
+fn trunc(_: &VM, value: &StackValue) -> Option<StackValue> {
+    float_unary(value, f32::trunc)
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use crate::vm::config::{VMConfig, BACKEND_REGISTER};
+use crate::vm::op::OpCode;
+use crate::vm::optimize::optimize;
+use crate::vm::vm::dispatch::{decode, Instruction};
+use crate::vm::vm::registers::{self, RegisterProgram};
+
+/// A program prepared for the settings it was loaded with: optimized if
+/// they ask for it, decoded for the run loop and compiled for the register
+/// backend. It never changes once loaded, so any number of VMs can run it
+/// through an `Arc` without paying for loading again. VMs created from a
+/// program use its settings. Created with `VmBuilder::load`.
+pub struct Program {
+    pub(super) instructions: Vec<OpCode>,
+    // The instructions decoded for the run loop, plus an end sentinel.
+    pub(super) code: Vec<Instruction>,
+    // Compiled register blocks when the config selects the register
+    // backend.
+    pub(super) registers: Option<RegisterProgram>,
+    pub(super) config: VMConfig,
+    // Present when optimization changed the instructions.
+    pub(super) source: Option<Source>,
+}
+
+// The program as it was loaded, behind an optimized one.
+pub(super) struct Source {
+    // Prepared without optimization. Runs whose stack could overflow use it
+    // instead, since they must fail at the same instruction with the same
+    // stack.
+    pub(super) program: Arc<Program>,
+    // Index in the loaded program of each optimized instruction, so errors
+    // and profiles refer to the instructions the host wrote.
+    pub(super) origins: Vec<usize>,
+    // Most values the loaded program holds above its inputs.
+    pub(super) peak_depth: usize,
+}
+
+impl Program {
+    pub(crate) fn new(instructions: Vec<OpCode>, config: VMConfig) -> Self {
+        // A program within its step limit may run every instruction, so
+        // folding can only change the outcome of a program that could hit
+        // it.
+        let step_limited = config.max_steps != 0 && config.max_steps < instructions.len() as u64;
+
+        if !config.optimize || step_limited {
+            return Program::prepare(instructions, config, None);
+        }
+
+        let (optimized, origins) = optimize(&instructions, config);
+
+        if optimized == instructions {
+            return Program::prepare(instructions, config, None);
+        }
+
+        let source = Source {
+            peak_depth: peak_depth(&instructions),
+            program: Arc::new(Program::prepare(instructions, config, None)),
+            origins,
+        };
+
+        Program::prepare(optimized, config, Some(source))
+    }
+
+    fn prepare(instructions: Vec<OpCode>, config: VMConfig, source: Option<Source>) -> Self {
+        Program {
+            code: decode(&instructions),
+            registers: (config.backend == BACKEND_REGISTER).then(|| registers::compile(&instructions)),
+            instructions,
+            config,
+            source,
+        }
+    }
+
+    // The instructions as loaded, before optimization.
+    pub(super) fn source_instructions(&self) -> &[OpCode] {
+        self.source.as_ref().map_or(&self.instructions, |source| &source.program.instructions)
+    }
+
+    // Index in the loaded program of the instruction at ip.
+    pub(super) fn origin(&self, ip: usize) -> usize {
+        self.source.as_ref().map_or(ip, |source| source.origins[ip])
+    }
+
+    // The unoptimized program, if a run starting with `depth` values on a
+    // stack of `capacity` could overflow it.
+    pub(super) fn unoptimized_for(&self, depth: usize, capacity: usize) -> Option<&Arc<Program>> {
+        self.source
+            .as_ref()
+            .filter(|source| depth + source.peak_depth > capacity)
+            .map(|source| &source.program)
+    }
+}
+
+// Highest stack depth above the inputs reached before the run ends.
+fn peak_depth(instructions: &[OpCode]) -> usize {
+    let mut depth = 0isize;
+    let mut peak = 0isize;
+
+    for op in instructions.iter().take_while(|op| !op.is_terminator()) {
+        depth += op.results() as isize - op.arity() as isize;
+        peak = peak.max(depth);
+    }
+
+    peak as usize
+}
+
+// Programs are shared between host threads through the handle table.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Program>();
+};
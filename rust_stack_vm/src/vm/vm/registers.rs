@@ -0,0 +1,344 @@
+use std::mem;
+use std::sync::Arc;
+use crate::stack::composite_stack::StackValue::{self, Byte, ByteArray, Float, Integer};
+use crate::stack::stack::{Stack, StackError};
+use crate::vm::float;
+use crate::vm::op::OpCode;
+use crate::vm::vm::VM;
+use crate::vm::vm::dispatch::{binary_op, unary_op, BinaryOp, UnaryOp};
+
+// Register backend, selected with VMConfig::backend.
+//
+// Maximal runs of scalar instructions (pushes, POP, arithmetic, comparisons
+// and conversions) are compiled into blocks of register code. Every value a
+// block computes lives in a register of its own, so pushes and pops inside
+// the block become register reads. Only the values the block takes from
+// the stack below it and the values it leaves behind touch the stack, when
+// the block commits. The remaining instructions run through the stack
+// backend's decoded handlers between blocks. Once there is control flow,
+// blocks will not extend past a basic block.
+//
+// A block runs only when it is certain to behave like its instructions
+// would one at a time: it has enough stack below it and room above it, no
+// step limit or interrupt check falls inside it, and memory is within its
+// quota. Its code reads operands from registers and writes results to
+// registers, so when an operation fails the VM has not changed yet. The
+// block is then abandoned and its instructions are stepped through on the
+// stack backend, which raises the error with the same report, stack and
+// statistics. A traced run prints the stack once per committed block and
+// after every instruction outside blocks. Profiling is per instruction, so
+// profiled runs use the stack backend.
+pub(super) struct RegisterProgram {
+    blocks: Vec<Block>,
+    // Index into blocks of the block starting at each ip.
+    block_at: Vec<Option<u32>>,
+}
+
+struct Block {
+    // Number of instructions the block covers.
+    len: usize,
+    // Values taken from the stack below the block.
+    inputs: usize,
+    // Highest stack depth reached by a push, relative to the depth at
+    // entry, or None if the block never pushes.
+    peak: Option<isize>,
+    // Register i holds the result of ops[i].
+    ops: Vec<Op>,
+    // Registers pushed when the block commits, bottom first.
+    outputs: Vec<usize>,
+    // The block's initial register file, holding its constants. Each VM
+    // runs the block on a copy of it, see register_files, and only
+    // overwrites the other registers.
+    registers: Vec<StackValue>,
+}
+
+enum Op {
+    Const(StackValue),
+    // The input this many values below the top of the stack at entry.
+    Load(usize),
+    Unary(UnaryOp, usize),
+    Binary(BinaryOp, usize, usize),
+}
+
+// Blocks shorter than this run faster on the stack backend.
+const MIN_BLOCK_LEN: usize = 2;
+
+pub(super) fn compile(instructions: &[OpCode]) -> RegisterProgram {
+    let mut blocks = Vec::new();
+    let mut block_at = vec![None; instructions.len()];
+    let mut start = 0;
+
+    while start < instructions.len() {
+        let len = instructions[start..].iter().take_while(|op| is_scalar(op)).count();
+
+        if len >= MIN_BLOCK_LEN {
+            block_at[start] = Some(blocks.len() as u32);
+            blocks.push(compile_block(&instructions[start..start + len]));
+        }
+
+        start += len.max(1);
+    }
+
+    RegisterProgram { blocks, block_at }
+}
+
+fn is_scalar(op: &OpCode) -> bool {
+    matches!(op, OpCode::PUSHINT(_) | OpCode::PUSHFLOAT(_) | OpCode::PUSHBYTE(_) | OpCode::POP)
+        || unary_op(op).is_some()
+        || binary_op(op).is_some()
+}
+
+// Pops a register, loading the next input once the block's own values are
+// used up.
+fn operand(ops: &mut Vec<Op>, stack: &mut Vec<usize>, inputs: &mut usize) -> usize {
+    stack.pop().unwrap_or_else(|| {
+        ops.push(Op::Load(*inputs));
+        *inputs += 1;
+
+        ops.len() - 1
+    })
+}
+
+fn compile_block(instructions: &[OpCode]) -> Block {
+    let mut ops = Vec::new();
+    // Registers standing in for the values pushed so far, bottom first.
+    let mut stack: Vec<usize> = Vec::new();
+    let mut inputs = 0;
+    let mut peak = None;
+
+    for op in instructions {
+        let result = match op {
+            OpCode::PUSHINT(i) => Op::Const(Integer(*i)),
+            OpCode::PUSHFLOAT(f) => Op::Const(Float(*f)),
+            OpCode::PUSHBYTE(b) => Op::Const(Byte(*b)),
+            OpCode::POP => {
+                if stack.pop().is_none() {
+                    inputs += 1;
+                }
+
+                continue;
+            },
+            _ => match (unary_op(op), binary_op(op)) {
+                (Some(unary), _) => Op::Unary(unary, operand(&mut ops, &mut stack, &mut inputs)),
+                (_, Some(binary)) => {
+                    let rhs = operand(&mut ops, &mut stack, &mut inputs);
+                    let lhs = operand(&mut ops, &mut stack, &mut inputs);
+
+                    Op::Binary(binary, lhs, rhs)
+                },
+                _ => unreachable!("{:?} is not a scalar instruction", op),
+            },
+        };
+
+        ops.push(result);
+        stack.push(ops.len() - 1);
+
+        let depth = stack.len() as isize - inputs as isize;
+        peak = Some(peak.map_or(depth, |peak: isize| peak.max(depth)));
+    }
+
+    let registers = ops.iter()
+        .map(|op| match op {
+            Op::Const(value) => value.clone(),
+            _ => Integer(0),
+        })
+        .collect();
+
+    Block {
+        len: instructions.len(),
+        inputs,
+        peak,
+        ops,
+        outputs: stack,
+        registers,
+    }
+}
+
+impl RegisterProgram {
+    // Fresh register files for a VM running this program, one per block.
+    pub(super) fn register_files(&self) -> Vec<Vec<StackValue>> {
+        self.blocks.iter().map(|block| block.registers.clone()).collect()
+    }
+}
+
+impl VM {
+    pub(super) fn run_registers<const TRACE: bool>(&mut self) -> Result<Vec<StackValue>, StackError> {
+        let program = Arc::clone(&self.program);
+        let blocks = program.registers.as_ref().expect("register backend without a program");
+
+        // The program is shared, so the VM keeps its own register files,
+        // copied from the program on the first run.
+        let mut files = mem::take(&mut self.register_files);
+
+        if files.len() != blocks.blocks.len() {
+            files = blocks.register_files();
+        }
+
+        let result = self.run_blocks::<TRACE>(blocks, &mut files);
+
+        self.register_files = files;
+
+        result
+    }
+
+    fn run_blocks<const TRACE: bool>(
+        &mut self,
+        program: &RegisterProgram,
+        files: &mut [Vec<StackValue>],
+    ) -> Result<Vec<StackValue>, StackError> {
+        loop {
+            if let Some(Some(block)) = program.block_at.get(self.ip)
+                && self.run_block(&program.blocks[*block as usize], &mut files[*block as usize])?
+            {
+                if TRACE {
+                    println!("{:?}", self.stack);
+                }
+
+                continue;
+            }
+
+            if let Some(values) = self.dispatch::<TRACE, false>()? {
+                return Ok(values);
+            }
+        }
+    }
+
+    // Runs the block at ip and commits it, or returns false without having
+    // changed the VM so its instructions are stepped through instead.
+    fn run_block(&mut self, block: &Block, registers: &mut [StackValue]) -> Result<bool, StackError> {
+        if self.steps >= self.next_check && !self.check_limits()? {
+            return Ok(false);
+        }
+
+        let depth = self.stack.values().len();
+        let capacity = self.stack.capacity() as isize;
+
+        if self.steps + block.len as u64 > self.next_check
+            || depth < block.inputs
+            || block.peak.is_some_and(|peak| depth as isize + peak > capacity)
+        {
+            return Ok(false);
+        }
+
+        for (i, op) in block.ops.iter().enumerate() {
+            let value = match op {
+                Op::Const(_) => continue,
+                // No scalar instruction accepts a byte array, so the block
+                // would fail on it anyway; this also saves cloning one.
+                Op::Load(n) => match *self.stack.values()[depth - 1 - n].get() {
+                    ByteArray(_) => return Ok(false),
+                    ref value => value.clone(),
+                },
+                Op::Unary(op, value) => match op(self, &registers[*value]) {
+                    Some(result) => result,
+                    None => return Ok(false),
+                },
+                Op::Binary(op, lhs, rhs) => match op(self, &registers[*lhs], &registers[*rhs]) {
+                    Some(result) => result,
+                    None => return Ok(false),
+                },
+            };
+
+            registers[i] = self.canonicalize(value);
+        }
+
+        self.stack.discard(block.inputs);
+
+        for output in &block.outputs {
+            self.stack.push(registers[*output].clone())?;
+        }
+
+        if let Some(peak) = block.peak {
+            self.stack.record_len((depth as isize + peak) as usize);
+        }
+
+        self.steps += block.len as u64;
+        self.ip += block.len;
+
+        Ok(true)
+    }
+
+    fn canonicalize(&self, value: StackValue) -> StackValue {
+        match value {
+            Float(f) if self.config.deterministic_float => Float(float::canonicalize(f)),
+            value => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stack::composite_stack::StackValue;
+    use crate::stack::stack::StackError;
+    use crate::vm::config::{VMConfig, BACKEND_REGISTER};
+    use crate::vm::op::OpCode;
+    use crate::vm::vm::registers::compile;
+    use crate::vm::vm::{Program, VirtualMachine, VM};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_register_blocks_cover_scalar_runs() {
+        let program = compile(&[
+            OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::ADD,
+            OpCode::MLOAD,
+            OpCode::POP, OpCode::PUSHFLOAT(1.0), OpCode::ADD, OpCode::I2F,
+            OpCode::HALT,
+        ]);
+
+        assert_eq!(program.block_at, vec![Some(0), None, None, None, Some(1), None, None, None, None]);
+
+        let first = &program.blocks[0];
+        assert_eq!((first.len, first.inputs, first.peak, first.outputs.clone()), (3, 0, Some(2), vec![2]));
+
+        // POP drops the MLOAD result and ADD loads the value below it.
+        let second = &program.blocks[1];
+        assert_eq!((second.len, second.inputs, second.peak, second.outputs.clone()), (4, 2, Some(0), vec![3]));
+    }
+
+    #[test]
+    fn test_register_backend_falls_back_on_errors() {
+        let config = VMConfig { backend: BACKEND_REGISTER, ..VMConfig::default() };
+
+        let mut vm = VM::with_config(4, vec![
+            OpCode::PUSHINT(6), OpCode::PUSHINT(7), OpCode::MUL, OpCode::PUSHINT(2), OpCode::SUB, OpCode::HALT,
+        ], config);
+        vm.set_trace(false);
+        assert_eq!(vm.execute(), Ok(vec![StackValue::Integer(40)]));
+        assert_eq!(vm.receipt().instructions_executed, 6);
+        assert_eq!(vm.receipt().peak_stack_depth, 2);
+
+        let mut vm = VM::with_config(4, vec![
+            OpCode::PUSHINT(1), OpCode::PUSHINT(2), OpCode::PUSHINT(0), OpCode::DIV, OpCode::HALT,
+        ], config);
+        vm.set_trace(false);
+        assert_eq!(vm.execute(), Err(StackError::DivisionByZero));
+
+        let report = vm.last_error().unwrap();
+        assert_eq!(report.ip, Some(3));
+        assert_eq!(report.stack, vec![StackValue::Integer(1)]);
+        assert_eq!(vm.receipt().instructions_executed, 4);
+    }
+
+    #[test]
+    fn test_register_files_are_per_vm() {
+        let config = VMConfig { backend: BACKEND_REGISTER, ..VMConfig::default() };
+        let program = Arc::new(Program::new(vec![OpCode::PUSHINT(2), OpCode::MUL, OpCode::PUSHINT(1), OpCode::ADD, OpCode::HALT], config));
+
+        let mut vms: Vec<VM> = (1..=3).map(|input| {
+            let mut vm = VM::from_program(4, Arc::clone(&program));
+            vm.set_trace(false);
+            vm.load_inputs(vec![StackValue::Integer(input)]).unwrap();
+
+            vm
+        }).collect();
+
+        let results: Vec<_> = vms.iter_mut().map(|vm| vm.execute()).collect();
+
+        assert_eq!(results, vec![
+            Ok(vec![StackValue::Integer(3)]),
+            Ok(vec![StackValue::Integer(5)]),
+            Ok(vec![StackValue::Integer(7)]),
+        ]);
+        assert_eq!(vms[0].register_files.len(), program.registers.as_ref().unwrap().blocks.len());
+    }
+}
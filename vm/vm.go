@@ -2,15 +2,22 @@ package vm
 
 import "C"
 import (
+	"context"
 	"fmt"
+	"runtime"
+
 	"github.com/andantan/hybrid-vm/ffi"
 )
 
 type VM struct {
-	Ptr ffi.VmPtr
+	Handle ffi.VmHandle
 }
 
 func NewVM(stackSize int, inst Instructions) (*VM, error) {
+	return NewVMWithConfig(stackSize, inst, ffi.Config{})
+}
+
+func NewVMWithConfig(stackSize int, inst Instructions, config ffi.Config) (*VM, error) {
 	if stackSize < 1 {
 		return nil, fmt.Errorf("stack size must be at least 1")
 	}
@@ -20,61 +27,277 @@ func NewVM(stackSize int, inst Instructions) (*VM, error) {
 	}
 
 	cInsts := inst.ToFFIOperationSlice()
-	vmPtr, err := ffi.CreateVM(stackSize, cInsts)
+	handle, err := ffi.CreateVMWithConfig(stackSize, cInsts, config)
 
 	if err != nil {
 		return nil, err
 	}
 
 	return &VM{
-		Ptr: vmPtr,
+		Handle: handle,
 	}, nil
 }
 
+// Run executes the loaded program once. The VM stays allocated so it can be
+// rerun with RunWithInputs or Reset; callers release it with Free.
 func (vm *VM) Run() (any, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
 	var result ffi.Result
 
 	defer func() {
 		result.Free()
-		vm.Free()
 	}()
 
-	result = ffi.RunVM(vm.Ptr)
+	result = ffi.RunVM(vm.Handle)
 
 	if result.IsError {
-		switch result.ErrorCode {
-		case 0:
-			return nil, fmt.Errorf("stack underflow")
-		case 1:
-			return nil, fmt.Errorf("stack overflow")
-		case 2:
-			return nil, fmt.Errorf("invalid type for operation")
-		case 3:
-			return nil, fmt.Errorf("division by zero")
-		default:
-			return nil, fmt.Errorf("unknown error (code: %d)", result.ErrorCode)
+		return nil, vm.runError(result)
+	}
+
+	// defer result.Free()
+
+	return resultValue(result), nil
+}
+
+func (vm *VM) RunMulti() ([]any, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	var results ffi.ResultArray
+
+	defer func() {
+		results.Free()
+	}()
+
+	results = ffi.RunVMMulti(vm.Handle)
+
+	values := make([]any, 0, len(results.Results))
+
+	for _, result := range results.Results {
+		if result.IsError {
+			return nil, vm.runError(result)
 		}
+
+		values = append(values, resultValue(result))
+	}
+
+	return values, nil
+}
+
+// RunWithInputs resets the VM and reruns the loaded program with inputs
+// pushed onto the stack in order.
+func (vm *VM) RunWithInputs(inputs ...any) (any, error) {
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	result, err := ffi.RunVMWithInputs(vm.Handle, inputs)
+
+	if err != nil {
+		return nil, err
+	}
+
+	defer func() {
+		result.Free()
+	}()
+
+	if result.IsError {
+		return nil, vm.runError(result)
+	}
+
+	return resultValue(result), nil
+}
+
+func (vm *VM) Reset() {
+	ffi.ResetVM(vm.Handle)
+}
+
+// ExecutionError describes a failed run: the failing instruction, the types
+// of its operands and the values left on top of the stack.
+type ExecutionError struct {
+	Code         int32
+	IP           int
+	HasIP        bool
+	Opcode       ffi.OperationCode
+	OperandTypes string
+	Message      string
+	Stack        []any
+}
+
+func (e *ExecutionError) Error() string {
+	return e.Message
+}
+
+// runError must be called on the OS thread that ran the VM, since the
+// panic message is kept per thread on the Rust side.
+func (vm *VM) runError(result ffi.Result) error {
+	if result.IsPanic {
+		return fmt.Errorf("vm panicked: %s", ffi.LastFFIErrorMessage())
+	}
+
+	return vm.lastError(result.ErrorCode)
+}
+
+func (vm *VM) lastError(code int32) error {
+	report := ffi.LastError(vm.Handle)
+
+	if !report.HasError {
+		return resultError(code)
+	}
+
+	stack := make([]any, len(report.Stack))
+
+	for i, value := range report.Stack {
+		stack[i] = resultValue(value)
+	}
+
+	return &ExecutionError{
+		Code:         report.Code,
+		IP:           report.IP,
+		HasIP:        report.HasIP,
+		Opcode:       report.Opcode,
+		OperandTypes: report.OperandTypes,
+		Message:      report.Message,
+		Stack:        stack,
 	}
+}
 
-	var v any
+func resultError(code int32) error {
+	switch code {
+	case 0:
+		return fmt.Errorf("stack underflow")
+	case 1:
+		return fmt.Errorf("stack overflow")
+	case 2:
+		return fmt.Errorf("invalid type for operation")
+	case 3:
+		return fmt.Errorf("division by zero")
+	case 4:
+		return fmt.Errorf("vm must be reset before running again")
+	case 5:
+		return fmt.Errorf("memory access out of bounds")
+	case 6:
+		return fmt.Errorf("invalid conversion")
+	case 7:
+		return fmt.Errorf("integer overflow")
+	case 8:
+		return fmt.Errorf("operation is not deterministic")
+	case 9:
+		return fmt.Errorf("step limit exceeded")
+	case 10:
+		return fmt.Errorf("execution interrupted by host")
+	case 11:
+		return fmt.Errorf("memory limit exceeded")
+	case ffi.ErrorCodeNullPointer:
+		return fmt.Errorf("null pointer passed to vm")
+	case ffi.ErrorCodeInvalidHandle:
+		return fmt.Errorf("invalid vm handle")
+	default:
+		return fmt.Errorf("unknown error (code: %d)", code)
+	}
+}
 
+func resultValue(result ffi.Result) any {
 	if result.IsFloat {
-		v = result.FloatValue
+		return result.FloatValue
 	} else if result.IsByte {
-		v = result.ByteValue
+		return result.ByteValue
 	} else if result.IsByteArray {
-		v = result.ByteArrayValue[:]
+		return result.ByteArrayValue[:]
 	} else if result.IsBool {
-		v = result.BoolValue
-	} else {
-		v = result.IntValue
+		return result.BoolValue
 	}
 
-	// defer result.Free()
+	return result.IntValue
+}
+
+// RestoreVM rebuilds a VM from a Snapshot buffer, possibly taken in another
+// process. inst must be the program the snapshot was taken from.
+func RestoreVM(snapshot []byte, inst Instructions) (*VM, error) {
+	if len(inst) == 0 {
+		return nil, fmt.Errorf("empty instructions")
+	}
+
+	handle, err := ffi.RestoreVM(snapshot, inst.ToFFIOperationSlice())
+
+	if err != nil {
+		return nil, err
+	}
+
+	return &VM{
+		Handle: handle,
+	}, nil
+}
 
-	return v, nil
+// Snapshot serializes the VM, usually after an interrupted run, so it can
+// be resumed later with RestoreVM.
+func (vm *VM) Snapshot() []byte {
+	return ffi.SnapshotVM(vm.Handle)
+}
+
+// RunContext runs the program like Run, but interrupts it when ctx is done.
+// An interrupted VM is suspended; calling Run again resumes it.
+func (vm *VM) RunContext(ctx context.Context) (any, error) {
+	done := make(chan struct{})
+	defer close(done)
+
+	go func() {
+		select {
+		case <-ctx.Done():
+			vm.Interrupt()
+		case <-done:
+		}
+	}()
+
+	return vm.Run()
+}
+
+// SetTrace turns printing the stack after every instruction on or off. VMs
+// start without it.
+func (vm *VM) SetTrace(enabled bool) {
+	ffi.SetTrace(vm.Handle, enabled)
+}
+
+// SetProfiling turns per-instruction profiling on or off. Enabling starts a
+// fresh profile that accumulates over every run.
+func (vm *VM) SetProfiling(enabled bool) {
+	ffi.SetProfiling(vm.Handle, enabled)
+}
+
+// ProfileReport returns the hot-spot table, or folded stacks for flame
+// graph tools such as flamegraph.pl.
+func (vm *VM) ProfileReport(folded bool) (string, error) {
+	report, ok := ffi.ProfileReport(vm.Handle, folded)
+
+	if !ok {
+		return "", fmt.Errorf("profiling is not enabled")
+	}
+
+	return report, nil
+}
+
+// Receipt reports instructions executed, gas, peak stack depth, peak
+// memory, frames pushed and wall time since the last Reset.
+func (vm *VM) Receipt() ffi.Receipt {
+	return ffi.VMReceipt(vm.Handle)
+}
+
+// PeakMemory reports the most bytes held in byte arrays and linear memory
+// during the last run.
+func (vm *VM) PeakMemory() uint64 {
+	return ffi.PeakMemory(vm.Handle)
+}
+
+// Interrupt stops a run in progress; it is safe to call from any goroutine.
+func (vm *VM) Interrupt() {
+	ffi.InterruptVM(vm.Handle)
 }
 
 func (vm *VM) Free() {
-	ffi.FreeVm(vm.Ptr)
+	if vm.Handle != ffi.InvalidVmHandle {
+		ffi.FreeVm(vm.Handle)
+
+		vm.Handle = ffi.InvalidVmHandle
+	}
 }
@@ -0,0 +1,64 @@
+package vm
+
+import (
+	"fmt"
+	"runtime"
+
+	"github.com/andantan/hybrid-vm/ffi"
+)
+
+// Job runs Program with Inputs pushed onto the stack in order.
+type Job struct {
+	Program *Program
+	Inputs  []any
+}
+
+// JobResult is the value a job returned, or the error it failed with.
+type JobResult struct {
+	Value any
+	Err   error
+}
+
+// RunBatch runs every job in a single call into the VM, on up to threads
+// threads, and returns the results in job order. Each job gets a fresh run
+// of its program with a stackSize slot stack.
+func RunBatch(jobs []Job, stackSize int, threads int) ([]JobResult, error) {
+	if stackSize < 1 {
+		return nil, fmt.Errorf("stack size must be at least 1")
+	}
+
+	runtime.LockOSThread()
+	defer runtime.UnlockOSThread()
+
+	ffiJobs := make([]ffi.Job, len(jobs))
+
+	for i, job := range jobs {
+		ffiJobs[i] = ffi.Job{
+			Program: job.Program.Handle,
+			Inputs:  job.Inputs,
+		}
+	}
+
+	results, err := ffi.RunBatch(ffiJobs, stackSize, threads)
+
+	if err != nil {
+		return nil, err
+	}
+
+	defer results.Free()
+
+	values := make([]JobResult, len(results.Results))
+
+	for i, result := range results.Results {
+		switch {
+		case result.IsPanic:
+			values[i].Err = fmt.Errorf("vm panicked: %s", ffi.LastFFIErrorMessage())
+		case result.IsError:
+			values[i].Err = resultError(result.ErrorCode)
+		default:
+			values[i].Value = resultValue(result)
+		}
+	}
+
+	return values, nil
+}
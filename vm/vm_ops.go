@@ -120,3 +120,213 @@ func NewOpConcat() OpCode {
 		val:  nil,
 	}
 }
+
+func NewOpReturn(count uint32) OpCode {
+	return OpCode{
+		kind: ffi.OpCodeReturn,
+		val:  count,
+	}
+}
+
+func NewOpMLoad() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMLoad,
+		val:  nil,
+	}
+}
+
+func NewOpMStore() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMStore,
+		val:  nil,
+	}
+}
+
+func NewOpMStore8() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMStore8,
+		val:  nil,
+	}
+}
+
+func NewOpMCopy() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMCopy,
+		val:  nil,
+	}
+}
+
+func NewOpMSize() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMSize,
+		val:  nil,
+	}
+}
+
+func NewOpMLoadBytes() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMLoadBytes,
+		val:  nil,
+	}
+}
+
+func NewOpMStoreBytes() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMStoreBytes,
+		val:  nil,
+	}
+}
+
+func NewOpI2F() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeI2F,
+		val:  nil,
+	}
+}
+
+func NewOpF2I() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeF2I,
+		val:  nil,
+	}
+}
+
+func NewOpI2B() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeI2B,
+		val:  nil,
+	}
+}
+
+func NewOpB2I() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeB2I,
+		val:  nil,
+	}
+}
+
+func NewOpBool2I() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeBool2I,
+		val:  nil,
+	}
+}
+
+func NewOpToBytesLE() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeToBytesLE,
+		val:  nil,
+	}
+}
+
+func NewOpToBytesBE() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeToBytesBE,
+		val:  nil,
+	}
+}
+
+func NewOpFromBytesLE(target uint8) OpCode {
+	return OpCode{
+		kind: ffi.OpCodeFromBytesLE,
+		val:  target,
+	}
+}
+
+func NewOpFromBytesBE(target uint8) OpCode {
+	return OpCode{
+		kind: ffi.OpCodeFromBytesBE,
+		val:  target,
+	}
+}
+
+func NewOpMod() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMod,
+		val:  nil,
+	}
+}
+
+func NewOpRem() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeRem,
+		val:  nil,
+	}
+}
+
+func NewOpNeg() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeNeg,
+		val:  nil,
+	}
+}
+
+func NewOpAbs() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeAbs,
+		val:  nil,
+	}
+}
+
+func NewOpMin() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMin,
+		val:  nil,
+	}
+}
+
+func NewOpMax() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeMax,
+		val:  nil,
+	}
+}
+
+func NewOpPow() OpCode {
+	return OpCode{
+		kind: ffi.OpCodePow,
+		val:  nil,
+	}
+}
+
+func NewOpSqrt() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeSqrt,
+		val:  nil,
+	}
+}
+
+func NewOpFloor() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeFloor,
+		val:  nil,
+	}
+}
+
+func NewOpCeil() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeCeil,
+		val:  nil,
+	}
+}
+
+func NewOpRound() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeRound,
+		val:  nil,
+	}
+}
+
+func NewOpTrunc() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeTrunc,
+		val:  nil,
+	}
+}
+
+func NewOpSlice() OpCode {
+	return OpCode{
+		kind: ffi.OpCodeSlice,
+		val:  nil,
+	}
+}
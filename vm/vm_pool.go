@@ -0,0 +1,67 @@
+package vm
+
+import (
+	"fmt"
+
+	"github.com/andantan/hybrid-vm/ffi"
+)
+
+// Pool hands out pre-allocated VMs. It is safe for concurrent use; each
+// checked out VM belongs to one goroutine until it is returned with Put.
+type Pool struct {
+	Handle ffi.VmPoolHandle
+}
+
+func NewPool(capacity int, stackSize int, config ffi.Config) (*Pool, error) {
+	if stackSize < 1 {
+		return nil, fmt.Errorf("stack size must be at least 1")
+	}
+
+	handle, err := ffi.CreateVMPool(capacity, stackSize, config)
+
+	if err != nil {
+		return nil, err
+	}
+
+	return &Pool{
+		Handle: handle,
+	}, nil
+}
+
+// Get checks out a VM with inst loaded. Pooled VMs do not print traces.
+func (pool *Pool) Get(inst Instructions) (*VM, error) {
+	if len(inst) == 0 {
+		return nil, fmt.Errorf("empty instructions")
+	}
+
+	handle, err := ffi.VMPoolCheckout(pool.Handle, inst.ToFFIOperationSlice())
+
+	if err != nil {
+		return nil, err
+	}
+
+	return &VM{
+		Handle: handle,
+	}, nil
+}
+
+// Put returns vm to the pool. The VM must not be used afterwards.
+func (pool *Pool) Put(vm *VM) error {
+	if !ffi.VMPoolCheckin(pool.Handle, vm.Handle) {
+		return fmt.Errorf("vm was not checked out from this pool")
+	}
+
+	vm.Handle = ffi.InvalidVmHandle
+
+	return nil
+}
+
+// Free releases the pool and its idle VMs. VMs still checked out stay
+// valid and must be released with their own Free.
+func (pool *Pool) Free() {
+	if pool.Handle != ffi.VmPoolHandle(ffi.InvalidVmHandle) {
+		ffi.FreeVMPool(pool.Handle)
+
+		pool.Handle = ffi.VmPoolHandle(ffi.InvalidVmHandle)
+	}
+}
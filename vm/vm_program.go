@@ -0,0 +1,58 @@
+package vm
+
+import (
+	"fmt"
+
+	"github.com/andantan/hybrid-vm/ffi"
+)
+
+// Program is a program decoded and prepared once, shared by every VM
+// created from it with NewVM. It is safe for concurrent use.
+type Program struct {
+	Handle ffi.ProgramHandle
+}
+
+// LoadProgram prepares inst for config. VMs created from the program use
+// config as well.
+func LoadProgram(inst Instructions, config ffi.Config) (*Program, error) {
+	if len(inst) == 0 {
+		return nil, fmt.Errorf("empty instructions")
+	}
+
+	handle, err := ffi.LoadProgram(inst.ToFFIOperationSlice(), config)
+
+	if err != nil {
+		return nil, err
+	}
+
+	return &Program{
+		Handle: handle,
+	}, nil
+}
+
+// NewVM creates a VM running the program without decoding it again.
+func (program *Program) NewVM(stackSize int) (*VM, error) {
+	if stackSize < 1 {
+		return nil, fmt.Errorf("stack size must be at least 1")
+	}
+
+	handle, err := ffi.CreateVMFromProgram(stackSize, program.Handle)
+
+	if err != nil {
+		return nil, err
+	}
+
+	return &VM{
+		Handle: handle,
+	}, nil
+}
+
+// Free releases the program. VMs already created from it stay valid and
+// must be released with their own Free.
+func (program *Program) Free() {
+	if program.Handle != ffi.ProgramHandle(ffi.InvalidVmHandle) {
+		ffi.FreeProgram(program.Handle)
+
+		program.Handle = ffi.ProgramHandle(ffi.InvalidVmHandle)
+	}
+}